@@ -0,0 +1,281 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use colored::*;
+use headless_chrome::protocol::cdp::Page::CaptureScreenshotFormatOption;
+use headless_chrome::Tab;
+use image::imageops::{self, FilterType};
+use image::GrayImage;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::fs as async_fs;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::fonts::FontFile;
+use crate::layout::{check_layout, FitPolicy};
+use crate::{load_page, render_simple_pages};
+
+const AUDIT_REPORT_PATH: &str = "./font_audit.json";
+// Covers the joining classes, the Persian-only letters and the digits, where clones differ.
+const AUDIT_SPECIMEN: &str = "ابجد هوز حطی کلمن سعفص قرشت ثخذ ضظغ پچژگ ۰۱۲۳۴۵۶۷۸۹";
+const AUDIT_VIEWPORT: (u32, u32) = (1200, 240);
+
+// Renders are compared after cropping to the ink and resizing to this size.
+const COMPARE_WIDTH: u32 = 256;
+const COMPARE_HEIGHT: u32 = 32;
+const INK_THRESHOLD: u8 = 128;
+
+// Both distances under these limits: the same design under two names.
+const DUPLICATE_HASH_DISTANCE: u32 = 4;
+const DUPLICATE_PIXEL_DISTANCE: f64 = 0.02;
+// Either distance under these limits: close enough for a classifier to confuse.
+const CONFUSABLE_HASH_DISTANCE: u32 = 10;
+const CONFUSABLE_PIXEL_DISTANCE: f64 = 0.06;
+
+/// One rendered face, fingerprinted.
+struct Fingerprint {
+    class: String,
+    id: String,
+    file_hash: String,
+    dhash: u64,
+    thumbnail: GrayImage,
+}
+
+/// Crops a grayscale render to the bounding box of its dark pixels.
+fn crop_to_ink(image: &GrayImage) -> Option<GrayImage> {
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if pixel[0] < INK_THRESHOLD {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    if min_x > max_x {
+        return None;
+    }
+    Some(imageops::crop_imm(image, min_x, min_y, max_x - min_x + 1, max_y - min_y + 1).to_image())
+}
+
+/// Difference hash: one bit per horizontally adjacent pair in a 9×8 downscale.
+fn dhash(image: &GrayImage) -> u64 {
+    let small = imageops::resize(image, 9, 8, FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Mean absolute difference of two equally sized thumbnails, from 0 to 1.
+fn pixel_distance(a: &GrayImage, b: &GrayImage) -> f64 {
+    let total: u64 = a
+        .pixels()
+        .zip(b.pixels())
+        .map(|(p, q)| p[0].abs_diff(q[0]) as u64)
+        .sum();
+    total as f64 / (a.len() as f64 * 255.0)
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    parent[i] = root;
+    root
+}
+
+/// Connected components of the pairs, with members in index order.
+fn clusters(count: usize, pairs: &[(usize, usize)]) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..count).collect();
+    for &(a, b) in pairs {
+        let (ra, rb) = (find(&mut parent, a), find(&mut parent, b));
+        parent[ra.max(rb)] = ra.min(rb);
+    }
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for i in 0..count {
+        let root = find(&mut parent, i);
+        groups.entry(root).or_default().push(i);
+    }
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+fn render_specimen(tab: &Tab, html: &str) -> Result<GrayImage, Box<dyn Error>> {
+    load_page(tab, html, AUDIT_VIEWPORT.0, AUDIT_VIEWPORT.1)?;
+    check_layout(tab, FitPolicy::Shrink)?;
+    let png = tab
+        .capture_screenshot(CaptureScreenshotFormatOption::Png, None, None, true)
+        .map_err(|e| format!("Failed to capture screenshot: {}", e))?;
+    Ok(image::load_from_memory(&png)?.to_luma8())
+}
+
+fn fingerprint(class: &str, font: &FontFile, render: &GrayImage) -> Result<Fingerprint, String> {
+    let ink = crop_to_ink(render).ok_or("rendered no visible glyphs")?;
+    let bytes = STANDARD
+        .decode(&font.base64)
+        .map_err(|e| format!("Invalid font data: {}", e))?;
+    let id = match font.face {
+        Some(face) => format!("{}/{}#{}", class, font.file, face),
+        None => format!("{}/{}", class, font.file),
+    };
+
+    Ok(Fingerprint {
+        class: class.to_string(),
+        id,
+        file_hash: format!("{:x}", Sha256::digest(&bytes)),
+        dhash: dhash(&ink),
+        thumbnail: imageops::resize(&ink, COMPARE_WIDTH, COMPARE_HEIGHT, FilterType::Triangle),
+    })
+}
+
+fn build_report(prints: &[Fingerprint], failures: Vec<Value>) -> Value {
+    let mut by_hash: BTreeMap<&str, Vec<&Fingerprint>> = BTreeMap::new();
+    for print in prints {
+        by_hash.entry(&print.file_hash).or_default().push(print);
+    }
+    let file_duplicates: Vec<Value> = by_hash
+        .into_iter()
+        .filter(|(_, group)| group.len() > 1)
+        .map(|(hash, group)| {
+            let cross_class = group.iter().any(|p| p.class != group[0].class);
+            json!({
+                "sha256": hash,
+                "cross_class": cross_class,
+                "fonts": group.iter().map(|p| &p.id).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    let mut duplicates = Vec::new();
+    let mut confusable = Vec::new();
+    for i in 0..prints.len() {
+        for j in i + 1..prints.len() {
+            let (a, b) = (&prints[i], &prints[j]);
+            let hash_distance = (a.dhash ^ b.dhash).count_ones();
+            let pixels = pixel_distance(&a.thumbnail, &b.thumbnail);
+            if hash_distance <= DUPLICATE_HASH_DISTANCE && pixels <= DUPLICATE_PIXEL_DISTANCE {
+                duplicates.push(json!({
+                    "fonts": [a.id, b.id],
+                    "cross_class": a.class != b.class,
+                    "hash_distance": hash_distance,
+                    "pixel_distance": (pixels * 1e4).round() / 1e4,
+                }));
+            }
+            // Faces of one class are meant to look alike; only other classes can be confused.
+            if a.class != b.class
+                && (hash_distance <= CONFUSABLE_HASH_DISTANCE
+                    || pixels <= CONFUSABLE_PIXEL_DISTANCE)
+            {
+                confusable.push((i, j));
+            }
+        }
+    }
+
+    let clusters: Vec<Value> = clusters(prints.len(), &confusable)
+        .into_iter()
+        .map(|members| {
+            let mut classes: Vec<&str> = members.iter().map(|&i| &*prints[i].class).collect();
+            classes.dedup();
+            json!({
+                "classes": classes,
+                "fonts": members.iter().map(|&i| &prints[i].id).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    json!({
+        "specimen": AUDIT_SPECIMEN,
+        "fonts": prints.len(),
+        "file_duplicates": file_duplicates,
+        "duplicates": duplicates,
+        "confusable_clusters": clusters,
+        "failed": failures,
+    })
+}
+
+/// `audit-fonts`: renders the specimen for every face under `FONTS_DIR` with the simple style
+/// and writes duplicate and confusable-cluster findings to `AUDIT_REPORT_PATH`.
+pub async fn run_audit() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rendered = render_simple_pages(
+        &[AUDIT_SPECIMEN],
+        |files| files.iter().collect(),
+        |tab, face| {
+            render_specimen(tab, &face.html[0])
+                .map_err(|e| e.to_string())
+                .and_then(|render| fingerprint(&face.class, &face.font, &render))
+        },
+    )
+    .await?;
+
+    let mut prints = Vec::new();
+    let mut failures = Vec::new();
+    for (face, result) in rendered {
+        match result {
+            Ok(print) => prints.push(print),
+            Err(e) => {
+                eprintln!("Could not audit {}/{}: {}", face.class, face.font.file, e);
+                failures.push(json!({ "class": face.class, "file": face.font.file, "error": e }));
+            }
+        }
+    }
+
+    let report = build_report(&prints, failures);
+    async_fs::write(AUDIT_REPORT_PATH, serde_json::to_string_pretty(&report)?).await?;
+
+    let count = |key: &str| report[key].as_array().map_or(0, Vec::len);
+    println!(
+        "{} {} faces: {} file duplicates, {} visual duplicates, {} confusable clusters ({})",
+        "Audited".green(),
+        prints.len(),
+        count("file_duplicates"),
+        count("duplicates"),
+        count("confusable_clusters"),
+        AUDIT_REPORT_PATH
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn specimen(offset: u32, stroke: u32) -> GrayImage {
+        GrayImage::from_fn(200, 60, |x, y| {
+            let ink =
+                y > 20 && y < 40 && x >= offset && x < offset + 120 && (x - offset) % 20 < stroke;
+            Luma([if ink { 0 } else { 255 }])
+        })
+    }
+
+    #[test]
+    fn shifted_renders_match_and_different_strokes_do_not() {
+        let a = crop_to_ink(&specimen(10, 6)).unwrap();
+        let b = crop_to_ink(&specimen(30, 6)).unwrap();
+        let c = crop_to_ink(&specimen(10, 14)).unwrap();
+        assert_eq!(dhash(&a), dhash(&b));
+
+        let thumb = |img: &GrayImage| {
+            imageops::resize(img, COMPARE_WIDTH, COMPARE_HEIGHT, FilterType::Triangle)
+        };
+        assert!(pixel_distance(&thumb(&a), &thumb(&b)) < DUPLICATE_PIXEL_DISTANCE);
+        assert!(pixel_distance(&thumb(&a), &thumb(&c)) > CONFUSABLE_PIXEL_DISTANCE);
+        assert!(crop_to_ink(&GrayImage::from_pixel(10, 10, Luma([255]))).is_none());
+    }
+
+    #[test]
+    fn clusters_join_transitively() {
+        let groups = clusters(6, &[(0, 3), (3, 5), (1, 2)]);
+        assert_eq!(groups, vec![vec![0, 3, 5], vec![1, 2]]);
+    }
+}
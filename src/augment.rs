@@ -0,0 +1,745 @@
+use image::{imageops, Rgb, RgbImage};
+use rand::{Rng, RngCore};
+use serde_json::{json, Value};
+
+use std::f64::consts::PI;
+
+pub type Point = (f64, f64);
+pub type Quad = [Point; 4];
+
+/// A rendered sample as it moves through the post-capture pipeline. Geometric augmentations
+/// must keep `quads` (text regions, clockwise from the top-left corner) in sync with the pixels.
+pub struct Canvas {
+    pub image: RgbImage,
+    pub quads: Vec<Quad>,
+}
+
+impl Canvas {
+    pub fn new(image: RgbImage, quads: Vec<Quad>) -> Self {
+        Self { image, quads }
+    }
+
+    pub(crate) fn map_quads(&mut self, h: &Homography) {
+        self.map_points(|p| h.map(p));
+    }
+
+    /// Moves every quad corner through a forward (source to destination) point mapping.
+    pub(crate) fn map_points(&mut self, f: impl Fn(Point) -> Point) {
+        for quad in self.quads.iter_mut() {
+            for point in quad.iter_mut() {
+                *point = f(*point);
+            }
+        }
+    }
+}
+
+/// A single photometric or geometric degradation applied to the screenshot.
+pub trait Augmentation: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Chance that the augmentation is applied to a given sample.
+    fn probability(&self) -> f64;
+
+    /// Applies the augmentation and returns the parameters that were drawn, for the metadata.
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value;
+}
+
+/// An ordered list of augmentations, each rolled independently per sample.
+pub struct AugmentationChain {
+    ops: Vec<Box<dyn Augmentation>>,
+}
+
+impl AugmentationChain {
+    pub fn new(ops: Vec<Box<dyn Augmentation>>) -> Self {
+        Self { ops }
+    }
+
+    pub fn empty() -> Self {
+        Self { ops: Vec::new() }
+    }
+
+    /// Names of the augmentations, in the order they run.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.ops.iter().map(|op| op.name()).collect()
+    }
+
+    /// Appends the augmentations of `other` after this chain's own.
+    pub fn followed_by(mut self, other: AugmentationChain) -> Self {
+        self.ops.extend(other.ops);
+        self
+    }
+
+    /// Builds a chain from `op[:probability],...` over the default chain's augmentations, run
+    /// in the order given. An op without a probability keeps its default one.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut available = Self::default().ops;
+        let mut ops: Vec<Box<dyn Augmentation>> = Vec::new();
+        for item in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let (name, probability) = match item.split_once(':') {
+                Some((name, p)) => match p.parse::<f64>() {
+                    Ok(p) if (0.0..=1.0).contains(&p) => (name, Some(p)),
+                    _ => return Err(format!("Probability of {} must be between 0 and 1", name)),
+                },
+                None => (item, None),
+            };
+            let Some(position) = available.iter().position(|op| op.name() == name) else {
+                return Err(format!(
+                    "Unknown or repeated augmentation {}; choose from {}",
+                    name,
+                    Self::default().names().join(", ")
+                ));
+            };
+            let op = available.remove(position);
+            ops.push(match probability {
+                Some(probability) => Box::new(Reweighted { op, probability }),
+                None => op,
+            });
+        }
+        Ok(Self::new(ops))
+    }
+
+    /// Runs the chain and returns one `{ "op": name, ...params }` record per applied augmentation.
+    pub fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Vec<Value> {
+        let mut applied = Vec::new();
+        for op in &self.ops {
+            if !rng.gen_bool(op.probability().clamp(0.0, 1.0)) {
+                continue;
+            }
+            let mut record = json!({ "op": op.name() });
+            if let (Some(record), Value::Object(params)) =
+                (record.as_object_mut(), op.apply(canvas, rng))
+            {
+                record.extend(params);
+            }
+            applied.push(record);
+        }
+        applied
+    }
+}
+
+impl Default for AugmentationChain {
+    fn default() -> Self {
+        Self::new(vec![
+            Box::new(PerspectiveWarp::default()),
+            Box::new(UnevenLighting::default()),
+            Box::new(Vignette::default()),
+            Box::new(MotionBlur::default()),
+            Box::new(DefocusBlur::default()),
+            Box::new(Resample::default()),
+            Box::new(GaussianNoise::default()),
+            Box::new(SaltAndPepper::default()),
+        ])
+    }
+}
+
+/// An augmentation run with a probability other than its own.
+struct Reweighted {
+    op: Box<dyn Augmentation>,
+    probability: f64,
+}
+
+impl Augmentation for Reweighted {
+    fn name(&self) -> &'static str {
+        self.op.name()
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        self.op.apply(canvas, rng)
+    }
+}
+
+pub(crate) fn round(value: f64, decimals: i32) -> f64 {
+    (value * 10f64.powi(decimals)).round() / 10f64.powi(decimals)
+}
+
+pub(crate) fn clamp_u8(value: f64) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+/// Standard normal sample (Box-Muller).
+pub(crate) fn gaussian(rng: &mut dyn RngCore) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+/// Projective transform between two planes, stored as a row-major 3x3 matrix with h33 = 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Homography([f64; 9]);
+
+impl Homography {
+    /// Solves for the homography that maps each `src[i]` onto `dst[i]`.
+    pub fn from_points(src: &Quad, dst: &Quad) -> Option<Homography> {
+        let mut a = [[0.0f64; 9]; 8];
+        for i in 0..4 {
+            let (x, y) = src[i];
+            let (u, v) = dst[i];
+            a[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+            a[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+        }
+
+        // Gaussian elimination with partial pivoting on the 8x8 augmented system.
+        for col in 0..8 {
+            let pivot = (col..8).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            let pivot_row = a[col];
+            for (row, values) in a.iter_mut().enumerate() {
+                if row != col {
+                    let factor = values[col] / pivot_row[col];
+                    for (value, pivot_value) in values.iter_mut().zip(pivot_row).skip(col) {
+                        *value -= factor * pivot_value;
+                    }
+                }
+            }
+        }
+
+        let mut h = [1.0f64; 9];
+        for i in 0..8 {
+            h[i] = a[i][8] / a[i][i];
+        }
+        Some(Homography(h))
+    }
+
+    pub fn map(&self, (x, y): Point) -> Point {
+        let h = &self.0;
+        let w = h[6] * x + h[7] * y + h[8];
+        (
+            (h[0] * x + h[1] * y + h[2]) / w,
+            (h[3] * x + h[4] * y + h[5]) / w,
+        )
+    }
+
+    pub fn inverse(&self) -> Option<Homography> {
+        let m = &self.0;
+        let det = m[0] * (m[4] * m[8] - m[5] * m[7]) - m[1] * (m[3] * m[8] - m[5] * m[6])
+            + m[2] * (m[3] * m[7] - m[4] * m[6]);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv = [
+            (m[4] * m[8] - m[5] * m[7]) / det,
+            (m[2] * m[7] - m[1] * m[8]) / det,
+            (m[1] * m[5] - m[2] * m[4]) / det,
+            (m[5] * m[6] - m[3] * m[8]) / det,
+            (m[0] * m[8] - m[2] * m[6]) / det,
+            (m[2] * m[3] - m[0] * m[5]) / det,
+            (m[3] * m[7] - m[4] * m[6]) / det,
+            (m[1] * m[6] - m[0] * m[7]) / det,
+            (m[0] * m[4] - m[1] * m[3]) / det,
+        ];
+        Some(Homography(inv.map(|v| v / inv[8])))
+    }
+
+    pub fn to_json(self) -> Value {
+        json!(self.0.map(|v| round(v, 6)))
+    }
+}
+
+/// Bilinear sample with edge clamping.
+pub(crate) fn sample_bilinear(img: &RgbImage, x: f64, y: f64) -> Rgb<u8> {
+    let (w, h) = img.dimensions();
+    let x = x.clamp(0.0, (w - 1) as f64);
+    let y = y.clamp(0.0, (h - 1) as f64);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+
+    let p00 = img.get_pixel(x0, y0);
+    let p10 = img.get_pixel(x1, y0);
+    let p01 = img.get_pixel(x0, y1);
+    let p11 = img.get_pixel(x1, y1);
+    let mut out = [0u8; 3];
+    for c in 0..3 {
+        let top = p00[c] as f64 * (1.0 - fx) + p10[c] as f64 * fx;
+        let bottom = p01[c] as f64 * (1.0 - fx) + p11[c] as f64 * fx;
+        out[c] = clamp_u8(top * (1.0 - fy) + bottom * fy);
+    }
+    Rgb(out)
+}
+
+/// Warps `img` by `h` (source to destination) into an image of the same size. Pixels that map
+/// from outside the source take `fill`, or the nearest edge pixel when it is `None`.
+pub(crate) fn warp_perspective(
+    img: &RgbImage,
+    h: &Homography,
+    fill: Option<Rgb<u8>>,
+) -> Option<RgbImage> {
+    let inverse = h.inverse()?;
+    let (w, ht) = img.dimensions();
+    Some(RgbImage::from_fn(w, ht, |x, y| {
+        let (sx, sy) = inverse.map((x as f64 + 0.5, y as f64 + 0.5));
+        match fill {
+            Some(color) if sx < 0.0 || sy < 0.0 || sx > w as f64 || sy > ht as f64 => color,
+            _ => sample_bilinear(img, sx - 0.5, sy - 0.5),
+        }
+    }))
+}
+
+/// Convolves with a normalized kernel given as `(dx, dy, weight)` taps, clamping at the edges.
+fn convolve_taps(img: &RgbImage, taps: &[(i32, i32, f64)]) -> RgbImage {
+    let (w, h) = img.dimensions();
+    let total: f64 = taps.iter().map(|t| t.2).sum();
+    RgbImage::from_fn(w, h, |x, y| {
+        let mut acc = [0.0f64; 3];
+        for &(dx, dy, weight) in taps {
+            let sx = (x as i32 + dx).clamp(0, w as i32 - 1) as u32;
+            let sy = (y as i32 + dy).clamp(0, h as i32 - 1) as u32;
+            let p = img.get_pixel(sx, sy);
+            for c in 0..3 {
+                acc[c] += p[c] as f64 * weight;
+            }
+        }
+        Rgb(acc.map(|v| clamp_u8(v / total)))
+    })
+}
+
+/// Random perspective distortion: each corner moves by up to `max_shift` of the image size.
+pub struct PerspectiveWarp {
+    pub probability: f64,
+    pub max_shift: (f64, f64),
+}
+
+impl Default for PerspectiveWarp {
+    fn default() -> Self {
+        Self {
+            probability: 0.25,
+            max_shift: (0.01, 0.08),
+        }
+    }
+}
+
+impl Augmentation for PerspectiveWarp {
+    fn name(&self) -> &'static str {
+        "perspective_warp"
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let (w, h) = canvas.image.dimensions();
+        let (w, h) = (w as f64, h as f64);
+        let shift = rng.gen_range(self.max_shift.0..=self.max_shift.1);
+        let src: Quad = [(0.0, 0.0), (w, 0.0), (w, h), (0.0, h)];
+        let mut dst = src;
+        for (x, y) in dst.iter_mut() {
+            *x += rng.gen_range(-shift..=shift) * w;
+            *y += rng.gen_range(-shift..=shift) * h;
+        }
+
+        let Some(homography) = Homography::from_points(&src, &dst) else {
+            return json!({ "skipped": true });
+        };
+        let Some(warped) = warp_perspective(&canvas.image, &homography, None) else {
+            return json!({ "skipped": true });
+        };
+        canvas.image = warped;
+        canvas.map_quads(&homography);
+        json!({ "shift": round(shift, 3), "homography": homography.to_json() })
+    }
+}
+
+/// Linear motion blur along a random direction.
+pub struct MotionBlur {
+    pub probability: f64,
+    pub length: (u32, u32),
+}
+
+impl Default for MotionBlur {
+    fn default() -> Self {
+        Self {
+            probability: 0.15,
+            length: (3, 11),
+        }
+    }
+}
+
+impl Augmentation for MotionBlur {
+    fn name(&self) -> &'static str {
+        "motion_blur"
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let length = rng.gen_range(self.length.0..=self.length.1);
+        let angle: f64 = rng.gen_range(0.0..180.0);
+        let (dx, dy) = (angle.to_radians().cos(), angle.to_radians().sin());
+        let half = length as f64 / 2.0;
+        let taps: Vec<(i32, i32, f64)> = (0..length)
+            .map(|i| {
+                let t = i as f64 - half + 0.5;
+                ((t * dx).round() as i32, (t * dy).round() as i32, 1.0)
+            })
+            .collect();
+        canvas.image = convolve_taps(&canvas.image, &taps);
+        json!({ "length": length, "angle": round(angle, 1) })
+    }
+}
+
+/// Out-of-focus blur with a disk-shaped kernel.
+pub struct DefocusBlur {
+    pub probability: f64,
+    pub radius: (f64, f64),
+}
+
+impl Default for DefocusBlur {
+    fn default() -> Self {
+        Self {
+            probability: 0.15,
+            radius: (1.0, 3.0),
+        }
+    }
+}
+
+impl Augmentation for DefocusBlur {
+    fn name(&self) -> &'static str {
+        "defocus_blur"
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let radius = rng.gen_range(self.radius.0..=self.radius.1);
+        let r = radius.ceil() as i32;
+        let mut taps = Vec::new();
+        for dy in -r..=r {
+            for dx in -r..=r {
+                if ((dx * dx + dy * dy) as f64) <= radius * radius {
+                    taps.push((dx, dy, 1.0));
+                }
+            }
+        }
+        canvas.image = convolve_taps(&canvas.image, &taps);
+        json!({ "radius": round(radius, 2) })
+    }
+}
+
+/// Additive zero-mean Gaussian noise per channel.
+pub struct GaussianNoise {
+    pub probability: f64,
+    pub sigma: (f64, f64),
+}
+
+impl Default for GaussianNoise {
+    fn default() -> Self {
+        Self {
+            probability: 0.3,
+            sigma: (2.0, 12.0),
+        }
+    }
+}
+
+impl Augmentation for GaussianNoise {
+    fn name(&self) -> &'static str {
+        "gaussian_noise"
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let sigma = rng.gen_range(self.sigma.0..=self.sigma.1);
+        for pixel in canvas.image.pixels_mut() {
+            for c in 0..3 {
+                pixel[c] = clamp_u8(pixel[c] as f64 + gaussian(rng) * sigma);
+            }
+        }
+        json!({ "sigma": round(sigma, 2) })
+    }
+}
+
+/// Replaces a fraction of pixels with pure black or white.
+pub struct SaltAndPepper {
+    pub probability: f64,
+    pub amount: (f64, f64),
+}
+
+impl Default for SaltAndPepper {
+    fn default() -> Self {
+        Self {
+            probability: 0.1,
+            amount: (0.001, 0.02),
+        }
+    }
+}
+
+impl Augmentation for SaltAndPepper {
+    fn name(&self) -> &'static str {
+        "salt_and_pepper"
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let amount = rng.gen_range(self.amount.0..=self.amount.1);
+        for pixel in canvas.image.pixels_mut() {
+            if rng.gen_bool(amount) {
+                *pixel = if rng.gen_bool(0.5) {
+                    Rgb([255, 255, 255])
+                } else {
+                    Rgb([0, 0, 0])
+                };
+            }
+        }
+        json!({ "amount": round(amount, 4) })
+    }
+}
+
+/// Darkens the image towards the corners.
+pub struct Vignette {
+    pub probability: f64,
+    pub strength: (f64, f64),
+}
+
+impl Default for Vignette {
+    fn default() -> Self {
+        Self {
+            probability: 0.2,
+            strength: (0.2, 0.6),
+        }
+    }
+}
+
+impl Augmentation for Vignette {
+    fn name(&self) -> &'static str {
+        "vignette"
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let strength = rng.gen_range(self.strength.0..=self.strength.1);
+        let (w, h) = canvas.image.dimensions();
+        let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+        let max_dist = (cx * cx + cy * cy).sqrt();
+        for (x, y, pixel) in canvas.image.enumerate_pixels_mut() {
+            let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+            let d = (dx * dx + dy * dy).sqrt() / max_dist;
+            let factor = 1.0 - strength * d * d;
+            for c in 0..3 {
+                pixel[c] = clamp_u8(pixel[c] as f64 * factor);
+            }
+        }
+        json!({ "strength": round(strength, 2) })
+    }
+}
+
+/// A brightness ramp across the image, as from a light source off to one side.
+pub struct UnevenLighting {
+    pub probability: f64,
+    pub strength: (f64, f64),
+}
+
+impl Default for UnevenLighting {
+    fn default() -> Self {
+        Self {
+            probability: 0.2,
+            strength: (0.1, 0.4),
+        }
+    }
+}
+
+impl Augmentation for UnevenLighting {
+    fn name(&self) -> &'static str {
+        "uneven_lighting"
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let strength = rng.gen_range(self.strength.0..=self.strength.1);
+        let angle: f64 = rng.gen_range(0.0..360.0);
+        let (dx, dy) = (angle.to_radians().cos(), angle.to_radians().sin());
+        let (w, h) = canvas.image.dimensions();
+        let half_span = (w as f64 * dx.abs() + h as f64 * dy.abs()) / 2.0;
+        let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+        for (x, y, pixel) in canvas.image.enumerate_pixels_mut() {
+            // -1 on the dark side, +1 on the lit side
+            let t = ((x as f64 - cx) * dx + (y as f64 - cy) * dy) / half_span.max(1.0);
+            let factor = 1.0 + strength * t;
+            for c in 0..3 {
+                pixel[c] = clamp_u8(pixel[c] as f64 * factor);
+            }
+        }
+        json!({ "strength": round(strength, 2), "angle": round(angle, 1) })
+    }
+}
+
+/// Down-samples and up-samples back to the original size, losing fine detail.
+pub struct Resample {
+    pub probability: f64,
+    pub scale: (f64, f64),
+}
+
+impl Default for Resample {
+    fn default() -> Self {
+        Self {
+            probability: 0.2,
+            scale: (0.35, 0.8),
+        }
+    }
+}
+
+impl Augmentation for Resample {
+    fn name(&self) -> &'static str {
+        "resample"
+    }
+
+    fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let scale = rng.gen_range(self.scale.0..=self.scale.1);
+        let filters = [
+            ("nearest", imageops::FilterType::Nearest),
+            ("triangle", imageops::FilterType::Triangle),
+            ("catmull_rom", imageops::FilterType::CatmullRom),
+        ];
+        let (filter_name, filter) = filters[rng.gen_range(0..filters.len())];
+        let (w, h) = canvas.image.dimensions();
+        let small_w = ((w as f64 * scale) as u32).max(1);
+        let small_h = ((h as f64 * scale) as u32).max(1);
+        let small = imageops::resize(&canvas.image, small_w, small_h, filter);
+        canvas.image = imageops::resize(&small, w, h, filter);
+        json!({ "scale": round(scale, 2), "filter": filter_name })
+    }
+}
+
+/// Decodes a screenshot and runs `chain` over it, tracking the given text quads.
+pub fn augment_screenshot(
+    bytes: &[u8],
+    quads: Vec<Quad>,
+    chain: &AugmentationChain,
+    rng: &mut dyn RngCore,
+) -> Result<(Canvas, Vec<Value>), String> {
+    let image = image::load_from_memory(bytes)
+        .map_err(|e| format!("Failed to decode screenshot: {}", e))?
+        .to_rgb8();
+    let mut canvas = Canvas::new(image, quads);
+    let applied = chain.apply(&mut canvas, rng);
+    Ok((canvas, applied))
+}
+
+/// Axis-aligned bounding box `[x, y, width, height]` of a quad.
+pub fn quad_bounds(quad: &Quad) -> [f64; 4] {
+    let xs = quad.iter().map(|p| p.0);
+    let ys = quad.iter().map(|p| p.1);
+    let min_x = xs.clone().fold(f64::INFINITY, f64::min);
+    let max_x = xs.fold(f64::NEG_INFINITY, f64::max);
+    let min_y = ys.clone().fold(f64::INFINITY, f64::min);
+    let max_y = ys.fold(f64::NEG_INFINITY, f64::max);
+    [
+        round(min_x, 1),
+        round(min_y, 1),
+        round(max_x - min_x, 1),
+        round(max_y - min_y, 1),
+    ]
+}
+
+pub fn quad_to_json(quad: &Quad) -> Value {
+    json!(quad.map(|(x, y)| [round(x, 1), round(y, 1)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_homography_maps_corners() {
+        let src: Quad = [(0.0, 0.0), (100.0, 0.0), (100.0, 50.0), (0.0, 50.0)];
+        let dst: Quad = [(3.0, 2.0), (97.0, -4.0), (104.0, 55.0), (-2.0, 48.0)];
+        let h = Homography::from_points(&src, &dst).unwrap();
+        for (s, d) in src.iter().zip(dst.iter()) {
+            let (x, y) = h.map(*s);
+            assert!((x - d.0).abs() < 1e-6 && (y - d.1).abs() < 1e-6);
+        }
+
+        let inverse = h.inverse().unwrap();
+        let (x, y) = inverse.map(h.map((40.0, 20.0)));
+        assert!((x - 40.0).abs() < 1e-6 && (y - 20.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_chain_records_applied_ops() {
+        let always = AugmentationChain::new(vec![
+            Box::new(GaussianNoise {
+                probability: 1.0,
+                sigma: (5.0, 5.0),
+            }),
+            Box::new(Vignette {
+                probability: 0.0,
+                strength: (0.5, 0.5),
+            }),
+        ]);
+        let mut canvas = Canvas::new(RgbImage::new(16, 16), vec![]);
+        let mut rng = StdRng::seed_from_u64(7);
+        let applied = always.apply(&mut canvas, &mut rng);
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0]["op"], "gaussian_noise");
+        assert_eq!(applied[0]["sigma"], 5.0);
+    }
+
+    #[test]
+    fn test_chain_parses_ops_and_probabilities() {
+        let chain = AugmentationChain::parse("motion_blur:0.5, vignette,gaussian_noise:1").unwrap();
+        let ops: Vec<(&str, f64)> = chain
+            .ops
+            .iter()
+            .map(|op| (op.name(), op.probability()))
+            .collect();
+        let vignette = Vignette::default().probability;
+        assert_eq!(
+            ops,
+            vec![
+                ("motion_blur", 0.5),
+                ("vignette", vignette),
+                ("gaussian_noise", 1.0)
+            ]
+        );
+        assert!(AugmentationChain::parse("vignette:1.5").is_err());
+        assert!(AugmentationChain::parse("vignette,vignette").is_err());
+        assert!(AugmentationChain::parse("sepia").is_err());
+    }
+
+    #[test]
+    fn test_warp_moves_text_quads() {
+        let warp = PerspectiveWarp {
+            probability: 1.0,
+            max_shift: (0.05, 0.05),
+        };
+        let quad: Quad = [(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0)];
+        let mut canvas = Canvas::new(RgbImage::new(40, 40), vec![quad]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let params = warp.apply(&mut canvas, &mut rng);
+        assert!(params.get("homography").is_some());
+        assert_ne!(canvas.quads[0], quad);
+        assert_eq!(canvas.image.dimensions(), (40, 40));
+    }
+}
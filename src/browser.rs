@@ -9,8 +9,6 @@ use std::time::Duration;
 const BROWSER_IDLE_TIME: Duration = Duration::from_secs(3000);
 // const CHROME_PATH: &str = "../chromium/chrome.exe";
 
-use anyhow;
-
 pub enum AppError {
     BrowserError(String),
     ProcessingError(String),
@@ -128,6 +126,7 @@ impl BrowserManager {
     }
 
     /// Get current browser without creating new one
+    #[allow(dead_code)]
     pub fn get_browser(&self) -> Option<Browser> {
         let browser_lock = self.browser.lock().unwrap();
         browser_lock.as_ref().and_then(|b| {
@@ -140,6 +139,7 @@ impl BrowserManager {
     }
 
     /// Force close and recreate browser
+    #[allow(dead_code)]
     pub fn recreate_browser(&self) -> Result<Browser, AppError> {
         self.terminate()?;
         self.get_or_create_browser()
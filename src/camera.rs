@@ -0,0 +1,400 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::{Rgb, RgbImage};
+use rand::{Rng, RngCore};
+use serde_json::{json, Value};
+
+use crate::augment::{
+    clamp_u8, gaussian, round, sample_bilinear, warp_perspective, Augmentation, AugmentationChain,
+    Canvas, Homography, Point, Quad,
+};
+
+// Phone-camera capture simulation for scene-text style samples. Ops run in the order light
+// passes through a phone: viewpoint, lens, sensor, ISP, then the upload pipeline.
+
+/// Parameter ranges of the simulated camera, and how often each stage of it fires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraProfile {
+    pub odds: CameraOdds,
+    /// Focal length as a multiple of the longer image side (about 24mm to 55mm full-frame).
+    pub focal_factor: (f64, f64),
+    pub max_yaw: f64,
+    pub max_pitch: f64,
+    pub max_roll: f64,
+    /// Radial distortion coefficient k1. Output pixels sample the source at `r * (1 + k1 r²)`,
+    /// so negative is pincushion and positive barrel.
+    pub k1: (f64, f64),
+    /// Lateral chromatic aberration, as the relative scale difference of red and blue.
+    pub chromatic_shift: (f64, f64),
+    /// Exposure error in EV before auto-gain.
+    pub exposure_ev: (f64, f64),
+    pub white_balance_shift: f64,
+    /// JPEG qualities used by messaging apps and social networks on upload.
+    pub jpeg_quality: (u8, u8),
+    pub max_recompressions: u32,
+}
+
+/// Probability that each stage of the capture runs on a sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraOdds {
+    pub viewpoint: f64,
+    pub lens_distortion: f64,
+    pub chromatic_aberration: f64,
+    pub sensor_noise: f64,
+    pub white_balance: f64,
+    pub recompression: f64,
+}
+
+impl Default for CameraProfile {
+    fn default() -> Self {
+        Self {
+            odds: CameraOdds {
+                viewpoint: 0.9,
+                lens_distortion: 0.7,
+                chromatic_aberration: 0.5,
+                sensor_noise: 0.8,
+                white_balance: 0.6,
+                recompression: 0.8,
+            },
+            focal_factor: (0.8, 1.8),
+            max_yaw: 25.0,
+            max_pitch: 25.0,
+            max_roll: 8.0,
+            k1: (-0.12, 0.06),
+            chromatic_shift: (0.0005, 0.003),
+            exposure_ev: (-2.0, 0.7),
+            white_balance_shift: 0.12,
+            jpeg_quality: (45, 85),
+            max_recompressions: 2,
+        }
+    }
+}
+
+impl CameraProfile {
+    pub fn chain(&self) -> AugmentationChain {
+        AugmentationChain::new(vec![
+            Box::new(Viewpoint(self.clone())),
+            Box::new(LensDistortion(self.clone())),
+            Box::new(ChromaticAberration(self.clone())),
+            Box::new(SensorNoise(self.clone())),
+            Box::new(WhiteBalance(self.clone())),
+            Box::new(Recompression(self.clone())),
+        ])
+    }
+}
+
+fn rotation_matrix(yaw: f64, pitch: f64, roll: f64) -> [[f64; 3]; 3] {
+    let (sy, cy) = yaw.to_radians().sin_cos();
+    let (sp, cp) = pitch.to_radians().sin_cos();
+    let (sr, cr) = roll.to_radians().sin_cos();
+    // R = Rz(roll) * Rx(pitch) * Ry(yaw)
+    [
+        [cr * cy - sr * sp * sy, -sr * cp, cr * sy + sr * sp * cy],
+        [sr * cy + cr * sp * sy, cr * cp, sr * sy - cr * sp * cy],
+        [-cp * sy, sp, cp * cy],
+    ]
+}
+
+/// Projects the image plane as seen by a pinhole camera rotated by yaw/pitch/roll with focal
+/// length `focal` (pixels), then scales the result back into the frame.
+fn camera_homography(
+    width: f64,
+    height: f64,
+    focal: f64,
+    yaw: f64,
+    pitch: f64,
+    roll: f64,
+) -> Option<Homography> {
+    let r = rotation_matrix(yaw, pitch, roll);
+    let (cx, cy) = (width / 2.0, height / 2.0);
+    let project = |(x, y): Point| {
+        let v = [x - cx, y - cy, focal];
+        let rotated: Vec<f64> = r
+            .iter()
+            .map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+            .collect();
+        (
+            focal * rotated[0] / rotated[2],
+            focal * rotated[1] / rotated[2],
+        )
+    };
+
+    let src: Quad = [(0.0, 0.0), (width, 0.0), (width, height), (0.0, height)];
+    let projected = src.map(project);
+    let min_x = projected.iter().map(|p| p.0).fold(f64::INFINITY, f64::min);
+    let max_x = projected
+        .iter()
+        .map(|p| p.0)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let min_y = projected.iter().map(|p| p.1).fold(f64::INFINITY, f64::min);
+    let max_y = projected
+        .iter()
+        .map(|p| p.1)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let scale = (width / (max_x - min_x)).min(height / (max_y - min_y));
+    if !scale.is_finite() || scale <= 0.0 {
+        return None;
+    }
+
+    let (mid_x, mid_y) = ((min_x + max_x) / 2.0, (min_y + max_y) / 2.0);
+    let fitted = projected.map(|(x, y)| (cx + (x - mid_x) * scale, cy + (y - mid_y) * scale));
+    Homography::from_points(&src, &fitted)
+}
+
+struct Viewpoint(CameraProfile);
+
+impl Augmentation for Viewpoint {
+    fn name(&self) -> &'static str {
+        "camera_viewpoint"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0.odds.viewpoint
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let p = &self.0;
+        let (w, h) = canvas.image.dimensions();
+        let (w, h) = (w as f64, h as f64);
+        let focal = rng.gen_range(p.focal_factor.0..=p.focal_factor.1) * w.max(h);
+        let yaw = rng.gen_range(-p.max_yaw..=p.max_yaw);
+        let pitch = rng.gen_range(-p.max_pitch..=p.max_pitch);
+        let roll = rng.gen_range(-p.max_roll..=p.max_roll);
+
+        let Some(homography) = camera_homography(w, h, focal, yaw, pitch, roll) else {
+            return json!({ "skipped": true });
+        };
+        let Some(warped) = warp_perspective(&canvas.image, &homography, None) else {
+            return json!({ "skipped": true });
+        };
+        canvas.image = warped;
+        canvas.map_quads(&homography);
+        json!({
+            "focal_px": round(focal, 1),
+            "yaw": round(yaw, 2),
+            "pitch": round(pitch, 2),
+            "roll": round(roll, 2),
+            "homography": homography.to_json(),
+        })
+    }
+}
+
+/// Radial distortion factor for a point at normalized radius squared `r2`.
+fn radial(k1: f64, k2: f64, r2: f64) -> f64 {
+    1.0 + k1 * r2 + k2 * r2 * r2
+}
+
+struct LensDistortion(CameraProfile);
+
+impl Augmentation for LensDistortion {
+    fn name(&self) -> &'static str {
+        "lens_distortion"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0.odds.lens_distortion
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let k1 = rng.gen_range(self.0.k1.0..=self.0.k1.1);
+        let k2 = k1 * rng.gen_range(-0.3..=0.3);
+        let (w, h) = canvas.image.dimensions();
+        let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+        let norm = cx.hypot(cy);
+
+        // Each output pixel samples the undistorted source at the distorted radius.
+        let source = &canvas.image;
+        let distorted = RgbImage::from_fn(w, h, |x, y| {
+            let (dx, dy) = ((x as f64 - cx) / norm, (y as f64 - cy) / norm);
+            let f = radial(k1, k2, dx * dx + dy * dy);
+            sample_bilinear(source, cx + dx * f * norm, cy + dy * f * norm)
+        });
+        canvas.image = distorted;
+
+        // Quads move the other way: solve dst * f(|dst|) = src by fixed-point iteration.
+        canvas.map_points(|(x, y)| {
+            let (sx, sy) = ((x - cx) / norm, (y - cy) / norm);
+            let (mut dx, mut dy) = (sx, sy);
+            for _ in 0..20 {
+                let f = radial(k1, k2, dx * dx + dy * dy);
+                dx = sx / f;
+                dy = sy / f;
+            }
+            (cx + dx * norm, cy + dy * norm)
+        });
+        json!({ "k1": round(k1, 4), "k2": round(k2, 4) })
+    }
+}
+
+struct ChromaticAberration(CameraProfile);
+
+impl Augmentation for ChromaticAberration {
+    fn name(&self) -> &'static str {
+        "chromatic_aberration"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0.odds.chromatic_aberration
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let shift = rng.gen_range(self.0.chromatic_shift.0..=self.0.chromatic_shift.1);
+        let (w, h) = canvas.image.dimensions();
+        let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+        let source = &canvas.image;
+        // Green is the reference channel, so text boxes stay where they are.
+        let shifted = RgbImage::from_fn(w, h, |x, y| {
+            let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+            let red = sample_bilinear(source, cx + dx * (1.0 + shift), cy + dy * (1.0 + shift));
+            let blue = sample_bilinear(source, cx + dx * (1.0 - shift), cy + dy * (1.0 - shift));
+            Rgb([red[0], source.get_pixel(x, y)[1], blue[2]])
+        });
+        canvas.image = shifted;
+        json!({ "shift": round(shift, 5) })
+    }
+}
+
+/// Shot noise plus read noise at the sensor, amplified by the gain auto-exposure applies to
+/// bring an under-exposed frame back up.
+struct SensorNoise(CameraProfile);
+
+impl Augmentation for SensorNoise {
+    fn name(&self) -> &'static str {
+        "sensor_noise"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0.odds.sensor_noise
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let ev = rng.gen_range(self.0.exposure_ev.0..=self.0.exposure_ev.1);
+        let exposure = 2f64.powf(ev);
+        let gain = if ev < 0.0 { 1.0 / exposure } else { 1.0 };
+        let shot = rng.gen_range(0.02..=0.08);
+        let read = rng.gen_range(0.5..=2.5);
+        for pixel in canvas.image.pixels_mut() {
+            for c in 0..3 {
+                // Over-exposed highlights clip at the sensor, before gain.
+                let signal = (pixel[c] as f64 * exposure).min(255.0);
+                let sigma = (shot * signal + read * read).sqrt();
+                pixel[c] = clamp_u8((signal + gaussian(rng) * sigma) * gain);
+            }
+        }
+        json!({
+            "exposure_ev": round(ev, 2),
+            "gain": round(gain, 2),
+            "shot": round(shot, 3),
+            "read": round(read, 2),
+        })
+    }
+}
+
+/// Auto-white-balance misjudging the illuminant: independent red and blue channel gains.
+struct WhiteBalance(CameraProfile);
+
+impl Augmentation for WhiteBalance {
+    fn name(&self) -> &'static str {
+        "white_balance"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0.odds.white_balance
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let s = self.0.white_balance_shift;
+        let gains = [
+            1.0 + rng.gen_range(-s..=s),
+            1.0,
+            1.0 + rng.gen_range(-s..=s),
+        ];
+        for pixel in canvas.image.pixels_mut() {
+            for c in 0..3 {
+                pixel[c] = clamp_u8(pixel[c] as f64 * gains[c]);
+            }
+        }
+        json!({ "gains": gains.map(|g| round(g, 3)) })
+    }
+}
+
+/// One or more lossy JPEG round trips, as when a photo is shared and re-shared.
+struct Recompression(CameraProfile);
+
+impl Augmentation for Recompression {
+    fn name(&self) -> &'static str {
+        "recompression"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0.odds.recompression
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let rounds = rng.gen_range(1..=self.0.max_recompressions.max(1));
+        let mut qualities = Vec::new();
+        for _ in 0..rounds {
+            let quality = rng.gen_range(self.0.jpeg_quality.0..=self.0.jpeg_quality.1);
+            let mut buffer = Vec::new();
+            let decoded = JpegEncoder::new_with_quality(&mut buffer, quality)
+                .encode_image(&canvas.image)
+                .ok()
+                .and_then(|_| image::load_from_memory(&buffer).ok());
+            if let Some(decoded) = decoded {
+                canvas.image = decoded.to_rgb8();
+                qualities.push(quality);
+            }
+        }
+        json!({ "qualities": qualities })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_frontal_camera_is_identity() {
+        let h = camera_homography(400.0, 300.0, 500.0, 0.0, 0.0, 0.0).unwrap();
+        let (x, y) = h.map((123.0, 45.0));
+        assert!((x - 123.0).abs() < 1e-6 && (y - 45.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_lens_distortion_moves_boxes_with_pixels() {
+        let quad: Quad = [(10.0, 10.0), (30.0, 10.0), (30.0, 20.0), (10.0, 20.0)];
+        let mut canvas = Canvas::new(RgbImage::new(100, 80), vec![quad]);
+        let profile = CameraProfile {
+            k1: (-0.1, -0.1),
+            ..CameraProfile::default()
+        };
+        LensDistortion(profile).apply(&mut canvas, &mut StdRng::seed_from_u64(5));
+
+        // Pincushion distortion pushes points away from the centre, so the top-left corner
+        // moves further up and left.
+        let (x, y) = canvas.quads[0][0];
+        assert!(x < 10.0 && y < 10.0, "corner moved to {:?}", (x, y));
+    }
+
+    #[test]
+    fn test_profile_odds_decide_which_stages_fire() {
+        let profile = CameraProfile {
+            odds: CameraOdds {
+                viewpoint: 0.0,
+                lens_distortion: 0.0,
+                chromatic_aberration: 1.0,
+                sensor_noise: 0.0,
+                white_balance: 1.0,
+                recompression: 0.0,
+            },
+            ..CameraProfile::default()
+        };
+        let mut canvas = Canvas::new(RgbImage::new(40, 30), vec![]);
+        let applied = profile
+            .chain()
+            .apply(&mut canvas, &mut StdRng::seed_from_u64(2));
+        let ops: Vec<&str> = applied.iter().filter_map(|r| r["op"].as_str()).collect();
+        assert_eq!(ops, ["chromatic_aberration", "white_balance"]);
+    }
+}
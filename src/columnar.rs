@@ -0,0 +1,441 @@
+use arrow_array::builder::{BinaryBuilder, StringBuilder};
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray, StructArray,
+};
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use parquet::format::KeyValue;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::sync::Arc;
+
+use crate::sinks::{image_size, ArchiveWriter, EncodedSample};
+
+// Parquet export in the layout Hugging Face `datasets` loads as-is: `data/<split>-NNNNN.parquet`
+// files carrying their `features` in the `huggingface` schema metadata, plus a README whose
+// front matter maps splits to files and names the class labels.
+
+// Row groups per file before a new file is started.
+const FILE_ROW_GROUPS: usize = 40;
+
+// Share of samples in each split.
+pub const SPLITS: [(&str, f64); 3] = [("train", 0.9), ("validation", 0.05), ("test", 0.05)];
+
+// Keys of the sample `style` map, each exported as its own `style_<key>` column.
+const STYLE_COLUMNS: [&str; 10] = [
+    "method",
+    "background",
+    "font_file",
+    "typography",
+    "paragraph",
+    "synthetic",
+    "font_mix",
+    "span_typography",
+    "scene",
+    "composition",
+];
+
+/// Deterministic split assignment by phrase, so a text lands in the same split for every font
+/// and across runs, and no phrase is seen in more than one split.
+pub fn split_for(phrase: &str) -> &'static str {
+    let digest = Sha256::digest(phrase.as_bytes());
+    let draw = u64::from_be_bytes(digest[..8].try_into().unwrap()) as f64 / u64::MAX as f64;
+    let mut edge = 0.0;
+    for (split, share) in SPLITS {
+        edge += share;
+        if draw < edge {
+            return split;
+        }
+    }
+    SPLITS[0].0
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParquetConfig {
+    pub row_group_rows: usize,
+    /// Names of the `label` column's classes, indexed by id.
+    pub class_names: Vec<String>,
+}
+
+fn string_field(name: &str, nullable: bool) -> Field {
+    Field::new(name, DataType::Utf8, nullable)
+}
+
+fn image_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("bytes", DataType::Binary, true),
+        Field::new("path", DataType::Utf8, true),
+    ])
+}
+
+/// Arrow schema of the exported rows. Nested metadata without a fixed shape is kept as JSON
+/// text.
+fn schema() -> SchemaRef {
+    let mut fields = vec![
+        string_field("key", false),
+        Field::new("image", DataType::Struct(image_fields()), false),
+        Field::new("label", DataType::Int64, true),
+        string_field("label_name", true),
+        string_field("font", false),
+        string_field("phrase", false),
+        string_field("split", false),
+        Field::new("synthetic", DataType::Boolean, false),
+        Field::new("width", DataType::Int64, true),
+        Field::new("height", DataType::Int64, true),
+        Field::new("jpeg_quality", DataType::Int64, true),
+        string_field("layout_outcome", true),
+        Field::new("font_size", DataType::Float64, true),
+        Field::new("original_font_size", DataType::Float64, true),
+        string_field("scan_profile", true),
+        Field::new("camera", DataType::Boolean, false),
+    ];
+    fields.extend(
+        STYLE_COLUMNS
+            .iter()
+            .map(|key| string_field(&format!("style_{}", key), true)),
+    );
+    fields.extend(
+        ["labels", "blocks", "spans", "augmentations"]
+            .iter()
+            .map(|name| string_field(name, true)),
+    );
+    Arc::new(Schema::new(fields))
+}
+
+/// The `features` dict of `datasets`, matching `schema()` column for column.
+fn features(class_names: &[String]) -> Value {
+    let mut features = Map::new();
+    for field in schema().fields() {
+        let feature = match (field.name().as_str(), field.data_type()) {
+            ("image", _) => json!({ "_type": "Image" }),
+            ("label", _) => json!({ "_type": "ClassLabel", "names": class_names }),
+            (_, DataType::Utf8) => json!({ "_type": "Value", "dtype": "string" }),
+            (_, DataType::Int64) => json!({ "_type": "Value", "dtype": "int64" }),
+            (_, DataType::Float64) => json!({ "_type": "Value", "dtype": "float64" }),
+            (_, DataType::Boolean) => json!({ "_type": "Value", "dtype": "bool" }),
+            (_, other) => json!({ "_type": "Value", "dtype": other.to_string() }),
+        };
+        features.insert(field.name().clone(), feature);
+    }
+    Value::Object(features)
+}
+
+/// The README front matter `datasets` reads the configuration and class names from.
+fn readme(class_names: &[String], splits: &[&str]) -> String {
+    let mut readme = String::from("---\nconfigs:\n- config_name: default\n  data_files:\n");
+    for split in splits {
+        readme.push_str(&format!(
+            "  - split: {}\n    path: data/{}-*.parquet\n",
+            split, split
+        ));
+    }
+    readme.push_str("dataset_info:\n  features:\n  - name: image\n    dtype: image\n");
+    readme.push_str("  - name: label\n    dtype:\n      class_label:\n        names:\n");
+    for (id, name) in class_names.iter().enumerate() {
+        readme.push_str(&format!("          '{}': {}\n", id, json!(name)));
+    }
+    readme.push_str("---\n\nRendered text samples; see `dataset_info.json` for all columns.\n");
+    readme
+}
+
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+fn strings(records: &[Value], get: impl Fn(&Value) -> Option<String>) -> ArrayRef {
+    let values: Vec<Option<String>> = records.iter().map(get).collect();
+    Arc::new(StringArray::from(values))
+}
+
+fn integers(records: &[Value], get: impl Fn(&Value) -> &Value) -> ArrayRef {
+    let values: Vec<Option<i64>> = records.iter().map(|r| get(r).as_i64()).collect();
+    Arc::new(Int64Array::from(values))
+}
+
+fn floats(records: &[Value], get: impl Fn(&Value) -> &Value) -> ArrayRef {
+    let values: Vec<Option<f64>> = records.iter().map(|r| get(r).as_f64()).collect();
+    Arc::new(Float64Array::from(values))
+}
+
+fn flags(records: &[Value], key: &str) -> ArrayRef {
+    let values: Vec<bool> = records
+        .iter()
+        .map(|r| r[key].as_bool().unwrap_or(false))
+        .collect();
+    Arc::new(BooleanArray::from(values))
+}
+
+fn to_batch(samples: &[Arc<EncodedSample>], split: &str) -> Result<RecordBatch, String> {
+    let records: Vec<Value> = samples.iter().map(|s| s.record()).collect();
+    let records = records.as_slice();
+    let mut bytes = BinaryBuilder::new();
+    let mut paths = StringBuilder::new();
+    for sample in samples {
+        bytes.append_value(&sample.image);
+        paths.append_value(format!("{}.{}", sample.key, sample.extension()));
+    }
+    let image = StructArray::new(
+        image_fields(),
+        vec![Arc::new(bytes.finish()), Arc::new(paths.finish())],
+        None,
+    );
+
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            samples.iter().map(|s| s.key.as_str()),
+        )),
+        Arc::new(image),
+        integers(records, |m| &m["label"]["id"]),
+        strings(records, |m| text(&m["label"]["name"])),
+        strings(records, |m| text(&m["font"])),
+        strings(records, |m| text(&m["phrase"])),
+        Arc::new(StringArray::from(vec![split; samples.len()])),
+        flags(records, "synthetic"),
+        integers(records, |m| &image_size(m)[0]),
+        integers(records, |m| &image_size(m)[1]),
+        integers(records, |m| &m["jpeg_quality"]),
+        strings(records, |m| text(&m["layout"]["outcome"])),
+        floats(records, |m| &m["layout"]["font_size"]),
+        floats(records, |m| &m["layout"]["original_font_size"]),
+        strings(records, |m| text(&m["scan_profile"])),
+        flags(records, "camera"),
+    ];
+    for key in STYLE_COLUMNS {
+        columns.push(strings(records, |m| text(&m["style"][key])));
+    }
+    for key in ["labels", "blocks", "spans", "augmentations"] {
+        columns.push(strings(records, |m| text(&m[key])));
+    }
+    RecordBatch::try_new(schema(), columns).map_err(|e| format!("Invalid record batch: {}", e))
+}
+
+struct SplitFiles {
+    writer: Option<ArrowWriter<File>>,
+    files: usize,
+    groups: usize,
+    rows: usize,
+    pending: Vec<Arc<EncodedSample>>,
+}
+
+/// Writes samples into one run of Parquet files per split under `dir/data`, a row group
+/// every `row_group_rows` samples.
+pub struct ParquetSet {
+    dir: String,
+    config: ParquetConfig,
+    properties: WriterProperties,
+    splits: BTreeMap<&'static str, SplitFiles>,
+}
+
+impl ParquetSet {
+    pub fn new(dir: &str, config: ParquetConfig) -> Result<ParquetSet, String> {
+        fs::create_dir_all(format!("{}/data", dir))
+            .map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+        let huggingface = json!({ "info": { "features": features(&config.class_names) } });
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(config.row_group_rows)
+            .set_key_value_metadata(Some(vec![KeyValue::new(
+                "huggingface".to_string(),
+                huggingface.to_string(),
+            )]))
+            .build();
+        Ok(ParquetSet {
+            dir: dir.to_string(),
+            config,
+            properties,
+            splits: BTreeMap::new(),
+        })
+    }
+
+    fn flush(&mut self, split: &'static str) -> Result<(), String> {
+        let Some(files) = self.splits.get_mut(split) else {
+            return Ok(());
+        };
+        if files.pending.is_empty() {
+            return Ok(());
+        }
+        if files.writer.is_none() {
+            let path = format!("{}/data/{}-{:05}.parquet", self.dir, split, files.files);
+            let file =
+                File::create(&path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+            let writer = ArrowWriter::try_new(file, schema(), Some(self.properties.clone()))
+                .map_err(|e| format!("Failed to start {}: {}", path, e))?;
+            files.writer = Some(writer);
+            files.files += 1;
+        }
+
+        let batch = to_batch(&files.pending, split)?;
+        let writer = files.writer.as_mut().unwrap();
+        writer
+            .write(&batch)
+            .and_then(|_| writer.flush())
+            .map_err(|e| format!("Failed to write {} row group: {}", split, e))?;
+        files.rows += files.pending.len();
+        files.groups += 1;
+        files.pending.clear();
+
+        if files.groups % FILE_ROW_GROUPS == 0 {
+            if let Some(writer) = files.writer.take() {
+                writer
+                    .close()
+                    .map_err(|e| format!("Failed to finish {} file: {}", split, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ArchiveWriter for ParquetSet {
+    fn push(&mut self, sample: Arc<EncodedSample>) -> Result<(), String> {
+        // The split the sample was generated for; older samples without one are split by
+        // their phrase.
+        let split = SPLITS
+            .iter()
+            .map(|(split, _)| *split)
+            .find(|split| sample.metadata["split"] == *split)
+            .unwrap_or_else(|| split_for(sample.metadata["phrase"].as_str().unwrap_or_default()));
+        let files = self.splits.entry(split).or_insert_with(|| SplitFiles {
+            writer: None,
+            files: 0,
+            groups: 0,
+            rows: 0,
+            pending: Vec::new(),
+        });
+        files.pending.push(sample);
+        if files.pending.len() >= self.config.row_group_rows {
+            self.flush(split)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining rows, closes every file and writes `dataset_info.json` and
+    /// `README.md`.
+    fn finish(mut self: Box<Self>) -> Result<Value, String> {
+        let splits: Vec<&'static str> = self.splits.keys().copied().collect();
+        let mut summary = Map::new();
+        for split in &splits {
+            self.flush(split)?;
+            let files = self.splits.get_mut(split).unwrap();
+            if let Some(writer) = files.writer.take() {
+                writer
+                    .close()
+                    .map_err(|e| format!("Failed to finish {} file: {}", split, e))?;
+            }
+            summary.insert(
+                split.to_string(),
+                json!({ "num_examples": files.rows, "files": files.files }),
+            );
+        }
+
+        let info = json!({
+            "features": features(&self.config.class_names),
+            "splits": summary,
+            "row_group_rows": self.config.row_group_rows,
+        });
+        let path = format!("{}/dataset_info.json", self.dir);
+        fs::write(
+            &path,
+            serde_json::to_string_pretty(&info).unwrap_or_default(),
+        )
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        let path = format!("{}/README.md", self.dir);
+        fs::write(&path, readme(&self.config.class_names, &splits))
+            .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn sample(index: usize) -> Arc<EncodedSample> {
+        Arc::new(EncodedSample {
+            key: EncodedSample::key_for("Vazir", index),
+            font: "Vazir".to_string(),
+            index,
+            image: vec![0xff, 0xd8, index as u8],
+            metadata: json!({
+                "font": "Vazir",
+                "label": { "name": "Vazir", "id": 1 },
+                "phrase": "سلام",
+                "split": SPLITS[index % SPLITS.len()].0,
+                "viewport": [640, 480],
+                "jpeg_quality": 90,
+                "encoding": { "resize": { "policy": "letterbox", "size": 224 }, "size": [224, 224] },
+                "layout": { "outcome": "fits", "font_size": 32.0 },
+                "style": { "method": "random", "typography": { "features": ["ss01"] } },
+                "camera": false,
+            }),
+            annotations: json!({ "blocks": [] }),
+        })
+    }
+
+    #[test]
+    fn rows_round_trip_with_features_metadata() {
+        let dir = std::env::temp_dir().join(format!("parquet-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let config = ParquetConfig {
+            row_group_rows: 4,
+            class_names: vec!["Sahel".to_string(), "Vazir".to_string()],
+        };
+        let mut set = Box::new(ParquetSet::new(dir, config).unwrap());
+        for i in 0..40 {
+            set.push(sample(i)).unwrap();
+        }
+        let info = set.finish().unwrap();
+        let train_rows = info["splits"]["train"]["num_examples"].as_u64().unwrap();
+        let total: u64 = SPLITS
+            .iter()
+            .filter_map(|(split, _)| info["splits"][split]["num_examples"].as_u64())
+            .sum();
+        assert_eq!(total, 40);
+        // Rows follow the split recorded at generation, not one derived from the key.
+        assert_eq!(train_rows, 14);
+
+        let file = File::open(format!("{}/data/train-00000.parquet", dir)).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+        let metadata = builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap();
+        assert!(metadata[0].value.as_ref().unwrap().contains("ClassLabel"));
+        assert_eq!(builder.metadata().row_group(0).num_rows(), 4);
+
+        let batches: Vec<RecordBatch> = builder.build().unwrap().map(Result::unwrap).collect();
+        let rows: usize = batches.iter().map(RecordBatch::num_rows).sum();
+        assert_eq!(rows as u64, train_rows);
+        let batch = &batches[0];
+        let labels = batch["label"]
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(labels.value(0), 1);
+        // Letterboxed images are exported at the encoded size, not the viewport's.
+        for (column, size) in [("width", 224), ("height", 224)] {
+            let values = batch[column].as_any().downcast_ref::<Int64Array>().unwrap();
+            assert_eq!(values.value(0), size);
+        }
+        let typography = batch["style_typography"]
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(typography.value(0), r#"{"features":["ss01"]}"#);
+
+        assert!(fs::read_to_string(format!("{}/README.md", dir))
+            .unwrap()
+            .contains("          '1': \"Vazir\""));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,523 @@
+use image::{imageops, GrayImage, Luma, Rgb, RgbImage};
+use rand::{Rng, RngCore};
+use serde_json::{json, Value};
+
+use crate::augment::{
+    clamp_u8, gaussian, round, warp_perspective, Augmentation, AugmentationChain, Canvas,
+    Homography, Quad,
+};
+
+// Scanned/photocopied document simulation. Every effect takes a `severity` in 0..=1, where 0
+// disables it and 1 is the strongest degradation we still expect to see in real scans.
+
+/// Severity of each effect in a scan profile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScanProfile {
+    pub ink_bleed: f64,
+    pub ink_erosion: f64,
+    pub paper_texture: f64,
+    pub toner_streaks: f64,
+    pub binarization: f64,
+    pub skew: f64,
+    pub fold_shadow: f64,
+    pub halftone: f64,
+}
+
+impl ScanProfile {
+    pub fn light() -> Self {
+        Self {
+            ink_bleed: 0.2,
+            ink_erosion: 0.1,
+            paper_texture: 0.4,
+            toner_streaks: 0.1,
+            binarization: 0.0,
+            skew: 0.3,
+            fold_shadow: 0.1,
+            halftone: 0.0,
+        }
+    }
+
+    pub fn medium() -> Self {
+        Self {
+            ink_bleed: 0.4,
+            ink_erosion: 0.3,
+            paper_texture: 0.6,
+            toner_streaks: 0.3,
+            binarization: 0.3,
+            skew: 0.5,
+            fold_shadow: 0.3,
+            halftone: 0.2,
+        }
+    }
+
+    pub fn heavy() -> Self {
+        Self {
+            ink_bleed: 0.8,
+            ink_erosion: 0.6,
+            paper_texture: 0.9,
+            toner_streaks: 0.6,
+            binarization: 0.6,
+            skew: 0.9,
+            fold_shadow: 0.6,
+            halftone: 0.5,
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "light" => Some(Self::light()),
+            "medium" => Some(Self::medium()),
+            "heavy" => Some(Self::heavy()),
+            _ => None,
+        }
+    }
+
+    /// Parses `[preset][,effect=severity...]`, e.g. `heavy,skew=0` or `halftone=0.8`. Effects
+    /// override the preset, `medium` when none is named.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut items = spec.split(',').map(str::trim).peekable();
+        let mut profile = match items.peek() {
+            Some(name) if !name.contains('=') => {
+                let name = items.next().unwrap_or_default();
+                Self::by_name(name).ok_or_else(|| format!("Unknown scan profile {}", name))?
+            }
+            _ => Self::medium(),
+        };
+        for item in items.filter(|item| !item.is_empty()) {
+            let (effect, severity) = item
+                .split_once('=')
+                .ok_or_else(|| format!("Expected effect=severity, got {}", item))?;
+            let severity = severity
+                .parse::<f64>()
+                .ok()
+                .filter(|s| (0.0..=1.0).contains(s))
+                .ok_or_else(|| format!("Severity of {} must be between 0 and 1", effect))?;
+            let field = match effect {
+                "ink_bleed" => &mut profile.ink_bleed,
+                "ink_erosion" => &mut profile.ink_erosion,
+                "paper_texture" => &mut profile.paper_texture,
+                "toner_streaks" => &mut profile.toner_streaks,
+                "binarization" => &mut profile.binarization,
+                "skew" => &mut profile.skew,
+                "fold_shadow" => &mut profile.fold_shadow,
+                "halftone" => &mut profile.halftone,
+                _ => return Err(format!("Unknown scan effect {}", effect)),
+            };
+            *field = severity;
+        }
+        Ok(profile)
+    }
+
+    /// Builds the effects in the order a physical scan produces them: print (bleed, erosion,
+    /// halftone), paper, then the scanner (streaks, fold shadow, skew, thresholding). Effects
+    /// with zero severity are left out; the rest fire with probability equal to their severity.
+    pub fn chain(&self) -> AugmentationChain {
+        let effects: Vec<Box<dyn Augmentation>> = vec![
+            Box::new(InkBleed(self.ink_bleed)),
+            Box::new(InkErosion(self.ink_erosion)),
+            Box::new(Halftone(self.halftone)),
+            Box::new(PaperTexture(self.paper_texture)),
+            Box::new(TonerStreaks(self.toner_streaks)),
+            Box::new(FoldShadow(self.fold_shadow)),
+            Box::new(ScannerSkew(self.skew)),
+            Box::new(Binarization(self.binarization)),
+        ];
+        AugmentationChain::new(
+            effects
+                .into_iter()
+                .filter(|effect| effect.probability() > 0.0)
+                .collect(),
+        )
+    }
+}
+
+fn luminance(pixel: &Rgb<u8>) -> f64 {
+    0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64
+}
+
+/// Separable min (`darken = true`) or max filter with a square window of `radius`.
+fn rank_filter(img: &RgbImage, radius: u32, darken: bool) -> RgbImage {
+    let pick = |a: u8, b: u8| if darken { a.min(b) } else { a.max(b) };
+    let pass = |src: &RgbImage, horizontal: bool| {
+        let (w, h) = src.dimensions();
+        RgbImage::from_fn(w, h, |x, y| {
+            let mut out = *src.get_pixel(x, y);
+            for d in 1..=radius {
+                let neighbours = if horizontal {
+                    [(x.saturating_sub(d), y), ((x + d).min(w - 1), y)]
+                } else {
+                    [(x, y.saturating_sub(d)), (x, (y + d).min(h - 1))]
+                };
+                for (nx, ny) in neighbours {
+                    let p = src.get_pixel(nx, ny);
+                    for c in 0..3 {
+                        out[c] = pick(out[c], p[c]);
+                    }
+                }
+            }
+            out
+        })
+    };
+    pass(&pass(img, true), false)
+}
+
+fn blend(base: &mut RgbImage, top: &RgbImage, alpha: f64) {
+    for (b, t) in base.pixels_mut().zip(top.pixels()) {
+        for c in 0..3 {
+            b[c] = clamp_u8(b[c] as f64 * (1.0 - alpha) + t[c] as f64 * alpha);
+        }
+    }
+}
+
+/// Smooth random field in 0..1, generated on a coarse grid and upscaled.
+fn low_frequency_noise(w: u32, h: u32, cell: u32, rng: &mut dyn RngCore) -> GrayImage {
+    let grid = GrayImage::from_fn((w / cell).max(2), (h / cell).max(2), |_, _| {
+        Luma([rng.gen()])
+    });
+    imageops::resize(&grid, w, h, imageops::FilterType::Triangle)
+}
+
+/// Ink spreading into the paper fibres: dark strokes grow.
+struct InkBleed(f64);
+
+impl Augmentation for InkBleed {
+    fn name(&self) -> &'static str {
+        "ink_bleed"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let radius = if rng.gen_bool(self.0) { 2 } else { 1 };
+        let alpha = rng.gen_range(0.4..=1.0) * (0.5 + self.0 / 2.0);
+        let bled = imageops::blur(&rank_filter(&canvas.image, radius, true), 0.6);
+        blend(&mut canvas.image, &bled, alpha);
+        json!({ "severity": self.0, "radius": radius, "alpha": round(alpha, 2) })
+    }
+}
+
+/// Worn or under-inked print: dark strokes thin out and break up.
+struct InkErosion(f64);
+
+impl Augmentation for InkErosion {
+    fn name(&self) -> &'static str {
+        "ink_erosion"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let eroded = rank_filter(&canvas.image, 1, false);
+        let (w, h) = canvas.image.dimensions();
+        // Erode in patches so strokes break up unevenly rather than thinning uniformly.
+        let mask = low_frequency_noise(w, h, 12, rng);
+        let coverage = 0.3 + 0.6 * self.0;
+        for ((x, y, pixel), e) in canvas.image.enumerate_pixels_mut().zip(eroded.pixels()) {
+            let m = mask.get_pixel(x, y)[0] as f64 / 255.0;
+            if m < coverage {
+                *pixel = *e;
+            }
+        }
+        json!({ "severity": self.0, "coverage": round(coverage, 2) })
+    }
+}
+
+/// Off-white paper with smooth blotches and fine grain.
+struct PaperTexture(f64);
+
+impl Augmentation for PaperTexture {
+    fn name(&self) -> &'static str {
+        "paper_texture"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let (w, h) = canvas.image.dimensions();
+        let blotches = low_frequency_noise(w, h, 48, rng);
+        let tint = [
+            1.0,
+            1.0 - rng.gen_range(0.0..0.04) * self.0,
+            1.0 - rng.gen_range(0.03..0.12) * self.0,
+        ];
+        let blotch_depth = 0.15 * self.0;
+        let grain = 10.0 * self.0;
+        for (x, y, pixel) in canvas.image.enumerate_pixels_mut() {
+            let b = blotches.get_pixel(x, y)[0] as f64 / 255.0;
+            let shade = 1.0 - blotch_depth * b;
+            let noise = gaussian(rng) * grain;
+            for c in 0..3 {
+                pixel[c] = clamp_u8(pixel[c] as f64 * shade * tint[c] + noise);
+            }
+        }
+        json!({ "severity": self.0, "tint": tint.map(|t| round(t, 3)) })
+    }
+}
+
+/// Dark streaks along the feed direction from a dirty drum or scanner glass.
+struct TonerStreaks(f64);
+
+impl Augmentation for TonerStreaks {
+    fn name(&self) -> &'static str {
+        "toner_streaks"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let (w, h) = canvas.image.dimensions();
+        let count = rng.gen_range(1..=(1 + (self.0 * 6.0) as u32));
+        let mut streaks = Vec::new();
+        for _ in 0..count {
+            let x0 = rng.gen_range(0..w);
+            let width = rng.gen_range(1..=(1 + (self.0 * 4.0) as u32));
+            let darkness = rng.gen_range(0.1..=0.2 + 0.5 * self.0);
+            let start = rng.gen_range(0..h / 2);
+            let end = rng.gen_range(h / 2..=h);
+            for y in start..end {
+                // Streaks fade in and out along their length.
+                let fade = ((y - start) as f64 / (end - start).max(1) as f64
+                    * std::f64::consts::PI)
+                    .sin()
+                    .max(0.2);
+                for x in x0..(x0 + width).min(w) {
+                    let pixel = canvas.image.get_pixel_mut(x, y);
+                    for c in 0..3 {
+                        pixel[c] = clamp_u8(pixel[c] as f64 * (1.0 - darkness * fade));
+                    }
+                }
+            }
+            streaks.push(json!({ "x": x0, "width": width, "darkness": round(darkness, 2) }));
+        }
+        json!({ "severity": self.0, "streaks": streaks })
+    }
+}
+
+/// Global threshold with a noisy, spatially varying offset, like a cheap fax or photocopier.
+struct Binarization(f64);
+
+impl Augmentation for Binarization {
+    fn name(&self) -> &'static str {
+        "binarization"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let (w, h) = canvas.image.dimensions();
+        let threshold = rng.gen_range(100.0..=160.0);
+        let drift = low_frequency_noise(w, h, 64, rng);
+        let drift_range = 60.0 * self.0;
+        for (x, y, pixel) in canvas.image.enumerate_pixels_mut() {
+            let d = (drift.get_pixel(x, y)[0] as f64 / 255.0 - 0.5) * drift_range;
+            let jitter = gaussian(rng) * 8.0 * self.0;
+            let value = if luminance(pixel) + jitter > threshold + d {
+                255
+            } else {
+                0
+            };
+            *pixel = Rgb([value, value, value]);
+        }
+        json!({ "severity": self.0, "threshold": round(threshold, 1) })
+    }
+}
+
+/// Small rotation from a sheet fed at an angle; uncovered areas are filled with paper white.
+struct ScannerSkew(f64);
+
+impl Augmentation for ScannerSkew {
+    fn name(&self) -> &'static str {
+        "skew"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let max_angle = 0.5 + 3.5 * self.0;
+        let angle: f64 = rng.gen_range(-max_angle..=max_angle);
+        let (w, h) = canvas.image.dimensions();
+        let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let rotate = |(x, y): (f64, f64)| {
+            let (dx, dy) = (x - cx, y - cy);
+            (cx + dx * cos - dy * sin, cy + dx * sin + dy * cos)
+        };
+        let src: Quad = [
+            (0.0, 0.0),
+            (w as f64, 0.0),
+            (w as f64, h as f64),
+            (0.0, h as f64),
+        ];
+        let dst: Quad = src.map(rotate);
+
+        let rotated = Homography::from_points(&src, &dst).and_then(|homography| {
+            warp_perspective(&canvas.image, &homography, Some(Rgb([250, 250, 248])))
+                .map(|rotated| (homography, rotated))
+        });
+        let Some((homography, rotated)) = rotated else {
+            return json!({ "skipped": true });
+        };
+        canvas.image = rotated;
+        canvas.map_quads(&homography);
+        json!({ "severity": self.0, "angle": round(angle, 2) })
+    }
+}
+
+/// A crease across the page: a soft shadow on one side of the fold and a highlight on the other.
+struct FoldShadow(f64);
+
+impl Augmentation for FoldShadow {
+    fn name(&self) -> &'static str {
+        "fold_shadow"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let (w, h) = canvas.image.dimensions();
+        let vertical = rng.gen_bool(0.6);
+        let span = if vertical { w } else { h };
+        let position = rng.gen_range(0.2..0.8) * span as f64;
+        let width = rng.gen_range(0.02..0.08) * span as f64;
+        let depth = 0.1 + 0.35 * self.0;
+        for (x, y, pixel) in canvas.image.enumerate_pixels_mut() {
+            let coord = if vertical { x } else { y } as f64;
+            let t = (coord - position) / width;
+            let factor = if t < 0.0 {
+                1.0 - depth * (t.exp())
+            } else {
+                1.0 + 0.3 * depth * (-t).exp()
+            };
+            for c in 0..3 {
+                pixel[c] = clamp_u8(pixel[c] as f64 * factor);
+            }
+        }
+        json!({
+            "severity": self.0,
+            "orientation": if vertical { "vertical" } else { "horizontal" },
+            "position": round(position, 1),
+            "depth": round(depth, 2),
+        })
+    }
+}
+
+/// Re-screens the page as printed halftone dots.
+struct Halftone(f64);
+
+impl Augmentation for Halftone {
+    fn name(&self) -> &'static str {
+        "halftone"
+    }
+
+    fn probability(&self) -> f64 {
+        self.0
+    }
+
+    fn apply(&self, canvas: &mut Canvas, rng: &mut dyn RngCore) -> Value {
+        let cell = rng.gen_range(3..=(3 + (self.0 * 4.0) as u32));
+        let (w, h) = canvas.image.dimensions();
+        let mut screened = RgbImage::from_pixel(w, h, Rgb([255, 255, 255]));
+        let half = cell as f64 / 2.0;
+        for cy in (0..h).step_by(cell as usize) {
+            for cx in (0..w).step_by(cell as usize) {
+                let (mut sum, mut count) = (0.0, 0.0);
+                for y in cy..(cy + cell).min(h) {
+                    for x in cx..(cx + cell).min(w) {
+                        sum += luminance(canvas.image.get_pixel(x, y));
+                        count += 1.0;
+                    }
+                }
+                // Dot area proportional to darkness.
+                let darkness = 1.0 - sum / count / 255.0;
+                let radius = (darkness * 2.0 / std::f64::consts::PI).sqrt() * cell as f64;
+                for y in cy..(cy + cell).min(h) {
+                    for x in cx..(cx + cell).min(w) {
+                        let dx = x as f64 - cx as f64 - half + 0.5;
+                        let dy = y as f64 - cy as f64 - half + 0.5;
+                        if (dx * dx + dy * dy).sqrt() <= radius {
+                            screened.put_pixel(x, y, Rgb([0, 0, 0]));
+                        }
+                    }
+                }
+            }
+        }
+        let alpha = 0.3 + 0.6 * self.0;
+        blend(&mut canvas.image, &screened, alpha);
+        json!({ "severity": self.0, "cell": cell, "alpha": round(alpha, 2) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_ink_bleed_grows_dark_strokes() {
+        let mut img = RgbImage::from_pixel(9, 9, Rgb([255, 255, 255]));
+        img.put_pixel(4, 4, Rgb([0, 0, 0]));
+        let bled = rank_filter(&img, 1, true);
+        assert_eq!(bled.get_pixel(3, 3), &Rgb([0, 0, 0]));
+        assert_eq!(bled.get_pixel(1, 1), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn test_skew_keeps_text_quads_in_sync() {
+        let quad: Quad = [(20.0, 20.0), (60.0, 20.0), (60.0, 40.0), (20.0, 40.0)];
+        let mut canvas = Canvas::new(RgbImage::new(80, 60), vec![quad]);
+        let params = ScannerSkew(1.0).apply(&mut canvas, &mut StdRng::seed_from_u64(3));
+        let angle = params["angle"].as_f64().unwrap();
+        assert!(angle.abs() > 1.0, "seed no longer skews: {}", angle);
+        // The quad turns about the image centre by the drawn angle.
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (x, y) = canvas.quads[0][0];
+        let expected = (
+            40.0 - 20.0 * cos + 10.0 * sin,
+            30.0 - 20.0 * sin - 10.0 * cos,
+        );
+        assert!((x - expected.0).abs() < 0.05 && (y - expected.1).abs() < 0.05);
+        assert_eq!(canvas.image.dimensions(), (80, 60));
+    }
+
+    #[test]
+    fn test_scan_spec_overrides_preset_effects() {
+        let profile = ScanProfile::parse("heavy,skew=0,halftone=0.9").unwrap();
+        assert_eq!(profile.skew, 0.0);
+        assert_eq!(profile.halftone, 0.9);
+        assert_eq!(profile.ink_bleed, ScanProfile::heavy().ink_bleed);
+        assert_eq!(
+            ScanProfile::parse("fold_shadow=1").unwrap().skew,
+            ScanProfile::medium().skew
+        );
+        assert!(ScanProfile::parse("smudge=0.5").is_err());
+        assert!(ScanProfile::parse("skew=2").is_err());
+
+        // Every recorded op names the effect that turns it up.
+        let effects = "ink_bleed,ink_erosion,paper_texture,toner_streaks,binarization,skew,\
+                       fold_shadow,halftone";
+        let spec: Vec<String> = effects.split(',').map(|e| format!("{}=1", e)).collect();
+        let chain = ScanProfile::parse(&spec.join(",")).unwrap().chain();
+        let mut canvas = Canvas::new(RgbImage::from_pixel(80, 60, Rgb([255, 255, 255])), vec![]);
+        let applied = chain.apply(&mut canvas, &mut StdRng::seed_from_u64(5));
+        assert_eq!(applied.len(), 8);
+        for record in applied {
+            let op = record["op"].as_str().unwrap();
+            assert!(effects.split(',').any(|e| e == op), "{}", op);
+        }
+    }
+}
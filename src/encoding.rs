@@ -0,0 +1,414 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::{self, FilterType};
+use image::{ColorType, GrayImage, ImageEncoder, Rgb, RgbImage};
+use rand::{Rng, RngCore};
+use serde_json::{json, Value};
+
+use crate::augment::{Point, Quad};
+
+// The last step before a sample leaves the pipeline: an optional crop and resize, the color
+// mode and the file format. Every geometric change is a `Transform`, and the same transform
+// moves the quads, so annotations stay on the pixels they describe.
+
+// Gray used for the bars around a letterboxed image.
+const LETTERBOX_FILL: Rgb<u8> = Rgb([114, 114, 114]);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    /// Lossless; lossy WebP needs libwebp.
+    WebP,
+}
+
+impl ImageFormat {
+    const ALL: [ImageFormat; 3] = [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP];
+
+    pub fn by_name(name: &str) -> Option<ImageFormat> {
+        let name = if name == "jpg" { "jpeg" } else { name };
+        ImageFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Png => "png",
+            ImageFormat::WebP => "webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::WebP => "webp",
+        }
+    }
+}
+
+/// MIME type of an image file extension as written by `ImageFormat::extension`.
+pub fn mime_type(extension: &str) -> &'static str {
+    match extension {
+        "png" => "image/png",
+        "webp" => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    Rgb,
+    Gray,
+    /// Black and white, thresholded per image with Otsu's method.
+    Binary,
+}
+
+impl ColorMode {
+    const ALL: [ColorMode; 3] = [ColorMode::Rgb, ColorMode::Gray, ColorMode::Binary];
+
+    pub fn by_name(name: &str) -> Option<ColorMode> {
+        ColorMode::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorMode::Rgb => "rgb",
+            ColorMode::Gray => "gray",
+            ColorMode::Binary => "binary",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResizePolicy {
+    /// Fit inside an N×N square, padding the rest with gray bars.
+    Letterbox(u32),
+    /// Scale so the shorter side is N pixels, keeping the aspect ratio.
+    ShortestSide(u32),
+}
+
+impl ResizePolicy {
+    /// `letterbox:N` or `short:N`.
+    pub fn parse(spec: &str) -> Option<ResizePolicy> {
+        let (kind, size) = spec.split_once(':')?;
+        let size = size.parse::<u32>().ok().filter(|&size| size > 0)?;
+        match kind {
+            "letterbox" => Some(ResizePolicy::Letterbox(size)),
+            "short" => Some(ResizePolicy::ShortestSide(size)),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, image: &RgbImage) -> (RgbImage, Transform) {
+        let (width, height) = image.dimensions();
+        let target = match self {
+            ResizePolicy::Letterbox(size) => *size as f64 / width.max(height) as f64,
+            ResizePolicy::ShortestSide(size) => *size as f64 / width.min(height) as f64,
+        };
+        let new_width = ((width as f64 * target).round() as u32).max(1);
+        let new_height = ((height as f64 * target).round() as u32).max(1);
+        let resized = imageops::resize(image, new_width, new_height, FilterType::Triangle);
+        // Rounding makes the two axes scale slightly differently; the transform follows it.
+        let scale = (
+            new_width as f64 / width as f64,
+            new_height as f64 / height as f64,
+        );
+        match self {
+            ResizePolicy::Letterbox(size) => {
+                let mut canvas = RgbImage::from_pixel(*size, *size, LETTERBOX_FILL);
+                let left = (*size - new_width) / 2;
+                let top = (*size - new_height) / 2;
+                imageops::replace(&mut canvas, &resized, left as i64, top as i64);
+                let transform = Transform {
+                    scale,
+                    offset: (left as f64, top as f64),
+                };
+                (canvas, transform)
+            }
+            ResizePolicy::ShortestSide(_) => (
+                resized,
+                Transform {
+                    scale,
+                    offset: (0.0, 0.0),
+                },
+            ),
+        }
+    }
+
+    fn to_json(self) -> Value {
+        match self {
+            ResizePolicy::Letterbox(size) => json!({ "policy": "letterbox", "size": size }),
+            ResizePolicy::ShortestSide(size) => json!({ "policy": "short", "size": size }),
+        }
+    }
+}
+
+/// Maps input pixel coordinates to output ones: `x * scale.0 + offset.0`, likewise for y.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub scale: (f64, f64),
+    pub offset: Point,
+}
+
+impl Transform {
+    const IDENTITY: Transform = Transform {
+        scale: (1.0, 1.0),
+        offset: (0.0, 0.0),
+    };
+
+    /// This transform followed by `next`.
+    fn then(self, next: Transform) -> Transform {
+        Transform {
+            scale: (self.scale.0 * next.scale.0, self.scale.1 * next.scale.1),
+            offset: (
+                self.offset.0 * next.scale.0 + next.offset.0,
+                self.offset.1 * next.scale.1 + next.offset.1,
+            ),
+        }
+    }
+
+    pub fn point(&self, (x, y): Point) -> Point {
+        (
+            x * self.scale.0 + self.offset.0,
+            y * self.scale.1 + self.offset.1,
+        )
+    }
+
+    pub fn quad(&self, quad: &Quad) -> Quad {
+        quad.map(|p| self.point(p))
+    }
+}
+
+/// How finished canvases are turned into files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputEncoding {
+    pub format: ImageFormat,
+    /// Inclusive JPEG quality range; one value is drawn per sample.
+    pub quality: (u8, u8),
+    pub color: ColorMode,
+    /// Crop to the text with a random margin on each side, drawn from this range as a
+    /// share of the text's height. Runs before `resize`.
+    pub crop_padding: Option<(f64, f64)>,
+    pub resize: Option<ResizePolicy>,
+}
+
+impl Default for OutputEncoding {
+    fn default() -> Self {
+        OutputEncoding {
+            format: ImageFormat::Jpeg,
+            quality: (77, 99),
+            color: ColorMode::Rgb,
+            crop_padding: None,
+            resize: None,
+        }
+    }
+}
+
+/// An encoded sample image with its quads in output coordinates.
+pub struct EncodedImage {
+    pub bytes: Vec<u8>,
+    pub quads: Vec<Quad>,
+    pub quality: Option<u8>,
+    /// Format, color mode, crop, resize and the transform from page to image pixels.
+    pub metadata: Value,
+}
+
+impl OutputEncoding {
+    pub fn encode(
+        &self,
+        image: RgbImage,
+        quads: Vec<Quad>,
+        rng: &mut dyn RngCore,
+    ) -> Result<EncodedImage, String> {
+        let mut image = image;
+        let mut transform = Transform::IDENTITY;
+
+        let mut crop = Value::Null;
+        if let Some(padding) = self.crop_padding {
+            if let Some((x, y, width, height)) = text_crop(&image, &quads, padding, rng) {
+                image = imageops::crop_imm(&image, x, y, width, height).to_image();
+                transform = transform.then(Transform {
+                    scale: (1.0, 1.0),
+                    offset: (-(x as f64), -(y as f64)),
+                });
+                crop = json!([x, y, width, height]);
+            }
+        }
+        if let Some(policy) = self.resize {
+            let (resized, step) = policy.apply(&image);
+            image = resized;
+            transform = transform.then(step);
+        }
+        let quads: Vec<Quad> = quads.iter().map(|quad| transform.quad(quad)).collect();
+
+        let (width, height) = image.dimensions();
+        let (pixels, color) = match self.color {
+            ColorMode::Rgb => (image.into_raw(), ColorType::Rgb8),
+            ColorMode::Gray => (imageops::grayscale(&image).into_raw(), ColorType::L8),
+            ColorMode::Binary => (
+                binarize(&imageops::grayscale(&image)).into_raw(),
+                ColorType::L8,
+            ),
+        };
+
+        let quality = match self.format {
+            ImageFormat::Jpeg => Some(rng.gen_range(self.quality.0..=self.quality.1)),
+            _ => None,
+        };
+        let mut bytes = Vec::new();
+        let written = match self.format {
+            ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut bytes, quality.unwrap_or(90))
+                .write_image(&pixels, width, height, color),
+            ImageFormat::Png => {
+                PngEncoder::new(&mut bytes).write_image(&pixels, width, height, color)
+            }
+            ImageFormat::WebP => {
+                WebPEncoder::new_lossless(&mut bytes).write_image(&pixels, width, height, color)
+            }
+        };
+        written.map_err(|e| format!("Failed to encode image: {}", e))?;
+
+        let metadata = json!({
+            "format": self.format.name(),
+            "extension": self.format.extension(),
+            "quality": quality,
+            "color": self.color.name(),
+            "crop": crop,
+            "resize": self.resize.map(ResizePolicy::to_json),
+            "transform": {
+                "scale": [transform.scale.0, transform.scale.1],
+                "offset": [transform.offset.0, transform.offset.1],
+            },
+            "size": [width, height],
+        });
+        Ok(EncodedImage {
+            bytes,
+            quads,
+            quality,
+            metadata,
+        })
+    }
+}
+
+/// `(x, y, width, height)` of the smallest box around every quad, grown by a random margin
+/// per side and clipped to the image. `None` without quads.
+fn text_crop(
+    image: &RgbImage,
+    quads: &[Quad],
+    (min, max): (f64, f64),
+    rng: &mut dyn RngCore,
+) -> Option<(u32, u32, u32, u32)> {
+    let points = || quads.iter().flatten();
+    let left = points().map(|p| p.0).reduce(f64::min)?;
+    let right = points().map(|p| p.0).reduce(f64::max)?;
+    let top = points().map(|p| p.1).reduce(f64::min)?;
+    let bottom = points().map(|p| p.1).reduce(f64::max)?;
+    let text_height = bottom - top;
+    let mut margin = || rng.gen_range(min..=max) * text_height;
+
+    let (width, height) = (image.width() as f64, image.height() as f64);
+    let x0 = (left - margin()).floor().clamp(0.0, width - 1.0);
+    let y0 = (top - margin()).floor().clamp(0.0, height - 1.0);
+    let x1 = (right + margin()).ceil().clamp(x0 + 1.0, width);
+    let y1 = (bottom + margin()).ceil().clamp(y0 + 1.0, height);
+    Some((x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32))
+}
+
+/// Otsu's threshold: the gray level that best separates the histogram into two classes.
+fn otsu_threshold(image: &GrayImage) -> u8 {
+    let mut histogram = [0u64; 256];
+    for pixel in image.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+    let total = image.pixels().len() as f64;
+    let sum: f64 = (0..256).map(|i| i as f64 * histogram[i] as f64).sum();
+
+    let (mut background, mut background_sum) = (0.0, 0.0);
+    let (mut best, mut best_variance) = (0u8, -1.0);
+    for (level, &count) in histogram.iter().enumerate() {
+        background += count as f64;
+        background_sum += level as f64 * count as f64;
+        let foreground = total - background;
+        if background == 0.0 || foreground == 0.0 {
+            continue;
+        }
+        let mean_background = background_sum / background;
+        let mean_foreground = (sum - background_sum) / foreground;
+        let variance = background * foreground * (mean_background - mean_foreground).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best = level as u8;
+        }
+    }
+    best
+}
+
+fn binarize(image: &GrayImage) -> GrayImage {
+    let threshold = otsu_threshold(image);
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let level = image.get_pixel(x, y)[0];
+        image::Luma([if level > threshold { 255 } else { 0 }])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn crop_and_letterbox_move_quads_with_the_pixels() {
+        // A dark 30×20 "word" on a light 200×100 page.
+        let mut page = RgbImage::from_pixel(200, 100, Rgb([230, 230, 230]));
+        for (x, y, pixel) in page.enumerate_pixels_mut() {
+            if (85..115).contains(&x) && (40..60).contains(&y) {
+                *pixel = Rgb([20, 20, 20]);
+            }
+        }
+        let quad: Quad = [(85.0, 40.0), (115.0, 40.0), (115.0, 60.0), (85.0, 60.0)];
+        let encoding = OutputEncoding {
+            format: ImageFormat::Png,
+            color: ColorMode::Binary,
+            crop_padding: Some((0.5, 0.5)),
+            resize: Some(ResizePolicy::Letterbox(100)),
+            ..OutputEncoding::default()
+        };
+        let output = encoding
+            .encode(page, vec![quad], &mut StdRng::seed_from_u64(7))
+            .unwrap();
+
+        // Cropped to 50×40 around the word with 10px margins, then scaled 2× and centred
+        // vertically in a 100×100 square.
+        assert_eq!(output.metadata["crop"], json!([75, 30, 50, 40]));
+        assert_eq!(output.metadata["size"], json!([100, 100]));
+        assert_eq!(
+            output.quads[0],
+            [(20.0, 30.0), (80.0, 30.0), (80.0, 70.0), (20.0, 70.0)]
+        );
+        assert_eq!(output.quality, None);
+
+        let decoded = image::load_from_memory(&output.bytes).unwrap().to_luma8();
+        assert_eq!(decoded.get_pixel(50, 50)[0], 0);
+        assert_eq!(decoded.get_pixel(10, 50)[0], 255);
+    }
+
+    #[test]
+    fn parses_resize_specs_and_format_names() {
+        assert_eq!(
+            ResizePolicy::parse("letterbox:640"),
+            Some(ResizePolicy::Letterbox(640))
+        );
+        assert_eq!(
+            ResizePolicy::parse("short:256"),
+            Some(ResizePolicy::ShortestSide(256))
+        );
+        assert_eq!(ResizePolicy::parse("short:0"), None);
+        assert_eq!(ImageFormat::by_name("jpg"), Some(ImageFormat::Jpeg));
+        assert_eq!(mime_type(ImageFormat::WebP.extension()), "image/webp");
+    }
+}
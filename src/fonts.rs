@@ -0,0 +1,449 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rand::{Rng, RngCore};
+use serde_json::{json, Map, Value};
+use ttf_parser::{name_id, Face};
+
+// Features a shaper applies by default that can be switched off without breaking the script.
+// Required Arabic forms (init, medi, fina, isol, rlig, ccmp, mark, mkmk) are never touched.
+const DEFAULT_ON_FEATURES: [&str; 4] = ["liga", "clig", "calt", "kern"];
+// Discretionary features that are only visible when switched on.
+const DEFAULT_OFF_FEATURES: [&str; 5] = ["dlig", "swsh", "cswh", "salt", "hlig"];
+
+const DISABLE_PROBABILITY: f64 = 0.2;
+const ENABLE_PROBABILITY: f64 = 0.3;
+// Chance of leaving an axis at its default, so the default instance stays well represented.
+const AXIS_DEFAULT_PROBABILITY: f64 = 0.2;
+
+/// Container format of a font file, from its first four bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FontFormat {
+    TrueType,
+    /// sfnt with CFF outlines (`OTTO`).
+    OpenType,
+    Woff,
+    Woff2,
+    /// TrueType/OpenType collection (`ttcf`); unpacked into its faces on load.
+    Collection,
+}
+
+impl FontFormat {
+    pub fn sniff(bytes: &[u8]) -> Option<FontFormat> {
+        match bytes.get(..4)? {
+            [0x00, 0x01, 0x00, 0x00] | b"true" => Some(FontFormat::TrueType),
+            b"OTTO" => Some(FontFormat::OpenType),
+            b"wOFF" => Some(FontFormat::Woff),
+            b"wOF2" => Some(FontFormat::Woff2),
+            b"ttcf" => Some(FontFormat::Collection),
+            _ => None,
+        }
+    }
+
+    /// MIME type for the `data:` URL.
+    pub fn mime(&self) -> &'static str {
+        match self {
+            FontFormat::TrueType | FontFormat::Collection => "font/ttf",
+            FontFormat::OpenType => "font/otf",
+            FontFormat::Woff => "font/woff",
+            FontFormat::Woff2 => "font/woff2",
+        }
+    }
+
+    /// Format hint for `src: url(...) format(...)`.
+    pub fn css_format(&self) -> &'static str {
+        match self {
+            FontFormat::TrueType => "truetype",
+            FontFormat::OpenType => "opentype",
+            FontFormat::Woff => "woff",
+            FontFormat::Woff2 => "woff2",
+            FontFormat::Collection => "collection",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Axis {
+    pub tag: String,
+    pub min: f32,
+    pub default: f32,
+    pub max: f32,
+}
+
+/// Identity of a face from its `name` and `OS/2` tables. Files whose tables cannot be read
+/// fall back to the file stem as family and a regular, upright 400 weight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaceNames {
+    pub family: String,
+    pub subfamily: String,
+    pub weight: u16,
+    pub italic: bool,
+    pub version: Option<String>,
+}
+
+impl FaceNames {
+    fn fallback(file: &str) -> FaceNames {
+        let stem = file.rsplit_once('.').map_or(file, |(stem, _)| stem);
+        FaceNames {
+            family: stem.to_string(),
+            subfamily: "Regular".to_string(),
+            weight: 400,
+            italic: false,
+            version: None,
+        }
+    }
+
+    fn from_face(face: &Face, file: &str) -> FaceNames {
+        // Typographic names (16/17) group all weights of a family; the legacy ones (1/2)
+        // split off anything beyond regular/bold/italic into separate families.
+        let name = |ids: &[u16]| {
+            ids.iter().find_map(|&id| {
+                face.names()
+                    .into_iter()
+                    .filter(|name| name.name_id == id && name.is_unicode())
+                    .find_map(|name| name.to_string())
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+            })
+        };
+        let fallback = FaceNames::fallback(file);
+
+        FaceNames {
+            family: name(&[name_id::TYPOGRAPHIC_FAMILY, name_id::FAMILY])
+                .unwrap_or(fallback.family),
+            subfamily: name(&[name_id::TYPOGRAPHIC_SUBFAMILY, name_id::SUBFAMILY])
+                .unwrap_or(fallback.subfamily),
+            weight: face.weight().to_number(),
+            italic: face.is_italic(),
+            version: name(&[name_id::VERSION]),
+        }
+    }
+}
+
+/// A font file from `FONTS_DIR`, with what its OpenType tables allow us to vary.
+#[derive(Debug, Clone)]
+pub struct FontFile {
+    pub base64: String,
+    pub format: FontFormat,
+    /// Index of the face inside the collection it was unpacked from.
+    pub face: Option<usize>,
+    /// File name inside the class directory.
+    pub file: String,
+    pub names: FaceNames,
+    /// GSUB and GPOS feature tags, deduplicated and sorted.
+    pub features: Vec<String>,
+    /// `fvar` axes; empty for static fonts.
+    pub axes: Vec<Axis>,
+}
+
+impl FontFile {
+    /// Sniffs the format of `bytes` and reads the feature and axis tables. Collections come
+    /// back as one standalone font per face; anything without a font signature is an error.
+    /// WOFF and WOFF2 tables are compressed, so those files load without capabilities.
+    pub fn load(file: &str, bytes: &[u8]) -> Result<Vec<FontFile>, String> {
+        match FontFormat::sniff(bytes) {
+            None => Err("not a font file".to_string()),
+            Some(FontFormat::Collection) => unpack_collection(bytes)?
+                .into_iter()
+                .enumerate()
+                .map(|(index, face)| {
+                    let format = FontFormat::sniff(&face)
+                        .ok_or(format!("face {} has no sfnt signature", index))?;
+                    Ok(FontFile::new(file, &face, format, Some(index)))
+                })
+                .collect(),
+            Some(format) => Ok(vec![FontFile::new(file, bytes, format, None)]),
+        }
+    }
+
+    fn new(file: &str, bytes: &[u8], format: FontFormat, face: Option<usize>) -> FontFile {
+        let (names, features, axes) = match Face::parse(bytes, 0) {
+            Ok(parsed) => (
+                FaceNames::from_face(&parsed, file),
+                layout_features(&parsed),
+                variation_axes(&parsed),
+            ),
+            Err(_) => (FaceNames::fallback(file), Vec::new(), Vec::new()),
+        };
+
+        FontFile {
+            base64: STANDARD.encode(bytes),
+            format,
+            face,
+            file: file.to_string(),
+            names,
+            features,
+            axes,
+        }
+    }
+
+    /// Which file, format and face a sample was rendered from.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "file": self.file,
+            "format": self.format.css_format(),
+            "face": self.face,
+            "family": self.names.family,
+            "subfamily": self.names.subfamily,
+            "weight": self.names.weight,
+            "italic": self.names.italic,
+            "version": self.names.version,
+        })
+    }
+
+    /// The `src` descriptor of an `@font-face` rule for this file.
+    pub fn css_src(&self) -> String {
+        format!(
+            "url(data:{};base64,{}) format('{}')",
+            self.format.mime(),
+            self.base64,
+            self.format.css_format()
+        )
+    }
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Result<u16, String> {
+    bytes
+        .get(at..at + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or(format!("truncated font at byte {}", at))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, String> {
+    bytes
+        .get(at..at + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(format!("truncated font at byte {}", at))
+}
+
+/// Splits a `ttcf` collection into standalone sfnt files. Each face's table directory is
+/// copied with its tables laid out after it, 4-byte aligned, and the offsets rewritten.
+/// Tables shared between faces are duplicated into each.
+fn unpack_collection(bytes: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let num_fonts = read_u32(bytes, 8)? as usize;
+    (0..num_fonts)
+        .map(|i| {
+            let directory = read_u32(bytes, 12 + 4 * i)? as usize;
+            let num_tables = read_u16(bytes, directory + 4)? as usize;
+            let header_len = 12 + 16 * num_tables;
+            let header = bytes
+                .get(directory..directory + header_len)
+                .ok_or(format!("truncated table directory for face {}", i))?;
+
+            let mut font = header.to_vec();
+            for table in 0..num_tables {
+                let record = 12 + 16 * table;
+                let offset = read_u32(header, record + 8)? as usize;
+                let length = read_u32(header, record + 12)? as usize;
+                let data = bytes
+                    .get(offset..offset + length)
+                    .ok_or(format!("table {} of face {} is out of bounds", table, i))?;
+
+                let new_offset = font.len() as u32;
+                font[record + 8..record + 12].copy_from_slice(&new_offset.to_be_bytes());
+                font.extend_from_slice(data);
+                font.resize(font.len().div_ceil(4) * 4, 0);
+            }
+            Ok(font)
+        })
+        .collect()
+}
+
+fn layout_features(face: &Face) -> Vec<String> {
+    let tables = face.tables();
+    let mut tags: Vec<String> = tables
+        .gsub
+        .into_iter()
+        .chain(tables.gpos)
+        .flat_map(|table| table.features.into_iter().map(|f| f.tag.to_string()))
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+fn variation_axes(face: &Face) -> Vec<Axis> {
+    face.variation_axes()
+        .into_iter()
+        .filter(|axis| !axis.hidden)
+        .map(|axis| Axis {
+            tag: axis.tag.to_string(),
+            min: axis.min_value,
+            default: axis.def_value,
+            max: axis.max_value,
+        })
+        .collect()
+}
+
+fn is_stylistic_set(tag: &str) -> bool {
+    tag.len() == 4 && tag.starts_with("ss") && tag[2..].parse::<u8>().is_ok()
+}
+
+/// Feature toggles and axis positions chosen for one sample.
+#[derive(Debug, Clone, Default)]
+pub struct Typography {
+    pub features: Vec<(String, bool)>,
+    pub variations: Vec<(String, f32)>,
+}
+
+impl Typography {
+    /// Toggles only features the font has: default-on ones are sometimes switched off,
+    /// discretionary ones and stylistic sets sometimes on. Every axis gets a uniform position
+    /// in its range, or its default.
+    pub fn sample(font: &FontFile, rng: &mut dyn RngCore) -> Typography {
+        let mut features = Vec::new();
+        for tag in &font.features {
+            if DEFAULT_ON_FEATURES.contains(&tag.as_str()) {
+                if rng.gen_bool(DISABLE_PROBABILITY) {
+                    features.push((tag.clone(), false));
+                }
+            } else if (DEFAULT_OFF_FEATURES.contains(&tag.as_str()) || is_stylistic_set(tag))
+                && rng.gen_bool(ENABLE_PROBABILITY)
+            {
+                features.push((tag.clone(), true));
+            }
+        }
+
+        let variations = font
+            .axes
+            .iter()
+            .map(|axis| {
+                let value = if axis.min >= axis.max || rng.gen_bool(AXIS_DEFAULT_PROBABILITY) {
+                    axis.default
+                } else {
+                    rng.gen_range(axis.min..=axis.max)
+                };
+                (axis.tag.clone(), (value * 10.0).round() / 10.0)
+            })
+            .collect();
+
+        Typography {
+            features,
+            variations,
+        }
+    }
+
+    /// `font-feature-settings` and `font-variation-settings` declarations; empty when
+    /// nothing was changed.
+    pub fn css(&self) -> String {
+        let mut declarations = Vec::new();
+        if !self.features.is_empty() {
+            let settings: Vec<String> = self
+                .features
+                .iter()
+                .map(|(tag, on)| format!("\"{}\" {}", tag, u8::from(*on)))
+                .collect();
+            declarations.push(format!("font-feature-settings: {};", settings.join(", ")));
+        }
+        if !self.variations.is_empty() {
+            let settings: Vec<String> = self
+                .variations
+                .iter()
+                .map(|(tag, value)| format!("\"{}\" {}", tag, value))
+                .collect();
+            declarations.push(format!("font-variation-settings: {};", settings.join(", ")));
+        }
+        declarations.join(" ")
+    }
+
+    pub fn to_json(&self) -> Value {
+        let features: Map<String, Value> = self
+            .features
+            .iter()
+            .map(|(tag, on)| (tag.clone(), json!(on)))
+            .collect();
+        let variations: Map<String, Value> = self
+            .variations
+            .iter()
+            .map(|(tag, value)| (tag.clone(), json!(value)))
+            .collect();
+        json!({ "features": features, "variations": variations })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // A collection of two faces sharing one table, the way TTCs share glyph data.
+    fn collection() -> Vec<u8> {
+        let mut ttc = b"ttcf".to_vec();
+        ttc.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 2]);
+        ttc.extend_from_slice(&20u32.to_be_bytes());
+        ttc.extend_from_slice(&48u32.to_be_bytes());
+        let table_offset = 76u32;
+        for directory in [[0x00, 0x01, 0x00, 0x00], *b"OTTO"] {
+            ttc.extend_from_slice(&directory);
+            ttc.extend_from_slice(&[0, 1, 0, 16, 0, 0, 0, 0]);
+            ttc.extend_from_slice(b"name");
+            ttc.extend_from_slice(&[0; 4]);
+            ttc.extend_from_slice(&table_offset.to_be_bytes());
+            ttc.extend_from_slice(&6u32.to_be_bytes());
+        }
+        ttc.extend_from_slice(b"shared");
+        ttc
+    }
+
+    #[test]
+    fn collections_unpack_into_standalone_faces() {
+        assert_eq!(FontFormat::sniff(b"wOF2...."), Some(FontFormat::Woff2));
+        assert_eq!(FontFormat::sniff(b"MIT License"), None);
+        assert!(FontFile::load("LICENSE", b"MIT License").is_err());
+
+        let faces = FontFile::load("Sahel.ttc", &collection()).unwrap();
+        assert_eq!(faces.len(), 2);
+        assert_eq!(faces[0].format, FontFormat::TrueType);
+        assert_eq!(faces[1].format, FontFormat::OpenType);
+        assert_eq!(faces[1].face, Some(1));
+        assert_eq!(faces[1].names.family, "Sahel");
+        assert!(faces[1].css_src().starts_with("url(data:font/otf;base64,"));
+
+        let face = STANDARD.decode(&faces[0].base64).unwrap();
+        assert_eq!(face.len(), 28 + 8);
+        assert_eq!(read_u32(&face, 12 + 8).unwrap(), 28);
+        assert_eq!(&face[28..34], b"shared");
+    }
+
+    #[test]
+    fn sampling_stays_within_font_capabilities() {
+        let font = FontFile {
+            base64: String::new(),
+            format: FontFormat::TrueType,
+            face: None,
+            file: "Vazir.ttf".to_string(),
+            names: FaceNames::fallback("Vazir.ttf"),
+            features: ["calt", "init", "kern", "liga", "ss02", "swsh"]
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            axes: vec![Axis {
+                tag: "wght".to_string(),
+                min: 100.0,
+                default: 400.0,
+                max: 900.0,
+            }],
+        };
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..200 {
+            let typography = Typography::sample(&font, &mut rng);
+            for (tag, on) in &typography.features {
+                assert_ne!(tag, "init");
+                assert!(font.features.contains(tag));
+                assert_eq!(*on, !DEFAULT_ON_FEATURES.contains(&tag.as_str()));
+            }
+            let (tag, weight) = &typography.variations[0];
+            assert_eq!(tag, "wght");
+            assert!((100.0..=900.0).contains(weight));
+        }
+
+        let typography = Typography {
+            features: vec![("liga".to_string(), false), ("ss02".to_string(), true)],
+            variations: vec![("wght".to_string(), 650.0)],
+        };
+        assert_eq!(
+            typography.css(),
+            "font-feature-settings: \"liga\" 0, \"ss02\" 1; font-variation-settings: \"wght\" 650;"
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use colored::*;
+use image::imageops::{self, FilterType};
+use image::{Rgb, RgbImage};
+use serde_json::Value;
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::manifest::{is_rejected, read_manifest};
+use crate::{encode_jpeg, OUTPUT_DIR};
+
+const GALLERY_DIR: &str = "gallery";
+const CONTACT_COLUMNS: u32 = 10;
+const CONTACT_THUMB: u32 = 160;
+const CONTACT_GAP: u32 = 4;
+const CONTACT_QUALITY: u8 = 85;
+// Fonts whose contact sheets are built at once; each holds its thumbnails in memory.
+const CONTACT_WORKERS: usize = 4;
+
+// The browser page. `{samples}` is replaced with the metadata of every sample as a JSON array;
+// it is inlined rather than fetched so the page works from `file://`.
+const GALLERY_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="UTF-8" />
+    <title>Dataset gallery</title>
+    <style>
+      body { font-family: sans-serif; margin: 0; background: #f3f3f3; }
+      form { position: sticky; top: 0; background: #fff; padding: 8px 12px; display: flex; flex-wrap: wrap; gap: 12px; align-items: center; border-bottom: 1px solid #ccc; z-index: 1; }
+      form label { font-size: 13px; }
+      input[type=number] { width: 64px; }
+      #params { flex-basis: 100%; font-size: 13px; }
+      #params div { display: flex; flex-wrap: wrap; gap: 8px 16px; margin-top: 6px; }
+      #count { margin-left: auto; font-size: 13px; color: #555; }
+      #grid { display: grid; grid-template-columns: repeat(auto-fill, minmax(220px, 1fr)); gap: 10px; padding: 12px; }
+      .card { background: #fff; border-radius: 4px; padding: 6px; font-size: 12px; }
+      .card img { width: 100%; height: 180px; object-fit: contain; background: #ddd; }
+      .phrase { direction: rtl; font-size: 14px; margin: 4px 0; }
+      .meta { color: #555; }
+      details pre { white-space: pre-wrap; font-size: 11px; max-height: 240px; overflow: auto; }
+    </style>
+  </head>
+  <body>
+    <form id="filters">
+      <label>Font <select name="font"><option value="">all</option></select></label>
+      <label>Method <select name="method"><option value="">all</option></select></label>
+      <label>Background <select name="background"><option value="">all</option></select></label>
+      <label>Layout <select name="outcome"><option value="">all</option></select></label>
+      <label>JPEG quality <input type="number" name="quality_min" /> – <input type="number" name="quality_max" /></label>
+      <label>Font size <input type="number" name="size_min" /> – <input type="number" name="size_max" /></label>
+      <label>Width <input type="number" name="width_min" /> – <input type="number" name="width_max" /></label>
+      <span id="count"></span>
+      <details id="params"><summary>Style parameters</summary><div></div></details>
+    </form>
+    <div id="grid"></div>
+    <script>
+      const SAMPLES = {samples};
+      const fields = {
+        font: (s) => s.font,
+        method: (s) => s.style && s.style.method,
+        background: (s) => s.style && s.style.background,
+        outcome: (s) => s.layout && s.layout.outcome,
+      };
+      const ranges = {
+        quality: (s) => s.jpeg_quality,
+        size: (s) => s.layout && s.layout.font_size,
+        width: (s) => s.viewport && s.viewport[0],
+      };
+      const form = document.getElementById('filters');
+      for (const [name, get] of Object.entries(fields)) {
+        const values = [...new Set(SAMPLES.map(get).filter((v) => v !== undefined))].sort();
+        for (const value of values) form.elements[name].add(new Option(value, value));
+      }
+      // One range per numeric style parameter the samples recorded, showing its observed span.
+      const params = (s) => (s.style && s.style.params) || {};
+      const numeric = [...new Set(SAMPLES.flatMap((s) =>
+        Object.keys(params(s)).filter((key) => typeof params(s)[key] === 'number')))].sort();
+      const paramBox = document.querySelector('#params div');
+      for (const key of numeric) {
+        const get = (s) => params(s)[key];
+        const values = SAMPLES.map(get).filter((v) => typeof v === 'number');
+        const bound = (suffix, value) => {
+          const input = document.createElement('input');
+          input.type = 'number';
+          input.step = 'any';
+          input.name = 'param.' + key + suffix;
+          input.placeholder = value;
+          return input;
+        };
+        const label = document.createElement('label');
+        label.append(key + ' ', bound('_min', Math.min(...values)), ' – ', bound('_max', Math.max(...values)));
+        paramBox.append(label);
+        ranges['param.' + key] = get;
+      }
+
+      const grid = document.getElementById('grid');
+      const render = () => {
+        const matches = SAMPLES.filter((s) => {
+          for (const [name, get] of Object.entries(fields)) {
+            const wanted = form.elements[name].value;
+            if (wanted && String(get(s)) !== wanted) return false;
+          }
+          for (const [name, get] of Object.entries(ranges)) {
+            const min = parseFloat(form.elements[name + '_min'].value);
+            const max = parseFloat(form.elements[name + '_max'].value);
+            const value = get(s);
+            if (!isNaN(min) && !(value >= min)) return false;
+            if (!isNaN(max) && !(value <= max)) return false;
+          }
+          return true;
+        });
+        document.getElementById('count').textContent = matches.length + ' / ' + SAMPLES.length + ' samples';
+        grid.replaceChildren(...matches.slice(0, 500).map((s) => {
+          const card = document.createElement('div');
+          card.className = 'card';
+          const img = document.createElement('img');
+          img.loading = 'lazy';
+          // Only runs with the folder output keep images on disk.
+          if (s.file) img.src = '../' + s.file;
+          img.alt = s.file ? s.key : 'image not on disk';
+          const phrase = document.createElement('div');
+          phrase.className = 'phrase';
+          phrase.textContent = s.phrase;
+          const meta = document.createElement('div');
+          meta.className = 'meta';
+          meta.textContent = [s.font + ' #' + s.index, fields.method(s), fields.background(s), fields.outcome(s), s.rejected ? 'rejected' : 'q' + s.jpeg_quality].filter(Boolean).join(' · ');
+          const details = document.createElement('details');
+          details.innerHTML = '<summary>metadata</summary>';
+          const pre = document.createElement('pre');
+          pre.textContent = JSON.stringify(s, null, 1);
+          details.append(pre);
+          card.append(img, phrase, meta, details);
+          return card;
+        }));
+      };
+      form.addEventListener('input', render);
+      render();
+    </script>
+  </body>
+</html>
+"#;
+
+/// Scales an image to fit a contact sheet cell.
+fn thumbnail(image: &RgbImage) -> RgbImage {
+    let scale = CONTACT_THUMB as f64 / image.width().max(image.height()) as f64;
+    let width = ((image.width() as f64 * scale) as u32).clamp(1, CONTACT_THUMB);
+    let height = ((image.height() as f64 * scale) as u32).clamp(1, CONTACT_THUMB);
+    imageops::resize(image, width, height, FilterType::Triangle)
+}
+
+/// Tiles thumbnails row by row onto a grey sheet, each centred in its cell.
+fn contact_sheet(thumbs: &[RgbImage]) -> RgbImage {
+    let cell = CONTACT_THUMB + CONTACT_GAP;
+    let columns = CONTACT_COLUMNS.min(thumbs.len().max(1) as u32);
+    let rows = (thumbs.len() as u32).div_ceil(CONTACT_COLUMNS).max(1);
+    let mut sheet = RgbImage::from_pixel(
+        columns * cell + CONTACT_GAP,
+        rows * cell + CONTACT_GAP,
+        Rgb([96, 96, 96]),
+    );
+    for (i, thumb) in thumbs.iter().enumerate() {
+        let (width, height) = thumb.dimensions();
+        let x = CONTACT_GAP + (i as u32 % CONTACT_COLUMNS) * cell + (CONTACT_THUMB - width) / 2;
+        let y = CONTACT_GAP + (i as u32 / CONTACT_COLUMNS) * cell + (CONTACT_THUMB - height) / 2;
+        imageops::replace(&mut sheet, thumb, x as i64, y as i64);
+    }
+    sheet
+}
+
+fn write_contact_sheet(samples: &[Value], output: &str) -> Result<(), String> {
+    // Each image is shrunk as soon as it is decoded, so only thumbnails are kept.
+    let thumbs: Vec<RgbImage> = samples
+        .iter()
+        .filter(|sample| !is_rejected(sample))
+        .filter_map(|sample| {
+            let path = Path::new(OUTPUT_DIR).join(sample["file"].as_str()?);
+            Some(thumbnail(&image::open(path).ok()?.to_rgb8()))
+        })
+        .collect();
+    if thumbs.is_empty() {
+        return Ok(());
+    }
+    let sheet = contact_sheet(&thumbs);
+    fs::write(output, encode_jpeg(&sheet, CONTACT_QUALITY)?)
+        .map_err(|e| format!("Failed to write {}: {}", output, e))
+}
+
+// Keeps phrases from closing the inline script.
+fn escape_for_script(json: &str) -> String {
+    json.replace("</", "<\\/")
+}
+
+/// `gallery`: contact sheets per font and a filterable `index.html` over the samples in the
+/// run's manifest, written to `OUTPUT_DIR/gallery`. Contact sheets need the images on disk,
+/// which only the folder output keeps, and leave out rejected pages; the page lists those
+/// under their layout outcome.
+pub async fn run_gallery() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let gallery_dir = format!("{}/{}", OUTPUT_DIR, GALLERY_DIR);
+    fs::create_dir_all(format!("{}/contact", gallery_dir))?;
+
+    // The manifest is ordered by font, so each font's samples are one run of entries.
+    let mut by_font: Vec<(String, Vec<Value>)> = Vec::new();
+    for sample in read_manifest(OUTPUT_DIR)? {
+        let font = sample["font"].as_str().unwrap_or_default();
+        if by_font.last().is_none_or(|(last, _)| last != font) {
+            by_font.push((font.to_string(), Vec::new()));
+        }
+        by_font.last_mut().unwrap().1.push(sample);
+    }
+
+    let workers = Arc::new(Semaphore::new(CONTACT_WORKERS));
+    let mut handles = Vec::new();
+    for (font, samples) in by_font {
+        let gallery_dir = gallery_dir.clone();
+        let permit = Arc::clone(&workers).acquire_owned().await?;
+        handles.push(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let output = format!("{}/contact/{}.jpg", gallery_dir, font);
+            if let Err(e) = write_contact_sheet(&samples, &output) {
+                eprintln!("Could not build contact sheet for {}: {}", font, e);
+            }
+            samples
+        }));
+    }
+
+    let mut samples = Vec::new();
+    for handle in handles {
+        samples.extend(handle.await?);
+    }
+
+    let data = escape_for_script(&serde_json::to_string(&samples)?);
+    let index = format!("{}/index.html", gallery_dir);
+    fs::write(&index, GALLERY_HTML.replace("{samples}", &data))?;
+
+    println!(
+        "{} {} samples: {}",
+        "Built gallery for".green(),
+        samples.len(),
+        index
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contact_sheet_wraps_rows_and_centres_thumbnails() {
+        let wide = thumbnail(&RgbImage::from_pixel(320, 80, Rgb([255, 0, 0])));
+        assert_eq!(wide.dimensions(), (CONTACT_THUMB, CONTACT_THUMB / 4));
+        let sheet = contact_sheet(&vec![wide; 12]);
+        let cell = CONTACT_THUMB + CONTACT_GAP;
+        assert_eq!(sheet.width(), CONTACT_COLUMNS * cell + CONTACT_GAP);
+        assert_eq!(sheet.height(), 2 * cell + CONTACT_GAP);
+
+        // A 4:1 thumbnail is 160×40, centred vertically in its cell.
+        let centre_y = CONTACT_GAP + CONTACT_THUMB / 2;
+        assert_eq!(
+            sheet.get_pixel(CONTACT_GAP + 1, centre_y),
+            &Rgb([255, 0, 0])
+        );
+        assert_eq!(
+            sheet.get_pixel(CONTACT_GAP + 1, CONTACT_GAP + 1),
+            &Rgb([96, 96, 96])
+        );
+        // The second row has only two thumbnails.
+        assert_eq!(
+            sheet.get_pixel(CONTACT_GAP + 2 * cell + 1, cell + centre_y),
+            &Rgb([96, 96, 96])
+        );
+
+        assert_eq!(escape_for_script(r#"["</script>"]"#), r#"["<\/script>"]"#);
+    }
+}
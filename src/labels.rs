@@ -0,0 +1,267 @@
+use serde_json::{json, Map, Value};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+use crate::fonts::FontFile;
+
+/// Granularity of the class label a sample is trained on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LabelLevel {
+    /// The directory under `FONTS_DIR`.
+    Class,
+    /// Typographic family from the name table, across all its weights and styles.
+    Family,
+    /// One face of a family, e.g. "Vazirmatn Bold".
+    Face,
+    /// A family at one `OS/2` weight class, merging upright and italic.
+    Weight,
+}
+
+impl LabelLevel {
+    const ALL: [LabelLevel; 4] = [
+        LabelLevel::Class,
+        LabelLevel::Family,
+        LabelLevel::Face,
+        LabelLevel::Weight,
+    ];
+
+    pub fn by_name(name: &str) -> Option<LabelLevel> {
+        LabelLevel::ALL
+            .into_iter()
+            .find(|level| level.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LabelLevel::Class => "class",
+            LabelLevel::Family => "family",
+            LabelLevel::Face => "face",
+            LabelLevel::Weight => "weight",
+        }
+    }
+
+    /// Whether the label tells weights apart, so a sample must render at the face's own
+    /// weight to match it.
+    pub fn names_weight(&self) -> bool {
+        matches!(self, LabelLevel::Face | LabelLevel::Weight)
+    }
+
+    fn key(&self, class: &str, font: &FontFile) -> String {
+        let names = &font.names;
+        match self {
+            LabelLevel::Class => class.to_string(),
+            LabelLevel::Family => names.family.clone(),
+            LabelLevel::Face => format!("{} {}", names.family, names.subfamily),
+            LabelLevel::Weight => format!("{} {}", names.family, names.weight),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct FaceEntry {
+    weight: u16,
+    italic: bool,
+    version: Option<String>,
+    files: BTreeSet<String>,
+}
+
+/// Label ids at every level plus the class → family → face hierarchy, persisted as
+/// `classes.json`. Ids are never renumbered: a label keeps the id it got in the first run
+/// that saw it, and new labels are appended after the largest id.
+#[derive(Debug, Clone, Default)]
+pub struct ClassIndex {
+    ids: BTreeMap<&'static str, BTreeMap<String, usize>>,
+    // (class, family, face) → what the face's files say about it.
+    faces: BTreeMap<(String, String, String), FaceEntry>,
+}
+
+impl ClassIndex {
+    /// Starts from the ids in an earlier `classes.json`, if there is one. The hierarchy is
+    /// rebuilt from the fonts registered in this run.
+    pub fn load(path: &str) -> Result<ClassIndex, String> {
+        let mut index = ClassIndex::default();
+        let Ok(raw) = fs::read_to_string(path) else {
+            return Ok(index);
+        };
+        let value: Value =
+            serde_json::from_str(&raw).map_err(|e| format!("Invalid {}: {}", path, e))?;
+        for level in LabelLevel::ALL {
+            let ids = value["levels"][level.name()]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(name, id)| Some((name.clone(), id.as_u64()? as usize)))
+                .collect();
+            index.ids.insert(level.name(), ids);
+        }
+        Ok(index)
+    }
+
+    /// Adds every face of `class`. Register classes and files in sorted order so that a
+    /// fresh index numbers them the same way on every machine.
+    pub fn register(&mut self, class: &str, fonts: &[FontFile]) {
+        for font in fonts {
+            for level in LabelLevel::ALL {
+                let ids = self.ids.entry(level.name()).or_default();
+                let key = level.key(class, font);
+                if !ids.contains_key(&key) {
+                    let next = ids.values().max().map_or(0, |id| id + 1);
+                    ids.insert(key, next);
+                }
+            }
+
+            let entry = self
+                .faces
+                .entry((
+                    class.to_string(),
+                    LabelLevel::Family.key(class, font),
+                    LabelLevel::Face.key(class, font),
+                ))
+                .or_default();
+            entry.weight = font.names.weight;
+            entry.italic = font.names.italic;
+            entry.version = font.names.version.clone();
+            entry.files.insert(font.file.clone());
+        }
+    }
+
+    /// Class directories registered in this run.
+    pub fn classes(&self) -> Vec<String> {
+        let classes: BTreeSet<&String> = self.faces.keys().map(|(class, _, _)| class).collect();
+        classes.into_iter().cloned().collect()
+    }
+
+    /// Label names at one level, indexed by id.
+    pub fn names(&self, level: LabelLevel) -> Vec<String> {
+        let ids = self.ids.get(level.name()).cloned().unwrap_or_default();
+        let mut names = vec![String::new(); ids.values().max().map_or(0, |id| id + 1)];
+        for (name, id) in ids {
+            names[id] = name;
+        }
+        names
+    }
+
+    fn id(&self, level: LabelLevel, key: &str) -> Option<usize> {
+        self.ids.get(level.name())?.get(key).copied()
+    }
+
+    /// `{name, id}` of `font` at every level.
+    pub fn labels(&self, class: &str, font: &FontFile) -> Value {
+        let labels: Map<String, Value> = LabelLevel::ALL
+            .into_iter()
+            .map(|level| {
+                let name = level.key(class, font);
+                let id = self.id(level, &name);
+                (level.name().to_string(), json!({ "name": name, "id": id }))
+            })
+            .collect();
+        Value::Object(labels)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut hierarchy: BTreeMap<&str, BTreeMap<&str, Vec<Value>>> = BTreeMap::new();
+        for ((class, family, face), entry) in &self.faces {
+            let weight_key = format!("{} {}", family, entry.weight);
+            hierarchy
+                .entry(class)
+                .or_default()
+                .entry(family)
+                .or_default()
+                .push(json!({
+                    "face": face,
+                    "id": self.id(LabelLevel::Face, face),
+                    "weight": entry.weight,
+                    "weight_id": self.id(LabelLevel::Weight, &weight_key),
+                    "italic": entry.italic,
+                    "version": entry.version,
+                    "files": entry.files,
+                }));
+        }
+
+        let hierarchy: Vec<Value> = hierarchy
+            .into_iter()
+            .map(|(class, families)| {
+                let families: Vec<Value> = families
+                    .into_iter()
+                    .map(|(family, faces)| {
+                        json!({
+                            "family": family,
+                            "id": self.id(LabelLevel::Family, family),
+                            "faces": faces,
+                        })
+                    })
+                    .collect();
+                json!({
+                    "class": class,
+                    "id": self.id(LabelLevel::Class, class),
+                    "families": families,
+                })
+            })
+            .collect();
+
+        json!({ "levels": self.ids, "hierarchy": hierarchy })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let raw = serde_json::to_string_pretty(&self.to_json())
+            .map_err(|e| format!("Failed to serialise class index: {}", e))?;
+        fs::write(path, raw).map_err(|e| format!("Failed to write {}: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fonts::{FaceNames, FontFormat};
+
+    fn font(file: &str, family: &str, subfamily: &str, weight: u16) -> FontFile {
+        FontFile {
+            base64: String::new(),
+            format: FontFormat::TrueType,
+            face: None,
+            file: file.to_string(),
+            names: FaceNames {
+                family: family.to_string(),
+                subfamily: subfamily.to_string(),
+                weight,
+                italic: false,
+                version: None,
+            },
+            features: Vec::new(),
+            axes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ids_survive_a_reload_and_new_labels_are_appended() {
+        let regular = font("Vazir.ttf", "Vazir", "Regular", 400);
+        let bold = font("Vazir-Bold.ttf", "Vazir", "Bold", 700);
+        let mut first = ClassIndex::default();
+        first.register("Vazir", &[regular.clone(), bold.clone()]);
+        assert_eq!(first.labels("Vazir", &bold)["face"]["id"], 1);
+
+        let path = std::env::temp_dir().join(format!("classes-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        first.save(path).unwrap();
+
+        // A new class that sorts first must not shift the ids handed out earlier.
+        let mut second = ClassIndex::load(path).unwrap();
+        let sahel = font("Sahel.ttf", "Sahel", "Regular", 400);
+        second.register("Sahel", std::slice::from_ref(&sahel));
+        second.register("Vazir", &[regular, bold.clone()]);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(second.labels("Vazir", &bold)["face"]["id"], 1);
+        assert_eq!(second.labels("Sahel", &sahel)["face"]["id"], 2);
+        assert_eq!(second.labels("Sahel", &sahel)["class"]["id"], 1);
+        assert_eq!(second.classes(), vec!["Sahel", "Vazir"]);
+
+        let labels = second.labels("Vazir", &bold);
+        assert_eq!(labels["weight"]["name"], "Vazir 700");
+        assert_eq!(
+            second.to_json()["hierarchy"][1]["families"][0]["faces"][0]["face"],
+            "Vazir Bold"
+        );
+    }
+}
@@ -0,0 +1,512 @@
+use headless_chrome::Tab;
+use serde_json::Value;
+
+use std::fmt;
+
+// Smallest font size the shrink-fit search is allowed to reach before giving up.
+const MIN_FIT_FONT_SIZE: f64 = 14.0;
+// Pixels of overflow tolerated before a sample counts as overflowing or clipped.
+const OVERFLOW_TOLERANCE: f64 = 1.0;
+
+// Measures every rendered `.text-container` once fonts are ready. A container that sets
+// `--target-lines` first gets the narrowest width that keeps it on that many lines. When
+// `{shrink}` is true and a block overflows its box or the viewport, its font size is
+// binary-searched down to `{min_size}`px. Returns the measurements before and after fitting,
+// per block, and the final line boxes of every `.font-span`, as JSON.
+const LAYOUT_CHECK_JS: &str = r#"
+(async () => {
+    await document.fonts.ready;
+    const body = document.body;
+    const tolerance = {tolerance};
+
+    // Range rects come per text fragment; fragments sharing a vertical centre form one line.
+    const lineBoxes = (el) => {
+        const range = document.createRange();
+        range.selectNodeContents(el);
+        const lines = [];
+        for (const r of range.getClientRects()) {
+            if (r.width === 0 || r.height === 0) continue;
+            const centre = r.top + r.height / 2;
+            const line = lines.find((l) => Math.abs(l.y + l.height / 2 - centre) < Math.min(l.height, r.height) / 2);
+            if (line) {
+                const right = Math.max(line.x + line.width, r.right);
+                const bottom = Math.max(line.y + line.height, r.bottom);
+                line.x = Math.min(line.x, r.left);
+                line.y = Math.min(line.y, r.top);
+                line.width = right - line.x;
+                line.height = bottom - line.y;
+            } else {
+                lines.push({ x: r.left, y: r.top, width: r.width, height: r.height });
+            }
+        }
+        return lines.sort((a, b) => a.y - b.y);
+    };
+    const fitLines = (el, target) => {
+        let lo = 40;
+        let hi = window.innerWidth;
+        el.style.width = hi + 'px';
+        while (hi - lo > 2) {
+            const mid = (lo + hi) / 2;
+            el.style.width = mid + 'px';
+            if (lineBoxes(el).length <= target) { hi = mid; } else { lo = mid; }
+        }
+        el.style.width = Math.ceil(hi) + 'px';
+    };
+
+    const measure = (el) => {
+        const range = document.createRange();
+        range.selectNodeContents(el);
+        const r = range.getBoundingClientRect();
+        const overflowX = Math.max(el.scrollWidth - el.clientWidth, body.scrollWidth - body.clientWidth, 0);
+        const overflowY = Math.max(el.scrollHeight - el.clientHeight, body.scrollHeight - body.clientHeight, 0);
+        return {
+            font_size: parseFloat(getComputedStyle(el).fontSize),
+            overflow_x: overflowX,
+            overflow_y: overflowY,
+            viewport_width: window.innerWidth,
+            viewport_height: window.innerHeight,
+            text_box: { x: r.left, y: r.top, width: r.width, height: r.height },
+            lines: lineBoxes(el),
+        };
+    };
+    const fits = (m) => {
+        const b = m.text_box;
+        return m.overflow_x <= tolerance && m.overflow_y <= tolerance
+            && b.x >= -tolerance && b.y >= -tolerance
+            && b.x + b.width <= m.viewport_width + tolerance
+            && b.y + b.height <= m.viewport_height + tolerance;
+    };
+
+    const blocks = [];
+    for (const el of document.querySelectorAll('.text-container')) {
+        const targetLines = parseInt(getComputedStyle(el).getPropertyValue('--target-lines'));
+        if (targetLines > 0) fitLines(el, targetLines);
+        const before = measure(el);
+        let after = before;
+        if ({shrink} && !fits(before)) {
+            let lo = {min_size};
+            let hi = before.font_size;
+            el.style.fontSize = lo + 'px';
+            if (fits(measure(el))) {
+                while (hi - lo > 0.5) {
+                    const mid = (lo + hi) / 2;
+                    el.style.fontSize = mid + 'px';
+                    if (fits(measure(el))) { lo = mid; } else { hi = mid; }
+                }
+                el.style.fontSize = (Math.floor(lo * 2) / 2) + 'px';
+            } else {
+                el.style.fontSize = '';
+            }
+            after = measure(el);
+        }
+        blocks.push({ before, after });
+    }
+
+    const rect = (r) => ({ x: r.left, y: r.top, width: r.width, height: r.height });
+    const spans = [...document.querySelectorAll('.font-span')].map((span) => ({
+        font: span.dataset.font,
+        text: span.textContent,
+        lines: [...span.getClientRects()].filter((r) => r.width > 0).map(rect),
+    }));
+    return JSON.stringify({ blocks, spans });
+})()
+"#;
+
+/// What to do with a sample whose text does not fit its box or the viewport.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FitPolicy {
+    /// Shrink the font until the text fits, rejecting it only if even the minimum size fails.
+    Shrink,
+    /// Reject every sample that does not fit as rendered.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Rect {
+    /// Corners clockwise from the top-left.
+    pub fn corners(&self) -> [(f64, f64); 4] {
+        [
+            (self.x, self.y),
+            (self.x + self.width, self.y),
+            (self.x + self.width, self.y + self.height),
+            (self.x, self.y + self.height),
+        ]
+    }
+
+    fn from_json(value: &Value) -> Rect {
+        Rect {
+            x: number(value, "x"),
+            y: number(value, "y"),
+            width: number(value, "width"),
+            height: number(value, "height"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutMetrics {
+    pub font_size: f64,
+    pub overflow_x: f64,
+    pub overflow_y: f64,
+    pub viewport_width: f64,
+    pub viewport_height: f64,
+    pub text_box: Rect,
+    /// One box per rendered line, top to bottom.
+    pub lines: Vec<Rect>,
+}
+
+impl LayoutMetrics {
+    fn from_json(value: &Value) -> LayoutMetrics {
+        LayoutMetrics {
+            font_size: number(value, "font_size"),
+            overflow_x: number(value, "overflow_x"),
+            overflow_y: number(value, "overflow_y"),
+            viewport_width: number(value, "viewport_width"),
+            viewport_height: number(value, "viewport_height"),
+            text_box: Rect::from_json(&value["text_box"]),
+            lines: rects(&value["lines"]),
+        }
+    }
+
+    /// Text is larger than the box it was laid out in (`scrollWidth`/`scrollHeight` exceed the
+    /// client size).
+    pub fn overflows_box(&self) -> bool {
+        self.overflow_x > OVERFLOW_TOLERANCE || self.overflow_y > OVERFLOW_TOLERANCE
+    }
+
+    /// Some of the text lies outside the viewport and is cut off in the screenshot.
+    pub fn clipped_by_viewport(&self) -> bool {
+        let b = &self.text_box;
+        b.x < -OVERFLOW_TOLERANCE
+            || b.y < -OVERFLOW_TOLERANCE
+            || b.x + b.width > self.viewport_width + OVERFLOW_TOLERANCE
+            || b.y + b.height > self.viewport_height + OVERFLOW_TOLERANCE
+    }
+
+    pub fn fits(&self) -> bool {
+        !self.overflows_box() && !self.clipped_by_viewport()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutOutcome {
+    Clean,
+    Fitted { from_size: f64, to_size: f64 },
+    RejectedOverflow,
+    RejectedClipped,
+}
+
+impl fmt::Display for LayoutOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutOutcome::Clean => write!(f, "clean"),
+            LayoutOutcome::Fitted { .. } => write!(f, "fitted"),
+            LayoutOutcome::RejectedOverflow => write!(f, "rejected_overflow"),
+            LayoutOutcome::RejectedClipped => write!(f, "rejected_clipped"),
+        }
+    }
+}
+
+/// A run of words set in one font inside a mixed-font line, measured after fitting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanLayout {
+    pub font: String,
+    pub text: String,
+    /// One box per rendered line, as a span can wrap.
+    pub lines: Vec<Rect>,
+}
+
+impl SpanLayout {
+    fn from_json(value: &Value) -> SpanLayout {
+        SpanLayout {
+            font: value["font"].as_str().unwrap_or_default().to_string(),
+            text: value["text"].as_str().unwrap_or_default().to_string(),
+            lines: rects(&value["lines"]),
+        }
+    }
+}
+
+/// Layout of one `.text-container` before and after shrink-fitting.
+#[derive(Debug, Clone)]
+pub struct BlockLayout {
+    pub before: LayoutMetrics,
+    pub after: LayoutMetrics,
+    pub outcome: LayoutOutcome,
+}
+
+impl BlockLayout {
+    fn from_measurements(before: LayoutMetrics, after: LayoutMetrics) -> BlockLayout {
+        let outcome = if before.fits() {
+            LayoutOutcome::Clean
+        } else if after.fits() {
+            LayoutOutcome::Fitted {
+                from_size: before.font_size,
+                to_size: after.font_size,
+            }
+        } else if after.overflows_box() {
+            LayoutOutcome::RejectedOverflow
+        } else {
+            LayoutOutcome::RejectedClipped
+        };
+
+        BlockLayout {
+            before,
+            after,
+            outcome,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LayoutReport {
+    /// One entry per text block, in document order.
+    pub blocks: Vec<BlockLayout>,
+    /// Font-labelled spans, in document order; empty unless the page mixes fonts per word.
+    pub spans: Vec<SpanLayout>,
+    /// The worst outcome over all blocks; a sample is rejected if any block is.
+    pub outcome: LayoutOutcome,
+}
+
+impl LayoutReport {
+    fn from_blocks(blocks: Vec<BlockLayout>, spans: Vec<SpanLayout>) -> LayoutReport {
+        let rank = |outcome: &LayoutOutcome| match outcome {
+            LayoutOutcome::Clean => 0,
+            LayoutOutcome::Fitted { .. } => 1,
+            LayoutOutcome::RejectedClipped => 2,
+            LayoutOutcome::RejectedOverflow => 3,
+        };
+        let outcome = blocks
+            .iter()
+            .map(|block| &block.outcome)
+            .max_by_key(|outcome| rank(outcome))
+            .cloned()
+            .unwrap_or(LayoutOutcome::Clean);
+
+        LayoutReport {
+            blocks,
+            spans,
+            outcome,
+        }
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self.outcome,
+            LayoutOutcome::RejectedOverflow | LayoutOutcome::RejectedClipped
+        )
+    }
+}
+
+/// Per-font counters of how many samples rendered cleanly, needed fitting or were dropped.
+#[derive(Debug, Clone, Default)]
+pub struct ClipStats {
+    pub clean: usize,
+    pub fitted: usize,
+    pub rejected_overflow: usize,
+    pub rejected_clipped: usize,
+}
+
+impl ClipStats {
+    pub fn record(&mut self, outcome: &LayoutOutcome) {
+        match outcome {
+            LayoutOutcome::Clean => self.clean += 1,
+            LayoutOutcome::Fitted { .. } => self.fitted += 1,
+            LayoutOutcome::RejectedOverflow => self.rejected_overflow += 1,
+            LayoutOutcome::RejectedClipped => self.rejected_clipped += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.clean + self.fitted + self.rejected_overflow + self.rejected_clipped
+    }
+}
+
+impl fmt::Display for ClipStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rendered: {} clean, {} fitted, {} rejected (overflow: {}, clipped: {})",
+            self.total(),
+            self.clean,
+            self.fitted,
+            self.rejected_overflow + self.rejected_clipped,
+            self.rejected_overflow,
+            self.rejected_clipped
+        )
+    }
+}
+
+fn number(value: &Value, key: &str) -> f64 {
+    value[key].as_f64().unwrap_or(0.0)
+}
+
+fn rects(value: &Value) -> Vec<Rect> {
+    value
+        .as_array()
+        .map(|rects| rects.iter().map(Rect::from_json).collect())
+        .unwrap_or_default()
+}
+
+fn parse_report(raw: &str) -> Result<LayoutReport, String> {
+    let value: Value =
+        serde_json::from_str(raw).map_err(|e| format!("Invalid layout report: {}", e))?;
+    let blocks = value["blocks"]
+        .as_array()
+        .ok_or("Layout report has no blocks")?
+        .iter()
+        .map(|block| {
+            BlockLayout::from_measurements(
+                LayoutMetrics::from_json(&block["before"]),
+                LayoutMetrics::from_json(&block["after"]),
+            )
+        })
+        .collect::<Vec<_>>();
+    if blocks.is_empty() {
+        return Err("No text container on the page".to_string());
+    }
+    let spans = value["spans"]
+        .as_array()
+        .map(|spans| spans.iter().map(SpanLayout::from_json).collect())
+        .unwrap_or_default();
+    Ok(LayoutReport::from_blocks(blocks, spans))
+}
+
+/// Checks the page currently loaded in `tab` for overflow and clipping, shrink-fitting the
+/// text in place when the policy allows it.
+pub fn check_layout(tab: &Tab, policy: FitPolicy) -> Result<LayoutReport, String> {
+    let js = LAYOUT_CHECK_JS
+        .replace("{tolerance}", &OVERFLOW_TOLERANCE.to_string())
+        .replace("{min_size}", &MIN_FIT_FONT_SIZE.to_string())
+        .replace("{shrink}", &(policy == FitPolicy::Shrink).to_string());
+
+    let result = tab
+        .evaluate(&js, true)
+        .map_err(|e| format!("Failed to measure layout: {}", e))?;
+
+    let raw = result
+        .value
+        .as_ref()
+        .and_then(|v| v.as_str())
+        .ok_or("Layout check returned no value")?;
+
+    parse_report(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn metrics(font_size: f64, overflow_x: f64, text_box: Rect) -> Value {
+        json!({
+            "font_size": font_size,
+            "overflow_x": overflow_x,
+            "overflow_y": 0.0,
+            "viewport_width": 500.0,
+            "viewport_height": 400.0,
+            "text_box": {
+                "x": text_box.x,
+                "y": text_box.y,
+                "width": text_box.width,
+                "height": text_box.height,
+            },
+        })
+    }
+
+    fn inside() -> Rect {
+        Rect {
+            x: 10.0,
+            y: 10.0,
+            width: 200.0,
+            height: 50.0,
+        }
+    }
+
+    #[test]
+    fn test_clean_layout() {
+        let m = metrics(40.0, 0.0, inside());
+        let raw = json!({ "blocks": [{ "before": m, "after": m }] }).to_string();
+        let report = parse_report(&raw).unwrap();
+        assert_eq!(report.outcome, LayoutOutcome::Clean);
+        assert!(!report.is_rejected());
+    }
+
+    #[test]
+    fn test_shrink_fitted_layout() {
+        let raw = json!({ "blocks": [{
+            "before": metrics(80.0, 35.0, inside()),
+            "after": metrics(52.5, 0.0, inside()),
+        }] })
+        .to_string();
+        let report = parse_report(&raw).unwrap();
+        assert_eq!(
+            report.outcome,
+            LayoutOutcome::Fitted {
+                from_size: 80.0,
+                to_size: 52.5
+            }
+        );
+    }
+
+    #[test]
+    fn test_viewport_clipping_is_rejected() {
+        let off_screen = Rect {
+            x: -40.0,
+            ..inside()
+        };
+        let m = metrics(60.0, 0.0, off_screen);
+        let raw = json!({ "blocks": [{ "before": m, "after": m }] }).to_string();
+        let report = parse_report(&raw).unwrap();
+        assert_eq!(report.outcome, LayoutOutcome::RejectedClipped);
+
+        let mut stats = ClipStats::default();
+        stats.record(&report.outcome);
+        stats.record(&LayoutOutcome::Clean);
+        assert_eq!(stats.total(), 2);
+        assert_eq!(stats.rejected_clipped, 1);
+    }
+
+    #[test]
+    fn test_worst_block_decides_outcome() {
+        let clean = metrics(40.0, 0.0, inside());
+        let overflowing = metrics(90.0, 60.0, inside());
+        let raw = json!({ "blocks": [
+            { "before": clean, "after": clean },
+            { "before": overflowing, "after": overflowing },
+        ] })
+        .to_string();
+        let report = parse_report(&raw).unwrap();
+        assert_eq!(report.blocks.len(), 2);
+        assert_eq!(report.blocks[0].outcome, LayoutOutcome::Clean);
+        assert_eq!(report.outcome, LayoutOutcome::RejectedOverflow);
+        assert!(report.is_rejected());
+    }
+
+    #[test]
+    fn test_spans_are_parsed_in_order() {
+        let m = metrics(40.0, 0.0, inside());
+        let raw = json!({
+            "blocks": [{ "before": m, "after": m }],
+            "spans": [
+                { "font": "Vazir", "text": "سلام", "lines": [{ "x": 150.0, "y": 10.0, "width": 60.0, "height": 40.0 }] },
+                { "font": "Nazanin", "text": "دنیا", "lines": [
+                    { "x": 10.0, "y": 10.0, "width": 120.0, "height": 40.0 },
+                    { "x": 100.0, "y": 55.0, "width": 80.0, "height": 40.0 },
+                ] },
+            ],
+        })
+        .to_string();
+        let report = parse_report(&raw).unwrap();
+        assert_eq!(report.spans.len(), 2);
+        assert_eq!(report.spans[0].font, "Vazir");
+        assert_eq!(report.spans[1].lines.len(), 2);
+        assert_eq!(report.spans[1].lines[1].y, 55.0);
+    }
+}
@@ -0,0 +1,398 @@
+use colored::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs;
+
+use crate::manifest::{is_rejected, read_manifest};
+use crate::sinks::EncodedSample;
+use crate::stats::{style_parameters, Bins};
+use crate::OUTPUT_DIR;
+
+// `leakage`: whether style parameters give the label away. Every parameter `stats` sees, plus
+// the length of the sample's text, is binned the same way and compared with the sample's
+// label by mutual information, normalized by the label entropy so 0 means independent and 1
+// means the parameter alone names the label. A majority-per-bin classifier's leave-one-out
+// accuracy, next to the share of the most common label, puts it in plainer terms. A sample
+// without the parameter counts as its own bin, since whether a parameter was drawn at all can
+// leak too.
+//
+// With `--resample`, the flagged parameters are fixed by stratified sub-sampling: within
+// every combination of their bins each label keeps as many samples as the rarest label has
+// there, so the kept samples carry no information about the label through them. The keys to
+// keep go to `keep.json`; nothing is deleted.
+
+const LEAKAGE_DIR: &str = "leakage";
+// Normalized mutual information above which a parameter is flagged.
+const DEFAULT_THRESHOLD: f64 = 0.05;
+
+/// Entropy in bits of a distribution given by counts.
+fn entropy(counts: impl Iterator<Item = usize>) -> f64 {
+    let counts: Vec<f64> = counts.filter(|&c| c > 0).map(|c| c as f64).collect();
+    let total: f64 = counts.iter().sum();
+    counts
+        .iter()
+        .map(|c| -(c / total) * (c / total).log2())
+        .sum()
+}
+
+/// Mutual information in bits between the two halves of `pairs`, less the Miller-Madow
+/// estimate of the bias small samples add, and never below zero.
+fn mutual_information(pairs: &[(usize, usize)]) -> f64 {
+    if pairs.is_empty() {
+        return 0.0;
+    }
+    let mut xs: HashMap<usize, usize> = HashMap::new();
+    let mut ys: HashMap<usize, usize> = HashMap::new();
+    let mut joint: HashMap<(usize, usize), usize> = HashMap::new();
+    for &(x, y) in pairs {
+        *xs.entry(x).or_default() += 1;
+        *ys.entry(y).or_default() += 1;
+        *joint.entry((x, y)).or_default() += 1;
+    }
+    let plugin = entropy(xs.values().copied()) + entropy(ys.values().copied())
+        - entropy(joint.values().copied());
+    let bias = (joint.len() as f64 - xs.len() as f64 - ys.len() as f64 + 1.0)
+        / (2.0 * pairs.len() as f64 * std::f64::consts::LN_2);
+    (plugin - bias).max(0.0)
+}
+
+/// Leave-one-out accuracy of predicting the label as the most common one in the sample's bin.
+fn majority_accuracy(pairs: &[(usize, usize)]) -> f64 {
+    let mut counts: HashMap<usize, BTreeMap<usize, usize>> = HashMap::new();
+    for &(x, y) in pairs {
+        *counts.entry(x).or_default().entry(y).or_default() += 1;
+    }
+    let correct = pairs
+        .iter()
+        .filter(|&&(x, y)| {
+            // The most common label among the bin's other samples; ties go to the lower label.
+            let prediction = counts[&x]
+                .iter()
+                .map(|(&label, &count)| (label, count - usize::from(label == y)))
+                .max_by_key(|&(label, count)| (count, std::cmp::Reverse(label)))
+                .filter(|&(_, count)| count > 0)
+                .map(|(label, _)| label);
+            prediction == Some(y)
+        })
+        .count();
+    correct as f64 / pairs.len().max(1) as f64
+}
+
+/// Which samples to keep so every label is equally common within every stratum. Strata
+/// missing a label are dropped entirely.
+fn stratified_keep(strata: &[(String, usize)], rng: &mut StdRng) -> Vec<bool> {
+    let labels: BTreeSet<usize> = strata.iter().map(|(_, label)| *label).collect();
+    let mut groups: BTreeMap<(&str, usize), Vec<usize>> = BTreeMap::new();
+    for (i, (stratum, label)) in strata.iter().enumerate() {
+        groups.entry((stratum, *label)).or_default().push(i);
+    }
+    let stratum_names: BTreeSet<&str> = strata.iter().map(|(s, _)| s.as_str()).collect();
+    let mut keep = vec![false; strata.len()];
+    for stratum in stratum_names {
+        let quota = labels
+            .iter()
+            .map(|label| groups.get(&(stratum, *label)).map_or(0, Vec::len))
+            .min()
+            .unwrap_or(0);
+        for label in &labels {
+            if let Some(members) = groups.get(&(stratum, *label)) {
+                for &i in members.choose_multiple(rng, quota) {
+                    keep[i] = true;
+                }
+            }
+        }
+    }
+    keep
+}
+
+struct LabeledSample {
+    key: String,
+    label: usize,
+    parameters: BTreeMap<String, Value>,
+}
+
+/// What is audited for one sample: the parameters `stats` sees, which include the rendered
+/// line count (`layout.lines`) and a paragraph's target lines where present, plus the length
+/// of the text. Phrases are assigned per font, so their length can name the label too.
+fn audited_parameters(sample: &Value) -> BTreeMap<String, Value> {
+    let mut parameters = style_parameters(sample);
+    if let Some(phrase) = sample["phrase"].as_str() {
+        parameters.insert("phrase.chars".to_string(), json!(phrase.chars().count()));
+        parameters.insert(
+            "phrase.words".to_string(),
+            json!(phrase.split_whitespace().count()),
+        );
+    }
+    parameters
+}
+
+/// Bin of every sample for one parameter; samples without it share the last bin.
+fn bin_column(samples: &[&LabeledSample], parameter: &str) -> Vec<usize> {
+    let values: Vec<&Value> = samples
+        .iter()
+        .filter_map(|sample| sample.parameters.get(parameter))
+        .collect();
+    let bins = Bins::of(&values);
+    samples
+        .iter()
+        .map(|sample| {
+            sample
+                .parameters
+                .get(parameter)
+                .and_then(|value| bins.index(value))
+                .unwrap_or(bins.len())
+        })
+        .collect()
+}
+
+fn measure(samples: &[&LabeledSample], parameter: &str, label_entropy: f64) -> Value {
+    let column = bin_column(samples, parameter);
+    let pairs: Vec<(usize, usize)> = column
+        .iter()
+        .zip(samples)
+        .map(|(&bin, sample)| (bin, sample.label))
+        .collect();
+    let information = mutual_information(&pairs);
+    json!({
+        "parameter": parameter,
+        "bins": column.iter().collect::<BTreeSet<_>>().len(),
+        "mutual_information": information,
+        "normalized": if label_entropy > 0.0 { information / label_entropy } else { 0.0 },
+        "accuracy": majority_accuracy(&pairs),
+    })
+}
+
+/// `leakage [--threshold=N] [--ignore=PREFIX]... [--resample[=SEED]]`: measures how much each
+/// style parameter of the samples in `OUTPUT_DIR` tells about their label and writes
+/// `OUTPUT_DIR/leakage/report.json`, plus `keep.json` when resampling.
+pub async fn run_leakage(args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut threshold = DEFAULT_THRESHOLD;
+    let mut ignored = Vec::new();
+    let mut resample = None;
+    for arg in args {
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        match flag {
+            "--threshold" => match value.and_then(|v| v.parse::<f64>().ok()) {
+                Some(t) if (0.0..=1.0).contains(&t) => threshold = t,
+                _ => return Err("--threshold takes a value between 0 and 1".into()),
+            },
+            "--ignore" => match value {
+                Some(prefix) => ignored.push(prefix.to_string()),
+                None => return Err("--ignore takes a parameter prefix".into()),
+            },
+            "--resample" => match value.unwrap_or("0").parse::<u64>() {
+                Ok(seed) => resample = Some(seed),
+                Err(_) => return Err("--resample takes a seed".into()),
+            },
+            _ => return Err(format!("Unknown argument {}", arg).into()),
+        }
+    }
+
+    let mut label_ids: BTreeMap<String, usize> = BTreeMap::new();
+    let mut samples = Vec::new();
+    let manifest = read_manifest(OUTPUT_DIR)?;
+    for sample in manifest.iter().filter(|sample| !is_rejected(sample)) {
+        let font = sample["font"].as_str().unwrap_or_default();
+        let label = sample["label"]["name"].as_str().unwrap_or(font).to_string();
+        let next = label_ids.len();
+        let label = *label_ids.entry(label).or_insert(next);
+        let index = sample["index"].as_u64().unwrap_or_default() as usize;
+        samples.push(LabeledSample {
+            key: sample["key"]
+                .as_str()
+                .map_or_else(|| EncodedSample::key_for(font, index), str::to_string),
+            label,
+            parameters: audited_parameters(sample),
+        });
+    }
+    if label_ids.len() < 2 {
+        return Err(format!("Need samples of at least two labels under {}", OUTPUT_DIR).into());
+    }
+
+    let all: Vec<&LabeledSample> = samples.iter().collect();
+    let label_counts = |samples: &[&LabeledSample]| {
+        let mut counts = vec![0; label_ids.len()];
+        for sample in samples {
+            counts[sample.label] += 1;
+        }
+        counts
+    };
+    let counts = label_counts(&all);
+    let label_entropy = entropy(counts.iter().copied());
+    let baseline = *counts.iter().max().unwrap_or(&0) as f64 / samples.len() as f64;
+
+    let parameters: BTreeSet<&String> = samples
+        .iter()
+        .flat_map(|sample| sample.parameters.keys())
+        .filter(|name| {
+            !ignored
+                .iter()
+                .any(|prefix| name.starts_with(prefix.as_str()))
+        })
+        .collect();
+    let mut results: Vec<Value> = parameters
+        .iter()
+        .map(|name| measure(&all, name, label_entropy))
+        .collect();
+    for result in &mut results {
+        result["flagged"] = json!(result["normalized"].as_f64().unwrap_or(0.0) > threshold);
+    }
+    results.sort_by(|a, b| {
+        b["normalized"]
+            .as_f64()
+            .partial_cmp(&a["normalized"].as_f64())
+            .unwrap()
+    });
+    let flagged: Vec<String> = results
+        .iter()
+        .filter(|result| result["flagged"] == true)
+        .filter_map(|result| result["parameter"].as_str().map(str::to_string))
+        .collect();
+
+    let dir = format!("{}/{}", OUTPUT_DIR, LEAKAGE_DIR);
+    fs::create_dir_all(&dir)?;
+    let mut report = json!({
+        "samples": samples.len(),
+        "labels": label_ids.len(),
+        "label_entropy": label_entropy,
+        "baseline_accuracy": baseline,
+        "threshold": threshold,
+        "ignored": ignored,
+        "parameters": results,
+    });
+
+    println!(
+        "{} {} samples, {} labels (majority baseline {:.1}%)",
+        "Leakage audit of".green(),
+        samples.len(),
+        label_ids.len(),
+        100.0 * baseline
+    );
+    for result in report["parameters"].as_array().into_iter().flatten() {
+        if result["flagged"] == true {
+            println!(
+                "  {} {}: {:.3} of the label entropy, {:.1}% accuracy alone",
+                "leaks".red(),
+                result["parameter"].as_str().unwrap_or_default(),
+                result["normalized"].as_f64().unwrap_or(0.0),
+                100.0 * result["accuracy"].as_f64().unwrap_or(0.0)
+            );
+        }
+    }
+
+    match resample {
+        Some(seed) if !flagged.is_empty() => {
+            let columns: Vec<Vec<usize>> =
+                flagged.iter().map(|name| bin_column(&all, name)).collect();
+            let strata: Vec<(String, usize)> = all
+                .iter()
+                .enumerate()
+                .map(|(i, sample)| {
+                    let bins: Vec<String> =
+                        columns.iter().map(|column| column[i].to_string()).collect();
+                    (bins.join("/"), sample.label)
+                })
+                .collect();
+            let keep = stratified_keep(&strata, &mut StdRng::seed_from_u64(seed));
+            let kept: Vec<&LabeledSample> = all
+                .iter()
+                .zip(&keep)
+                .filter(|(_, keep)| **keep)
+                .map(|(sample, _)| *sample)
+                .collect();
+            let kept_entropy = entropy(label_counts(&kept).into_iter());
+            let after: Vec<Value> = flagged
+                .iter()
+                .map(|name| measure(&kept, name, kept_entropy))
+                .collect();
+            let keys: Vec<&str> = kept.iter().map(|sample| sample.key.as_str()).collect();
+            fs::write(
+                format!("{}/keep.json", dir),
+                serde_json::to_string_pretty(&json!({ "seed": seed, "keys": keys }))?,
+            )?;
+            report["resample"] = json!({
+                "seed": seed,
+                "strata_parameters": flagged,
+                "kept": kept.len(),
+                "after": after,
+            });
+            println!(
+                "{} {} of {} samples: {}/keep.json",
+                "Stratified resample keeps".green(),
+                kept.len(),
+                samples.len(),
+                dir
+            );
+        }
+        Some(_) => println!("Nothing to resample; no parameter is over the threshold."),
+        None if !flagged.is_empty() => {
+            println!("Run with --resample to pick a subset balanced over the flagged parameters.")
+        }
+        None => println!("No parameter is over the threshold of {}.", threshold),
+    }
+
+    fs::write(
+        format!("{}/report.json", dir),
+        serde_json::to_string_pretty(&report)?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dependent_parameters_leak_and_resampling_removes_it() {
+        // Label 0 mostly in bin 0, label 1 mostly in bin 1.
+        let mut pairs = Vec::new();
+        for i in 0..200 {
+            let label = i % 2;
+            let bin = if i % 10 == 0 { 1 - label } else { label };
+            pairs.push((bin, label));
+        }
+        let information = mutual_information(&pairs);
+        assert!(information > 0.4, "{}", information);
+        assert!((majority_accuracy(&pairs) - 0.9).abs() < 1e-9);
+
+        // A parameter drawn independently of the label carries next to nothing.
+        let independent: Vec<(usize, usize)> = (0..200).map(|i| ((i / 2) % 5, i % 2)).collect();
+        assert!(mutual_information(&independent) < 0.01);
+
+        let strata: Vec<(String, usize)> = pairs
+            .iter()
+            .map(|&(bin, label)| (bin.to_string(), label))
+            .collect();
+        let keep = stratified_keep(&strata, &mut StdRng::seed_from_u64(1));
+        let kept: Vec<(usize, usize)> = pairs
+            .iter()
+            .zip(&keep)
+            .filter(|(_, keep)| **keep)
+            .map(|(pair, _)| *pair)
+            .collect();
+        assert_eq!(kept.len(), 40);
+        assert_eq!(mutual_information(&kept), 0.0);
+    }
+
+    #[test]
+    fn text_length_and_line_counts_are_audited() {
+        let sample = json!({
+            "phrase": "سلام بر شما",
+            "layout": { "outcome": "fits", "lines": 3 },
+            "style": { "paragraph": { "target_lines": 3 } },
+        });
+        let parameters = audited_parameters(&sample);
+        assert_eq!(parameters["phrase.chars"], 11);
+        assert_eq!(parameters["phrase.words"], 3);
+        assert_eq!(parameters["layout.lines"], 3);
+        assert_eq!(parameters["style.paragraph.target_lines"], 3);
+    }
+}
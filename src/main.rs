@@ -1,17 +1,55 @@
+mod audit;
+mod augment;
 mod browser;
+mod camera;
+mod columnar;
+mod degrade;
+mod encoding;
+mod fonts;
+mod gallery;
+mod labels;
+mod layout;
+mod leakage;
+mod manifest;
+mod preview;
+mod profiles;
+mod records;
+mod scene;
+mod seeding;
+mod serve;
+mod shards;
+mod sinks;
+mod specimen;
+mod stats;
 mod styles;
+use crate::augment::{augment_screenshot, quad_bounds, quad_to_json, AugmentationChain};
 use crate::browser::BrowserManager;
-use crate::styles::create_html_content;
+use crate::camera::CameraProfile;
+use crate::columnar::split_for;
+use crate::degrade::ScanProfile;
+use crate::encoding::{ColorMode, ImageFormat, OutputEncoding, ResizePolicy};
+use crate::fonts::FontFile;
+use crate::labels::{ClassIndex, LabelLevel};
+use crate::layout::{check_layout, ClipStats, FitPolicy, LayoutReport, Rect};
+use crate::manifest::Manifest;
+use crate::profiles::{StyleProfile, StyleProfiles};
+use crate::records::{RecordFormat, EXPORT_DIR};
+use crate::seeding::task_rng;
+use crate::shards::ShardConfig;
+use crate::sinks::{image_extension, open_sink, EncodedSample, OutputSink, OutputSpec};
+use crate::styles::{create_html_content, FontMix, HtmlSample, PageOptions, TextBlock};
 
-use base64::{engine::general_purpose::STANDARD, Engine as _};
 use colored::*;
 use futures::future::join_all;
 use headless_chrome::protocol::cdp::Emulation;
 use headless_chrome::protocol::cdp::Page::CaptureScreenshotFormatOption;
 use headless_chrome::{Browser, Tab};
+use image::codecs::jpeg::JpegEncoder;
+use image::RgbImage;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::{thread_rng, Rng};
-use serde_json::Value;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
 use tokio::fs as async_fs;
 use tokio::fs::File as AsyncFile;
 use tokio::io::AsyncReadExt;
@@ -20,9 +58,11 @@ use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::{RwLock, Semaphore};
+use tokio::task;
 
 const SEMAPHORES: usize = 12;
 const IMAGES_PER_FONT: usize = 250;
@@ -31,24 +71,336 @@ const FONTS_DIR: &str = "./fonts";
 const TEMPLATE_PATH: &str = "./index.html";
 const PHRASES_PATH: &str = "../dataGenerator/texts/phrases.json";
 const IMAGE_FOLDER: &str = "../dataGenerator/background";
+// Characters of text gathered per target line in paragraph mode.
+const PARAGRAPH_CHARS_PER_LINE: usize = 45;
+// Most fonts on one multi-font page; more blocks no longer fit a legible band each.
+const MAX_FONTS_PER_IMAGE: usize = 8;
+// Label ids at every granularity; kept outside OUTPUT_DIR so ids stay stable across runs.
+const CLASSES_PATH: &str = "./classes.json";
+// Style profiles merged over the built-in ones; see profiles.rs for the format.
+const STYLES_PATH: &str = "./styles.toml";
+// Screenshots of pages the layout check rejected, under OUTPUT_DIR, one folder per font.
+const REJECTED_DIR: &str = "rejected";
+// Directories under OUTPUT_DIR that hold exports and reports rather than samples of a font.
+const NON_SAMPLE_DIRS: [&str; 9] = [
+    "gallery",
+    "shards",
+    "parquet",
+    "tfrecord",
+    "lmdb",
+    "stats",
+    "leakage",
+    REJECTED_DIR,
+    EXPORT_DIR,
+];
 
-// Caches base64-encoded font files per font directory. Key is the directory path.
-static FONT_BASE64_CACHE: Lazy<RwLock<HashMap<String, Arc<Vec<String>>>>> =
+// Command-line switches for a generation run.
+#[derive(Clone)]
+struct RunOptions {
+    fit_policy: FitPolicy,
+    augmentations: Arc<AugmentationChain>,
+    // Name of the scan degradation profile; replaces the augmentation chain and switches
+    // the styles to the printed-document look.
+    scan_profile: Option<String>,
+    // Phone-capture simulation, run after the scan profile or in place of the default chain.
+    camera: bool,
+    // Composite text onto flat regions of real background photos.
+    scene: bool,
+    // Number of fonts per page in multi-font compositions; 1 keeps one font per image.
+    fonts_per_image: usize,
+    // Mix the extra fonts into the phrase word by word instead of as separate blocks.
+    font_spans: bool,
+    // Inclusive range of line counts for paragraph mode.
+    paragraph_lines: Option<(usize, usize)>,
+    // Share of samples rendered with a synthesized bold, oblique or width variant.
+    synthetic: f64,
+    // Granularity of the `label` written with every sample.
+    label_level: LabelLevel,
+    // Where samples go: the per-font folders unless archive formats or an object store are
+    // chosen. Every selected output gets every sample.
+    outputs: Vec<OutputSpec>,
+    // Opened by the run once the class names are known.
+    sink: Option<Arc<dyn OutputSink>>,
+    // Every sample's metadata, whatever the sink; only generation runs keep one.
+    manifest: Option<Arc<Manifest>>,
+    // File format, color mode and crop/resize of the saved images.
+    encoding: OutputEncoding,
+    // Split written with every sample instead of the one drawn from its key.
+    split: Option<&'static str>,
+    // Distributions the page styles are drawn from, and per-font replacements for it.
+    style_profile: Arc<StyleProfile>,
+    font_profiles: HashMap<String, Arc<StyleProfile>>,
+}
+
+impl RunOptions {
+    fn style_profile(&self, font: &str) -> &StyleProfile {
+        self.font_profiles.get(font).unwrap_or(&self.style_profile)
+    }
+
+    fn from_args(args: &[String]) -> Result<RunOptions, String> {
+        let mut options = RunOptions {
+            fit_policy: FitPolicy::Shrink,
+            augmentations: Arc::new(AugmentationChain::empty()),
+            scan_profile: None,
+            camera: false,
+            scene: false,
+            fonts_per_image: 1,
+            font_spans: false,
+            paragraph_lines: None,
+            label_level: LabelLevel::Class,
+            synthetic: 0.0,
+            outputs: Vec::new(),
+            sink: None,
+            manifest: None,
+            encoding: OutputEncoding::default(),
+            split: None,
+            style_profile: StyleProfiles::builtin().default_profile(),
+            font_profiles: HashMap::new(),
+        };
+        let mut augment = true;
+        let mut custom_chain = None;
+        let mut profile_names = Vec::new();
+        let mut scan = None;
+        let mut shuffle = None;
+        for arg in args {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            match flag {
+                "--reject-overflow" => options.fit_policy = FitPolicy::Reject,
+                "--no-augment" => augment = false,
+                "--augment" => match AugmentationChain::parse(value.unwrap_or_default()) {
+                    Ok(chain) => custom_chain = Some(chain),
+                    Err(e) => return Err(format!("--augment takes op[:probability],...: {}", e)),
+                },
+                "--scan" => {
+                    let spec = value.unwrap_or("medium");
+                    match ScanProfile::parse(spec) {
+                        Ok(profile) => {
+                            scan = Some(profile);
+                            options.scan_profile = Some(spec.to_string());
+                        }
+                        Err(e) => {
+                            return Err(format!(
+                                "--scan takes [preset][,effect=severity...]: {}",
+                                e
+                            ))
+                        }
+                    }
+                }
+                "--camera" => options.camera = true,
+                "--scene" => options.scene = true,
+                "--paragraph" => {
+                    let range = value.unwrap_or("3-8");
+                    let (min, max) = range.split_once('-').unwrap_or((range, range));
+                    match (min.parse::<usize>(), max.parse::<usize>()) {
+                        (Ok(min), Ok(max)) if 1 <= min && min <= max => {
+                            options.paragraph_lines = Some((min, max))
+                        }
+                        _ => return Err(format!("Invalid paragraph line range {}", range)),
+                    }
+                }
+                "--synthetic" => match value.unwrap_or("0.3").parse::<f64>() {
+                    Ok(share) if (0.0..=1.0).contains(&share) => options.synthetic = share,
+                    _ => return Err("--synthetic takes a share between 0 and 1".to_string()),
+                },
+                "--label" => match value.and_then(LabelLevel::by_name) {
+                    Some(level) => options.label_level = level,
+                    None => {
+                        return Err("--label must be one of class, family, face, weight".to_string())
+                    }
+                },
+                "--multi-font" | "--font-spans" => match value.unwrap_or("3").parse::<usize>() {
+                    Ok(count) if (2..=MAX_FONTS_PER_IMAGE).contains(&count) => {
+                        options.fonts_per_image = count;
+                        options.font_spans = flag == "--font-spans";
+                    }
+                    _ => {
+                        return Err(format!(
+                            "{} takes 2 to {} fonts per image",
+                            flag, MAX_FONTS_PER_IMAGE
+                        ))
+                    }
+                },
+                "--folder" => options.outputs.push(OutputSpec::Folder),
+                "--shards" => match value.unwrap_or("512").parse::<u64>() {
+                    Ok(megabytes) if megabytes > 0 => {
+                        options.outputs.push(OutputSpec::Shards(ShardConfig {
+                            max_bytes: megabytes * 1024 * 1024,
+                            shuffle: 0,
+                        }))
+                    }
+                    _ => return Err("--shards takes a shard size in megabytes".to_string()),
+                },
+                "--parquet" => match value.unwrap_or("256").parse::<usize>() {
+                    Ok(rows) if rows > 0 => options.outputs.push(OutputSpec::Parquet {
+                        row_group_rows: rows,
+                    }),
+                    _ => return Err("--parquet takes a row group size in rows".to_string()),
+                },
+                "--records" => match value.and_then(RecordFormat::by_name) {
+                    Some(format) => options.outputs.push(OutputSpec::Records(format)),
+                    None => return Err("--records must be one of tfrecord, lmdb".to_string()),
+                },
+                "--object-store" => match value {
+                    Some(url) => options
+                        .outputs
+                        .push(OutputSpec::ObjectStore(url.to_string())),
+                    None => {
+                        return Err(
+                            "--object-store takes an http://host:port/bucket URL".to_string()
+                        )
+                    }
+                },
+                "--format" => {
+                    let spec = value.unwrap_or("jpeg");
+                    let (name, quality) = match spec.split_once(':') {
+                        Some((name, range)) => (name, Some(range)),
+                        None => (spec, None),
+                    };
+                    match ImageFormat::by_name(name) {
+                        Some(format) => options.encoding.format = format,
+                        None => return Err("--format must be one of jpeg, png, webp".to_string()),
+                    }
+                    if let Some(range) = quality {
+                        let (min, max) = range.split_once('-').unwrap_or((range, range));
+                        match (min.parse::<u8>(), max.parse::<u8>()) {
+                            (Ok(min), Ok(max)) if 1 <= min && min <= max && max <= 100 => {
+                                options.encoding.quality = (min, max)
+                            }
+                            _ => return Err(format!("Invalid JPEG quality range {}", range)),
+                        }
+                    }
+                }
+                "--color" => match value.and_then(ColorMode::by_name) {
+                    Some(mode) => options.encoding.color = mode,
+                    None => return Err("--color must be one of rgb, gray, binary".to_string()),
+                },
+                "--resize" => match value.and_then(ResizePolicy::parse) {
+                    Some(policy) => options.encoding.resize = Some(policy),
+                    None => return Err("--resize takes letterbox:N or short:N".to_string()),
+                },
+                "--crop-text" => {
+                    let range = value.unwrap_or("0.1-0.5");
+                    let (min, max) = range.split_once('-').unwrap_or((range, range));
+                    match (min.parse::<f64>(), max.parse::<f64>()) {
+                        (Ok(min), Ok(max)) if 0.0 <= min && min <= max => {
+                            options.encoding.crop_padding = Some((min, max))
+                        }
+                        _ => return Err(format!("Invalid text crop padding range {}", range)),
+                    }
+                }
+                "--style-profile" => match value {
+                    Some(spec) => profile_names.push(spec),
+                    None => return Err("--style-profile takes NAME or FONT:NAME".to_string()),
+                },
+                "--shuffle-shards" => match value.unwrap_or("1000").parse::<usize>() {
+                    Ok(buffer) => shuffle = Some(buffer),
+                    Err(_) => {
+                        return Err("--shuffle-shards takes a buffer size in samples".to_string())
+                    }
+                },
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+        if let Some(buffer) = shuffle {
+            let mut sharded = false;
+            for output in &mut options.outputs {
+                if let OutputSpec::Shards(config) = output {
+                    config.shuffle = buffer;
+                    sharded = true;
+                }
+            }
+            if !sharded {
+                return Err("--shuffle-shards only applies with --shards".to_string());
+            }
+        }
+        if options.outputs.is_empty() {
+            options.outputs.push(OutputSpec::Folder);
+        }
+        // Compositions lay out their own blocks, so the scene and document page styles are
+        // never reached; the scan degradation still runs.
+        if options.fonts_per_image > 1 && !options.font_spans && (options.scene || scan.is_some()) {
+            eprintln!("--multi-font pages ignore the --scene and --scan page styles");
+        }
+        if !profile_names.is_empty() {
+            let profiles = StyleProfiles::load(STYLES_PATH).unwrap_or_else(|e| {
+                eprintln!("{}; using the built-in style profiles", e);
+                StyleProfiles::builtin().clone()
+            });
+            for spec in profile_names {
+                let (font, name) = match spec.rsplit_once(':') {
+                    Some((font, name)) => (Some(font), name),
+                    None => (None, spec),
+                };
+                match (profiles.get(name), font) {
+                    (Some(profile), Some(font)) => {
+                        options.font_profiles.insert(font.to_string(), profile);
+                    }
+                    (Some(profile), None) => options.style_profile = profile,
+                    (None, _) => {
+                        return Err(format!(
+                            "Unknown style profile {}; choose from {}",
+                            name,
+                            profiles.names().join(", ")
+                        ))
+                    }
+                }
+            }
+        }
+
+        // `--no-augment` drops the default and chosen augmentations; the scan and camera
+        // simulations asked for by flag still run.
+        let mut chain = match &scan {
+            Some(profile) => profile.chain(),
+            None => AugmentationChain::empty(),
+        };
+        if augment {
+            // Chosen augmentations replace the default chain and run after a scan profile.
+            chain = chain.followed_by(match custom_chain {
+                Some(custom) => custom,
+                None if options.camera || scan.is_some() => AugmentationChain::empty(),
+                None => AugmentationChain::default(),
+            });
+        }
+        if options.camera {
+            chain = chain.followed_by(CameraProfile::default().chain());
+        }
+        options.augmentations = Arc::new(chain);
+        Ok(options)
+    }
+}
+
+// Caches parsed font files per font directory. Key is the directory path.
+static FONT_FILE_CACHE: Lazy<RwLock<HashMap<String, Arc<Vec<FontFile>>>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 
-async fn convert_font_to_base64(font_path: &str) -> Result<String, std::io::Error> {
+async fn read_font_file(font_path: &str) -> Result<Vec<FontFile>, std::io::Error> {
     let mut file = AsyncFile::open(font_path).await?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer).await?;
-    let encoded = STANDARD.encode(&buffer);
 
-    Ok(encoded)
+    // READMEs, licenses and the like share the font directories; skip them.
+    let file_name = std::path::Path::new(font_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(font_path);
+    match FontFile::load(file_name, &buffer) {
+        Ok(faces) => Ok(faces),
+        Err(e) => {
+            eprintln!("Skipping {}: {}", font_path, e);
+            Ok(Vec::new())
+        }
+    }
 }
 
-async fn get_font_vector(font_dir: &str) -> Result<Arc<Vec<String>>, Box<dyn Error + Send + Sync>> {
+async fn get_font_vector(
+    font_dir: &str,
+) -> Result<Arc<Vec<FontFile>>, Box<dyn Error + Send + Sync>> {
     // Fast path: read lock and return cached clone if present
     {
-        let cache_guard = FONT_BASE64_CACHE.read().await;
+        let cache_guard = FONT_FILE_CACHE.read().await;
         if let Some(cached) = cache_guard.get(font_dir) {
             return Ok(cached.clone());
         }
@@ -61,18 +413,20 @@ async fn get_font_vector(font_dir: &str) -> Result<Arc<Vec<String>>, Box<dyn Err
     while let Some(entry) = font_files.next_entry().await? {
         let path_str = entry.path();
         if path_str.is_file() {
-            let base64_font = convert_font_to_base64(path_str.to_str().unwrap()).await?;
-            font_data.push(base64_font);
+            let faces = read_font_file(path_str.to_str().unwrap()).await?;
+            font_data.extend(faces);
         }
     }
 
     if font_data.is_empty() {
         return Err(format!("not font found in {}", font_dir).into());
     }
+    // Directory order is arbitrary; sort so label ids and sampling do not depend on it.
+    font_data.sort_by(|a: &FontFile, b| (&a.file, a.face).cmp(&(&b.file, b.face)));
 
     let result_arc = Arc::new(font_data);
     {
-        let mut cache_guard = FONT_BASE64_CACHE.write().await;
+        let mut cache_guard = FONT_FILE_CACHE.write().await;
         cache_guard.insert(font_dir.to_string(), Arc::clone(&result_arc));
     }
 
@@ -96,10 +450,8 @@ async fn recreate_output_dir(
 async fn get_available_fonts(fonts_dir: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
     let paths = fs::read_dir(fonts_dir)?;
     let mut fonts = Vec::new();
-    for path in paths {
-        if let Ok(entry) = path {
-            fonts.push(entry.file_name().into_string().unwrap());
-        }
+    for entry in paths.flatten() {
+        fonts.push(entry.file_name().into_string().unwrap());
     }
     Ok(fonts)
 }
@@ -125,7 +477,7 @@ fn assign_phrases_to_fonts(
 
     for phrase in phrases {
         if let Some(font) = font_cycle.next() {
-            let font_entry = assignments.entry(font.clone()).or_insert(Vec::new());
+            let font_entry = assignments.entry(font.clone()).or_default();
             if font_entry.len() < limit {
                 font_entry.push(phrase.clone());
             }
@@ -172,47 +524,194 @@ async fn get_image_buffers() -> Result<Vec<Arc<Vec<u8>>>, String> {
 
 async fn process_font(
     font: &str,
-    phrase_assignments: &Vec<String>,
-    html_template: &String,
-    images: &Vec<Arc<Vec<u8>>>,
+    phrase_assignments: &[String],
+    assets: &Assets,
     browser: Arc<Browser>,
+    options: &RunOptions,
 ) -> Result<String, Box<dyn Error + Send + Sync>> {
-    let font_dir = format!("{}/{}", FONTS_DIR, font);
-    let base64_fonts = get_font_vector(&font_dir).await?;
-
+    // Fail the whole font early when its files do not load; the cache keeps them warm.
+    get_font_vector(&format!("{}/{}", FONTS_DIR, font)).await?;
+    let mut clip_stats = ClipStats::default();
     let tab = browser.new_tab().unwrap();
     for (i, phrase) in phrase_assignments.iter().enumerate() {
-        let base64_font = base64_fonts.choose(&mut thread_rng()).unwrap();
-
-        let html_content =
-            create_html_content(&font, &html_template, &phrase, &base64_font, &images, None)
-                .await
-                .expect("failed to generate html content");
-
-        if let Err(e) = create_image(&tab, &html_content, &font, i).await {
-            eprintln!("Error creating image for font {}: {}", font, e);
-            continue;
+        let sample = Sample {
+            font,
+            face: None,
+            phrase,
+            pool: phrase_assignments,
+            index: i,
+        };
+        match render_sample(&tab, &sample, assets, options).await {
+            Ok(report) => clip_stats.record(&report.outcome),
+            Err(e) => {
+                eprintln!("Error creating image for font {}: {}", font, e);
+                continue;
+            }
         }
     }
     tab.close(false).unwrap();
 
     Ok(format!(
-        "{} {}!",
+        "{} {}! ({})",
         "Created the data for".green(),
-        font.red()
+        font.red(),
+        clip_stats
     ))
 }
 
-async fn create_image(
-    tab: &Tab,
-    html_content: &str,
-    font: &str,
+/// Assets every render reads, loaded once per run.
+struct Assets {
+    html_template: String,
+    images: Vec<Arc<Vec<u8>>>,
+    classes: ClassIndex,
+}
+
+/// What to render: a phrase in one font class, and the pool paragraphs and extra blocks
+/// draw more text from.
+struct Sample<'a> {
+    font: &'a str,
+    // Index into the class's font files; drawn at random when absent.
+    face: Option<usize>,
+    phrase: &'a str,
+    pool: &'a [String],
     index: usize,
-) -> Result<(), Box<dyn Error>> {
-    let width = thread_rng().gen_range(400..1000);
-    let height = thread_rng().gen_range(400..1000);
-    let quality = thread_rng().gen_range(77..100);
+}
+
+/// A sample's page before it is rendered, with the text and labels it was built for.
+struct Page {
+    html_sample: HtmlSample,
+    phrase: String,
+    labels: Value,
+    /// The split of the sample's own phrase; every other text on the page comes from it too.
+    split: &'static str,
+}
+
+/// Styles, renders and writes one sample to the options' sink.
+async fn render_sample(
+    tab: &Tab,
+    sample: &Sample<'_>,
+    assets: &Assets,
+    options: &RunOptions,
+) -> Result<LayoutReport, Box<dyn Error + Send + Sync>> {
+    let page = build_page(sample, assets, options).await?;
+    create_image(tab, &page, sample.font, sample.index, options)
+        .await
+        .map_err(|e| e.to_string().into())
+}
 
+/// Picks the face, text and extra blocks of a sample and styles its page.
+async fn build_page(
+    sample: &Sample<'_>,
+    assets: &Assets,
+    options: &RunOptions,
+) -> Result<Page, Box<dyn Error + Send + Sync>> {
+    let font = sample.font;
+    let font_files = get_font_vector(&format!("{}/{}", FONTS_DIR, font)).await?;
+    let font_file = match sample.face {
+        Some(face) => font_files
+            .get(face)
+            .ok_or_else(|| format!("{} has no face {}", font, face))?,
+        None => font_files.choose(&mut task_rng()).unwrap(),
+    };
+
+    // Paragraph and extra-block text stays in the sample's split, so no test phrase is ever
+    // rendered into a training image.
+    let split = options.split.unwrap_or_else(|| split_for(sample.phrase));
+    let pool: Vec<&str> = sample
+        .pool
+        .iter()
+        .map(String::as_str)
+        .filter(|phrase| split_for(phrase) == split)
+        .collect();
+
+    let paragraph_lines = options
+        .paragraph_lines
+        .map(|(min, max)| task_rng().gen_range(min..=max));
+    let phrase = &match paragraph_lines {
+        Some(lines) => build_paragraph(sample.phrase, &pool, lines),
+        None => sample.phrase.to_string(),
+    };
+
+    let method = if options.scene {
+        Some("scene")
+    } else {
+        options.scan_profile.as_ref().map(|_| "document")
+    };
+
+    // Extra blocks come from other font classes, so each block's label is distinct.
+    let all_fonts = assets.classes.classes();
+    let other_fonts: Vec<&String> = all_fonts.iter().filter(|f| *f != font).collect();
+    let mut extra_fonts = Vec::new();
+    let picked: Vec<&String> = other_fonts
+        .choose_multiple(&mut task_rng(), options.fonts_per_image - 1)
+        .copied()
+        .collect();
+    for other in picked {
+        let other_files = get_font_vector(&format!("{}/{}", FONTS_DIR, other)).await?;
+        let other_phrase = *pool.choose(&mut task_rng()).unwrap_or(&sample.phrase);
+        extra_fonts.push((other.as_str(), other_files, other_phrase));
+    }
+    let extra_blocks: Vec<TextBlock> = extra_fonts
+        .iter()
+        .map(|(other, other_files, other_phrase)| TextBlock {
+            font_name: other,
+            font: other_files.choose(&mut task_rng()).unwrap(),
+            phrase: other_phrase,
+        })
+        .collect();
+
+    let synthetic = task_rng().gen_bool(options.synthetic);
+    let html_sample = create_html_content(
+        &assets.html_template,
+        &TextBlock {
+            font_name: font,
+            font: font_file,
+            phrase,
+        },
+        &assets.images,
+        PageOptions {
+            method,
+            mix: match (extra_blocks.is_empty(), options.font_spans) {
+                (true, _) => FontMix::Single,
+                (false, false) => FontMix::Blocks(&extra_blocks),
+                (false, true) => FontMix::Spans(&extra_blocks),
+            },
+            paragraph_lines,
+            synthetic,
+            profile: options.style_profile(font),
+            keep_weight: options.label_level.names_weight(),
+        },
+    )
+    .await
+    .map_err(|e| format!("Failed to generate html content: {}", e))?;
+
+    Ok(Page {
+        labels: assets.classes.labels(font, font_file),
+        html_sample,
+        phrase: phrase.clone(),
+        split,
+    })
+}
+
+// Starts from the sample's own phrase and appends random phrases from the same pool until
+// there is enough text to fill `lines` lines.
+fn build_paragraph(phrase: &str, pool: &[&str], lines: usize) -> String {
+    let target = lines * PARAGRAPH_CHARS_PER_LINE;
+    let mut text = phrase.to_string();
+    while text.chars().count() < target {
+        match pool.choose(&mut task_rng()) {
+            Some(next) => {
+                text.push(' ');
+                text.push_str(next);
+            }
+            None => break,
+        }
+    }
+    text
+}
+
+// Sizes the viewport and writes `html` into the tab.
+fn load_page(tab: &Tab, html: &str, width: u32, height: u32) -> Result<(), Box<dyn Error>> {
     tab.call_method(Emulation::SetDeviceMetricsOverride {
         width,
         height,
@@ -234,30 +733,349 @@ async fn create_image(
         document.open();
         document.write(`{}`);
          "#,
-        html_content
+        html
     );
 
     tab.evaluate(js.as_str(), true)
         .map_err(|e| format!("Failed to inject HTML: {}", e))?;
+    Ok(())
+}
 
-    let screenshot = tab
-        .capture_screenshot(
-            CaptureScreenshotFormatOption::Jpeg,
-            Some(quality),
-            None,
-            true,
+/// A face with its pages laid out in the simple style, so every face is set the same way.
+struct SimplePages {
+    class: String,
+    font: FontFile,
+    html: Vec<String>,
+}
+
+/// Lays out `phrases` in the simple style for the faces `pick` chooses from each class under
+/// `FONTS_DIR`, skipping classes whose fonts do not load, and renders every face on one tab
+/// with `render`. The tab API blocks, so the render loop runs on a blocking thread.
+async fn render_simple_pages<T, P, R>(
+    phrases: &[&str],
+    pick: P,
+    render: R,
+) -> Result<Vec<(SimplePages, Result<T, String>)>, Box<dyn Error + Send + Sync>>
+where
+    P: Fn(&[FontFile]) -> Vec<&FontFile>,
+    R: Fn(&Tab, &SimplePages) -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut classes = get_available_fonts(FONTS_DIR).await?;
+    classes.sort();
+    let template = async_fs::read_to_string(TEMPLATE_PATH).await?;
+
+    let mut faces = Vec::new();
+    for class in &classes {
+        let files = match get_font_vector(&format!("{}/{}", FONTS_DIR, class)).await {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Skipping class {}: {}", class, e);
+                continue;
+            }
+        };
+        for font in pick(&files) {
+            let mut html = Vec::new();
+            for phrase in phrases {
+                let text = TextBlock {
+                    font_name: class,
+                    font,
+                    phrase,
+                };
+                let sample = create_html_content(
+                    &template,
+                    &text,
+                    &[],
+                    PageOptions {
+                        method: Some("simple"),
+                        mix: FontMix::Single,
+                        paragraph_lines: None,
+                        synthetic: false,
+                        profile: &StyleProfiles::builtin().default_profile(),
+                        keep_weight: true,
+                    },
+                )
+                .await?;
+                html.push(sample.html);
+            }
+            faces.push(SimplePages {
+                class: class.clone(),
+                font: font.clone(),
+                html,
+            });
+        }
+    }
+
+    let rendered = task::spawn_blocking(move || {
+        let browser = BrowserManager::new()
+            .create_browser()
+            .map_err(|e| format!("Failed to launch browser: {:?}", e))?;
+        let tab = browser.new_tab().map_err(|e| e.to_string())?;
+        let rendered: Vec<_> = faces
+            .into_iter()
+            .map(|face| {
+                let result = render(&tab, &face);
+                (face, result)
+            })
+            .collect();
+        let _ = tab.close(false);
+        Ok::<_, String>(rendered)
+    })
+    .await??;
+    Ok(rendered)
+}
+
+async fn create_image(
+    tab: &Tab,
+    page: &Page,
+    font: &str,
+    index: usize,
+    options: &RunOptions,
+) -> Result<LayoutReport, Box<dyn Error>> {
+    let Page {
+        html_sample,
+        phrase,
+        labels,
+        split,
+    } = page;
+    let (width, height) = html_sample.viewport.unwrap_or_else(|| {
+        (
+            task_rng().gen_range(400..1000),
+            task_rng().gen_range(400..1000),
         )
+    });
+
+    load_page(tab, &html_sample.html, width, height)?;
+
+    // Overflowing or off-screen text would be saved with a clean label, so it is either
+    // shrunk to fit or dropped here.
+    let layout = check_layout(tab, options.fit_policy)?;
+    if layout.is_rejected() {
+        let sizes: Vec<String> = layout
+            .blocks
+            .iter()
+            .map(|b| format!("{}px -> {}px", b.before.font_size, b.after.font_size))
+            .collect();
+        eprintln!(
+            "Rejected sample {} for font {}: {} ({})",
+            index,
+            font,
+            layout.outcome,
+            sizes.join(", ")
+        );
+        // Rejected pages never reach the sinks. The manifest keeps them with a screenshot, so
+        // the gallery can show what the layout check dropped.
+        if let Some(manifest) = &options.manifest {
+            let screenshot = tab
+                .capture_screenshot(CaptureScreenshotFormatOption::Png, None, None, true)
+                .map_err(|e| format!("Failed to capture screenshot: {}", e))?;
+            let file = format!("{}/{}/{}.png", REJECTED_DIR, font, index);
+            let path = Path::new(OUTPUT_DIR).join(&file);
+            fs::create_dir_all(path.parent().unwrap_or(Path::new(OUTPUT_DIR)))?;
+            fs::write(&path, screenshot)?;
+            let first = layout.blocks.first();
+            manifest.append(&json!({
+                "key": EncodedSample::key_for(font, index),
+                "font": font,
+                "label": labels[options.label_level.name()],
+                "labels": labels,
+                "index": index,
+                "split": split,
+                "phrase": phrase,
+                "viewport": [width, height],
+                "layout": {
+                    "outcome": layout.outcome.to_string(),
+                    "font_size": first.map(|block| block.after.font_size),
+                    "original_font_size": first.map(|block| block.before.font_size),
+                    "lines": first.map(|block| block.after.lines.len()),
+                },
+                "style": html_sample.style,
+                "scan_profile": options.scan_profile,
+                "camera": options.camera,
+                "rejected": true,
+                "file": file,
+            }))?;
+        }
+        return Ok(layout);
+    }
+
+    // Captured losslessly; the output encoding runs once, after the augmentations.
+    let screenshot = tab
+        .capture_screenshot(CaptureScreenshotFormatOption::Png, None, None, true)
         .map_err(|e| format!("Failed to capture screenshot: {}", e))?;
 
-    let output_image = format!("{}/{}/{}.jpg", OUTPUT_DIR, font, index);
-    async_fs::write(&output_image, &screenshot)
-        .await
-        .map_err(|e| format!("Failed to write image file {}: {}", output_image, e))?;
+    let chain = Arc::clone(&options.augmentations);
+    // Every box the annotations need goes through the augmentations as one flat list: the
+    // block boxes, then each span's lines, then each block's lines. Split apart again below.
+    let groups: Vec<Vec<Rect>> =
+        std::iter::once(layout.blocks.iter().map(|b| b.after.text_box).collect())
+            .chain(layout.spans.iter().map(|span| span.lines.clone()))
+            .chain(layout.blocks.iter().map(|b| b.after.lines.clone()))
+            .collect();
+    let text_quads: Vec<_> = groups.iter().flatten().map(Rect::corners).collect();
+    let encoding = options.encoding.clone();
+    // The blocking pool runs outside the task, so it gets its own generator seeded from it.
+    let mut rng = StdRng::seed_from_u64(task_rng().gen());
+    let (encoded, applied) = task::spawn_blocking(move || {
+        let (canvas, applied) = augment_screenshot(&screenshot, text_quads, &chain, &mut rng)?;
+        let encoded = encoding.encode(canvas.image, canvas.quads, &mut rng)?;
+        Ok::<_, String>((encoded, applied))
+    })
+    .await??;
+    let text_quads = encoded.quads;
 
-    Ok(())
+    // Quads stay in block order through the augmentations, so they line up with the fonts.
+    let mut rest = text_quads.as_slice();
+    let mut groups = groups.iter().map(|group| {
+        let (quads, tail) = rest.split_at(group.len());
+        rest = tail;
+        quads
+    });
+    let block_quads = groups.next().unwrap_or_default();
+    let span_quads: Vec<_> = groups.by_ref().take(layout.spans.len()).collect();
+    let line_quads: Vec<_> = groups.collect();
+
+    let blocks: Vec<Value> = layout
+        .blocks
+        .iter()
+        .zip(block_quads)
+        .zip(&html_sample.block_fonts)
+        .zip(&line_quads)
+        .map(|(((block, quad), block_font), lines)| {
+            json!({
+                "font": block_font,
+                "outcome": block.outcome.to_string(),
+                "font_size": block.after.font_size,
+                "original_font_size": block.before.font_size,
+                "quad": quad_to_json(quad),
+                "box": quad_bounds(quad),
+                "line_count": lines.len(),
+                "line_quads": lines.iter().map(quad_to_json).collect::<Vec<_>>(),
+                "line_boxes": lines.iter().map(quad_bounds).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    let spans: Vec<Value> = layout
+        .spans
+        .iter()
+        .zip(&span_quads)
+        .map(|(span, quads)| {
+            json!({
+                "font": span.font,
+                "text": span.text,
+                "quads": quads.iter().map(quad_to_json).collect::<Vec<_>>(),
+                "boxes": quads.iter().map(quad_bounds).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    let key = EncodedSample::key_for(font, index);
+    let annotations = json!({
+        "blocks": blocks,
+        "spans": spans,
+        "text_quads": block_quads.iter().map(quad_to_json).collect::<Vec<_>>(),
+        "text_boxes": block_quads.iter().map(quad_bounds).collect::<Vec<_>>(),
+    });
+    let metadata = json!({
+        "font": font,
+        "label": labels[options.label_level.name()],
+        "labels": labels,
+        "synthetic": html_sample.style.contains_key("synthetic"),
+        "index": index,
+        "split": split,
+        "phrase": phrase,
+        "viewport": [width, height],
+        "jpeg_quality": encoded.quality,
+        "encoding": encoded.metadata,
+        "layout": {
+            "outcome": layout.outcome.to_string(),
+            "font_size": layout.blocks[0].after.font_size,
+            "original_font_size": layout.blocks[0].before.font_size,
+            "lines": layout.blocks[0].after.lines.len(),
+        },
+        "style": html_sample.style,
+        "scan_profile": options.scan_profile,
+        "camera": options.camera,
+        "augmentations": applied,
+    });
+    let manifest_entry = options.manifest.as_ref().map(|_| {
+        let mut entry = metadata.clone();
+        entry["key"] = json!(key);
+        if options.outputs.contains(&OutputSpec::Folder) {
+            entry["file"] = json!(format!("{}/{}.{}", font, index, image_extension(&metadata)));
+        }
+        entry
+    });
+    let sink = options.sink.as_ref().ok_or("No output sink opened")?;
+    sink.write(Arc::new(EncodedSample {
+        key,
+        font: font.to_string(),
+        index,
+        image: encoded.bytes,
+        metadata,
+        annotations,
+    }))
+    .await?;
+    if let (Some(manifest), Some(entry)) = (&options.manifest, manifest_entry) {
+        manifest.append(&entry)?;
+    }
+
+    Ok(layout)
+}
+
+/// Reads the metadata sidecars of one font folder, ordered by sample index.
+fn read_samples(dir: &Path) -> Vec<Value> {
+    let mut samples: Vec<Value> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| serde_json::from_str(&fs::read_to_string(path).ok()?).ok())
+        .collect();
+    samples.sort_by_key(|sample| sample["index"].as_u64());
+    samples
+}
+
+/// Per-font sample folders under an output directory, sorted. Skips the directories other
+/// outputs and commands write there.
+fn sample_folders(dir: &str) -> Vec<String> {
+    let mut fonts: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| !NON_SAMPLE_DIRS.contains(&name.as_str()))
+        .collect();
+    fonts.sort();
+    fonts
+}
+
+fn encode_jpeg(image: &RgbImage, quality: u8) -> Result<Vec<u8>, String> {
+    let mut buffer = Vec::new();
+    JpegEncoder::new_with_quality(&mut buffer, quality)
+        .encode_image(image)
+        .map_err(|e| format!("Failed to encode image: {}", e))?;
+    Ok(buffer)
 }
 
-async fn async_main() -> Result<(), Box<dyn Error + Send + Sync>> {
+/// Registers every font class, keeping the ids of earlier runs, and saves the index.
+async fn load_class_index(fonts: &[String]) -> Result<ClassIndex, Box<dyn Error + Send + Sync>> {
+    let mut class_index = ClassIndex::load(CLASSES_PATH)?;
+    let mut sorted_fonts = fonts.to_vec();
+    sorted_fonts.sort();
+    for font in &sorted_fonts {
+        match get_font_vector(&format!("{}/{}", FONTS_DIR, font)).await {
+            Ok(files) => class_index.register(font, &files),
+            Err(e) => eprintln!("Skipping class {}: {}", font, e),
+        }
+    }
+    class_index.save(CLASSES_PATH)?;
+    Ok(class_index)
+}
+
+async fn async_main(mut options: RunOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
     let start = Instant::now();
 
     let (fonts_result, template_result, phrases_result, images_result) = tokio::join!(
@@ -272,7 +1090,22 @@ async fn async_main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let phrase_list = phrases_result?;
     let image_buffers = images_result?;
 
-    recreate_output_dir(OUTPUT_DIR, &available_fonts).await?;
+    // The folder sink fills one folder per font; other outputs bring their own layout.
+    let font_folders = match options.outputs.contains(&OutputSpec::Folder) {
+        true => available_fonts.clone(),
+        false => Vec::new(),
+    };
+    recreate_output_dir(OUTPUT_DIR, &font_folders).await?;
+
+    let class_index = load_class_index(&available_fonts).await?;
+    class_index.save(&format!("{}/classes.json", OUTPUT_DIR))?;
+    let sink: Arc<dyn OutputSink> = Arc::from(open_sink(
+        &options.outputs,
+        OUTPUT_DIR,
+        class_index.names(options.label_level),
+    )?);
+    options.sink = Some(Arc::clone(&sink));
+    options.manifest = Some(Arc::new(Manifest::create(OUTPUT_DIR)?));
     let phrase_assignments: HashMap<String, Vec<String>> =
         assign_phrases_to_fonts(&available_fonts, &phrase_list, IMAGES_PER_FONT);
 
@@ -281,10 +1114,14 @@ async fn async_main() -> Result<(), Box<dyn Error + Send + Sync>> {
         start.elapsed().as_millis()
     );
 
-    let image_buffers = Arc::new(image_buffers);
-    let html_template = Arc::new(html_template);
+    let assets = Arc::new(Assets {
+        html_template,
+        images: image_buffers,
+        classes: class_index,
+    });
     let available_fonts = Arc::new(available_fonts);
     let phrase_assignments = Arc::new(phrase_assignments);
+    let options = Arc::new(options);
     // let browser = Arc::from(BrowserManager::new());
     let browser_manager = BrowserManager::new();
     let browser = Arc::from(browser_manager.create_browser().unwrap());
@@ -296,18 +1133,18 @@ async fn async_main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut handles = Vec::new();
 
     for (index, font) in available_fonts.iter().enumerate() {
-        let html_template = Arc::clone(&html_template);
+        let assets = Arc::clone(&assets);
         let phrase_assignments = Arc::clone(&phrase_assignments);
-        let image_buffers = Arc::clone(&image_buffers);
         let font = font.clone();
         let tx = tx.clone();
         let browser = Arc::clone(&browser);
         let semaphore = Arc::clone(&semaphore);
+        let options = Arc::clone(&options);
 
         let handle = tokio::spawn(async move {
             let _permit = semaphore.acquire().await.unwrap();
             let result = if let Some(phrases) = phrase_assignments.get(&font) {
-                match process_font(&font, phrases, &html_template, &image_buffers, browser).await {
+                match process_font(&font, phrases, &assets, browser, &options).await {
                     Ok(msg) => (true, format!("result: {}", msg)),
                     Err(e) => (false, format!("Error: {}", e)),
                 }
@@ -351,6 +1188,9 @@ async fn async_main() -> Result<(), Box<dyn Error + Send + Sync>> {
     drop(tx);
     let _ = printer_handle.await?;
 
+    let summary = sink.finish().await?;
+    println!("Outputs: {}", summary);
+
     // Check for panics
     let panic_count = join_results.iter().filter(|res| res.is_err()).count();
     if panic_count > 0 {
@@ -365,7 +1205,7 @@ async fn async_main() -> Result<(), Box<dyn Error + Send + Sync>> {
         minutes, seconds
     );
 
-    println!("{}", "All tasks completed!"); // .cyan()
+    println!("All tasks completed!"); // .cyan()
 
     Ok(())
 }
@@ -378,5 +1218,42 @@ fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         .enable_all() // Enable all runtime features (I/O, time, etc.)
         .build()?;
 
-    runtime.block_on(async_main())
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("audit-fonts") => runtime.block_on(audit::run_audit()),
+        Some("specimen") => runtime.block_on(specimen::run_specimens()),
+        Some("gallery") => runtime.block_on(gallery::run_gallery()),
+        Some("stats") => runtime.block_on(stats::run_stats()),
+        Some("leakage") => runtime.block_on(leakage::run_leakage(&args[1..])),
+        Some("export") => runtime.block_on(records::run_export(&args[1..])),
+        Some("serve") => runtime.block_on(serve::run_serve(&args[1..])),
+        Some("preview") => runtime.block_on(preview::run_preview(&args[1..])),
+        _ => runtime.block_on(async_main(RunOptions::from_args(&args)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|flag| flag.to_string()).collect()
+    }
+
+    #[test]
+    fn mistyped_flags_stop_the_run() {
+        for flags in [
+            &["--style-profile=wlid"][..],
+            &["--records=tfrcord"],
+            &["--scan=hevy"],
+            &["--format=jpg:0-200"],
+            &["--resize=box:224"],
+            &["--shuffle-shards=100"],
+            &["--reject-overflw"],
+        ] {
+            assert!(RunOptions::from_args(&args(flags)).is_err(), "{:?}", flags);
+        }
+        let options = RunOptions::from_args(&args(&["--scan=heavy", "--format=png"])).unwrap();
+        assert_eq!(options.scan_profile.as_deref(), Some("heavy"));
+    }
 }
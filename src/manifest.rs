@@ -0,0 +1,115 @@
+use serde_json::Value;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::Mutex;
+
+// `OUTPUT_DIR/manifest.jsonl`: one line of metadata per sample of a generation run, whichever
+// outputs it went to, so `stats`, `leakage` and `gallery` work on archive and object-store
+// runs too. A line is the sample's metadata plus its `key` and, when the image is on disk,
+// its `file` relative to the output directory. Pages the layout check rejected are listed as
+// well, marked `rejected`, with their screenshot as the `file`.
+
+pub const MANIFEST_FILE: &str = "manifest.jsonl";
+
+/// The manifest of the current run; render tasks append to it concurrently.
+pub struct Manifest {
+    file: Mutex<File>,
+}
+
+impl Manifest {
+    /// Starts an empty manifest in `dir`, replacing the one of an earlier run.
+    pub fn create(dir: &str) -> Result<Manifest, String> {
+        let path = format!("{}/{}", dir, MANIFEST_FILE);
+        let file = File::create(&path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        Ok(Manifest {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, entry: &Value) -> Result<(), String> {
+        let mut line = entry.to_string();
+        line.push('\n');
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", MANIFEST_FILE, e))
+    }
+}
+
+/// Whether an entry is a page the layout check rejected rather than a sample of the dataset.
+pub fn is_rejected(entry: &Value) -> bool {
+    entry["rejected"] == true
+}
+
+/// The entries of the manifest in `dir`, ordered by font and index. Lines that do not parse,
+/// such as one cut short by an interrupted run, are skipped.
+pub fn read_manifest(dir: &str) -> Result<Vec<Value>, String> {
+    let path = format!("{}/{}", dir, MANIFEST_FILE);
+    let raw =
+        fs::read_to_string(&path).map_err(|_| format!("No {}; generate a dataset first", path))?;
+    let mut entries: Vec<Value> = raw
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    entries.sort_by_key(|entry| {
+        (
+            entry["font"].as_str().map(str::to_string),
+            entry["index"].as_u64(),
+        )
+    });
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn entries_read_back_in_font_and_index_order() {
+        let dir = std::env::temp_dir().join(format!("manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let manifest = Manifest::create(dir).unwrap();
+        for (font, index) in [("Vazir", 2), ("Sahel", 5), ("Vazir", 0)] {
+            manifest
+                .append(&json!({ "font": font, "index": index }))
+                .unwrap();
+        }
+        drop(manifest);
+        let path = format!("{}/{}", dir, MANIFEST_FILE);
+        let mut raw = fs::read_to_string(&path).unwrap();
+        raw.push_str("{\"font\": \"Vaz");
+        fs::write(&path, raw).unwrap();
+
+        let order: Vec<(String, u64)> = read_manifest(dir)
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                (
+                    entry["font"].as_str().unwrap().to_string(),
+                    entry["index"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("Sahel".to_string(), 5),
+                ("Vazir".to_string(), 0),
+                ("Vazir".to_string(), 2)
+            ]
+        );
+
+        assert!(is_rejected(&json!({ "rejected": true })));
+        assert!(!is_rejected(&json!({ "layout": { "outcome": "fits" } })));
+
+        // A new run starts over.
+        Manifest::create(dir).unwrap();
+        assert!(read_manifest(dir).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
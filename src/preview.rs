@@ -0,0 +1,558 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use colored::*;
+use headless_chrome::protocol::cdp::Page::CaptureScreenshotFormatOption;
+use headless_chrome::{Browser, Tab};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng, SeedableRng};
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::augment::{augment_screenshot, quad_bounds};
+use crate::browser::BrowserManager;
+use crate::encoding::{ImageFormat, OutputEncoding};
+use crate::layout::check_layout;
+use crate::profiles::StyleProfiles;
+use crate::seeding::{task_rng, with_seed};
+use crate::serve::{query_pairs, read_head, respond};
+use crate::{
+    build_page, get_available_fonts, get_font_vector, get_image_buffers, load_class_index,
+    load_page, load_phrases, Assets, RunOptions, Sample, FONTS_DIR, PHRASES_PATH, STYLES_PATH,
+    TEMPLATE_PATH,
+};
+
+// `preview`: a local page for style work. Pick a font, face and phrase, set the options a
+// run takes on the command line, and see the rendered page, its HTML and the recorded style.
+// Every render shows its seed; the same seed and controls render the same page again.
+// Style parameters can be pinned one at a time, so a single look can be studied while the
+// rest of the page is re-rolled.
+
+const PREVIEW_LISTEN: &str = "127.0.0.1:8643";
+// Base64 payloads longer than this are shortened in the HTML shown on the page.
+const MAX_INLINE_BASE64: usize = 64;
+
+const PREVIEW_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="UTF-8" />
+    <title>Style preview</title>
+    <style>
+      body { font-family: sans-serif; margin: 0; display: flex; min-height: 100vh; background: #f3f3f3; }
+      form { width: 300px; flex: none; background: #fff; padding: 12px; border-right: 1px solid #ccc; display: flex; flex-direction: column; gap: 8px; font-size: 13px; }
+      form textarea { direction: rtl; font-size: 15px; min-height: 60px; }
+      form input[type=number] { width: 90px; }
+      #pins { display: grid; grid-template-columns: auto 1fr; gap: 2px 6px; align-items: center; }
+      #pins input { width: 100%; box-sizing: border-box; }
+      main { flex: 1; padding: 12px; overflow: auto; }
+      #stage { position: relative; display: inline-block; background: #ddd; }
+      #stage img { display: block; }
+      .box { position: absolute; border: 1px solid #e0218a; pointer-events: none; }
+      #meta { font-size: 13px; margin: 8px 0; }
+      #error { color: #b00; }
+      pre { white-space: pre-wrap; font-size: 11px; background: #fff; padding: 8px; max-height: 360px; overflow: auto; }
+    </style>
+  </head>
+  <body>
+    <form id="controls">
+      <label>Font <select name="font"></select></label>
+      <label>Face <select name="face"><option value="">random</option></select></label>
+      <label>Phrase (empty picks one) <textarea name="phrase"></textarea></label>
+      <label>Method <select name="method">
+        <option value="random">random</option>
+        <option value="document">document</option>
+        <option value="scene">scene</option>
+      </select></label>
+      <label>Scan profile <select name="scan">
+        <option>light</option><option selected>medium</option><option>heavy</option>
+      </select></label>
+      <label>Style profile <select name="profile"></select></label>
+      <label>Paragraph lines <input type="number" name="paragraph" min="0" value="0" /></label>
+      <label>Extra fonts <input type="number" name="extra" min="0" value="0" /></label>
+      <label><input type="checkbox" name="spans" /> Mix extra fonts into the words</label>
+      <label><input type="checkbox" name="synthetic" /> Synthetic bold/oblique/width</label>
+      <label><input type="checkbox" name="camera" /> Camera capture</label>
+      <label><input type="checkbox" name="augment" /> Augmentations</label>
+      <label><input type="checkbox" name="boxes" checked /> Show text boxes</label>
+      <details>
+        <summary>Pinned style parameters</summary>
+        <p>Empty re-rolls with the seed; <code>off</code> disables, <code>on</code> enables, and a value fixes it.</p>
+        <div id="pins"></div>
+      </details>
+      <label>Seed <input type="number" name="seed" min="0" value="1" /></label>
+      <button type="submit">Render</button>
+      <button type="button" id="reroll">Re-roll seed</button>
+    </form>
+    <main>
+      <div id="meta"></div>
+      <div id="error"></div>
+      <div id="stage"><img id="shot" /></div>
+      <h4>Style</h4>
+      <pre id="style"></pre>
+      <h4>HTML</h4>
+      <pre id="html"></pre>
+    </main>
+    <script>
+      const form = document.getElementById('controls');
+      let fonts = {};
+      let profiles = {};
+
+      // One input per parameter of the selected profile; the placeholder shows what it is
+      // drawn from. Pins survive a profile switch.
+      function fillPins() {
+        const pins = document.getElementById('pins');
+        const kept = Object.fromEntries(
+          [...pins.querySelectorAll('input')].map((input) => [input.name, input.value]));
+        pins.replaceChildren();
+        Object.entries(profiles[form.profile.value] || {}).forEach(([name, spec]) => {
+          const label = document.createElement('label');
+          label.textContent = name;
+          const input = document.createElement('input');
+          input.name = 'pin.' + name;
+          input.placeholder = spec;
+          input.title = spec;
+          input.value = kept[input.name] || '';
+          label.htmlFor = input.id = input.name;
+          pins.append(label, input);
+        });
+      }
+
+      function fillFaces() {
+        const faces = form.face;
+        faces.length = 1;
+        (fonts[form.font.value] || []).forEach((name, i) => faces.add(new Option(name, i)));
+      }
+
+      function drawBoxes(boxes) {
+        document.querySelectorAll('.box').forEach((b) => b.remove());
+        if (!form.boxes.checked) return;
+        const stage = document.getElementById('stage');
+        boxes.forEach(([x, y, w, h]) => {
+          const box = document.createElement('div');
+          box.className = 'box';
+          Object.assign(box.style, { left: x + 'px', top: y + 'px', width: w + 'px', height: h + 'px' });
+          stage.appendChild(box);
+        });
+      }
+
+      async function render() {
+        const params = new URLSearchParams(
+          [...new FormData(form)].filter(([name, value]) => !name.startsWith('pin.') || value));
+        const response = await fetch('/render?' + params);
+        const result = await response.json();
+        document.getElementById('error').textContent = result.error || '';
+        if (result.error) return;
+        document.getElementById('shot').src = 'data:image/png;base64,' + result.image;
+        document.getElementById('meta').textContent = [
+          'seed ' + result.seed,
+          result.viewport.join('×'),
+          'layout ' + result.layout.outcome,
+          result.layout.font_size + 'px',
+          result.phrase,
+        ].join(' · ');
+        document.getElementById('style').textContent = JSON.stringify(result.style, null, 2) +
+          '\naugmentations: ' + JSON.stringify(result.augmentations);
+        document.getElementById('html').textContent = result.html;
+        drawBoxes(result.text_boxes);
+      }
+
+      form.addEventListener('submit', (event) => { event.preventDefault(); render(); });
+      form.font.addEventListener('change', fillFaces);
+      form.profile.addEventListener('change', fillPins);
+      form.boxes.addEventListener('change', render);
+      document.getElementById('reroll').addEventListener('click', () => {
+        form.seed.value = Math.floor(Math.random() * 4294967296);
+        render();
+      });
+
+      fetch('/profiles').then((r) => r.json()).then((list) => {
+        if (list.error) {
+          document.getElementById('error').textContent = list.error;
+          return;
+        }
+        profiles = list;
+        Object.keys(profiles).forEach((name) =>
+          form.profile.add(new Option(name, name, false, name === 'default')));
+        fillPins();
+      });
+      fetch('/fonts').then((r) => r.json()).then((list) => {
+        fonts = list;
+        Object.keys(fonts).forEach((font) => form.font.add(new Option(font, font)));
+        fillFaces();
+        render();
+      });
+    </script>
+  </body>
+</html>
+"#;
+
+struct Preview {
+    assets: Assets,
+    phrases: Vec<String>,
+    // Renders take turns on one tab.
+    tab: Mutex<Arc<Tab>>,
+    _browser: Browser,
+}
+
+/// One render as the form describes it. The form's controls map onto the command-line flags
+/// of a generation run, so a preview renders what a run with those flags would.
+#[derive(Debug, PartialEq)]
+struct PreviewRequest {
+    font: String,
+    face: Option<usize>,
+    phrase: Option<String>,
+    flags: Vec<String>,
+    /// Style parameters fixed over the selected profile, as `StyleProfile::pinned` takes them.
+    pins: Vec<(String, String)>,
+    seed: u64,
+}
+
+fn parse_preview_request(query: &str) -> Result<PreviewRequest, String> {
+    let mut request = PreviewRequest {
+        font: String::new(),
+        face: None,
+        phrase: None,
+        flags: Vec::new(),
+        pins: Vec::new(),
+        seed: thread_rng().gen(),
+    };
+    let mut method = "random".to_string();
+    let mut scan = "medium".to_string();
+    let (mut extra, mut spans, mut augment) = (0, false, false);
+    let number = |name: &str, value: &str| {
+        value
+            .parse::<usize>()
+            .map_err(|_| format!("{} must be a number", name))
+    };
+    for (name, value) in query_pairs(query)? {
+        match name {
+            "font" => request.font = value,
+            "face" if value.is_empty() => request.face = None,
+            "face" => request.face = Some(number(name, &value)?),
+            "phrase" => request.phrase = Some(value).filter(|phrase| !phrase.trim().is_empty()),
+            "method" => method = value,
+            "scan" => scan = value,
+            "profile" if value.is_empty() => {}
+            "profile" => request.flags.push(format!("--style-profile={}", value)),
+            "paragraph" => match number(name, &value)? {
+                0 => {}
+                lines => request
+                    .flags
+                    .push(format!("--paragraph={}-{}", lines, lines)),
+            },
+            "extra" => extra = number(name, &value)?,
+            "spans" => spans = true,
+            "synthetic" => request.flags.push("--synthetic=1".to_string()),
+            "camera" => request.flags.push("--camera".to_string()),
+            "augment" => augment = true,
+            "seed" => {
+                request.seed = value
+                    .parse()
+                    .map_err(|_| "seed must be a number".to_string())?
+            }
+            // Display-only controls.
+            "boxes" => {}
+            _ if name.starts_with("pin.") => {
+                if !value.trim().is_empty() {
+                    let parameter = &name["pin.".len()..];
+                    request
+                        .pins
+                        .push((parameter.to_string(), value.trim().to_string()));
+                }
+            }
+            _ => return Err(format!("Unknown parameter {}", name)),
+        }
+    }
+    if request.font.is_empty() {
+        return Err("Pick a font".to_string());
+    }
+    match method.as_str() {
+        "random" => {}
+        "document" => request.flags.push(format!("--scan={}", scan)),
+        "scene" => request.flags.push("--scene".to_string()),
+        _ => return Err(format!("Unknown method {}", method)),
+    }
+    if extra > 0 {
+        let flag = if spans {
+            "--font-spans"
+        } else {
+            "--multi-font"
+        };
+        request.flags.push(format!("{}={}", flag, extra + 1));
+    }
+    if !augment {
+        request.flags.push("--no-augment".to_string());
+    }
+    Ok(request)
+}
+
+/// Shortens the base64 fonts and backgrounds inlined in a page so its HTML stays readable.
+fn abbreviate_data_urls(html: &str) -> String {
+    let mut out = String::with_capacity(html.len().min(64 * 1024));
+    let mut rest = html;
+    while let Some(start) = rest.find("base64,") {
+        let (head, tail) = rest.split_at(start + "base64,".len());
+        out.push_str(head);
+        let payload = tail
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '='))
+            .unwrap_or(tail.len());
+        if payload > MAX_INLINE_BASE64 {
+            out.push_str(&tail[..MAX_INLINE_BASE64 / 2]);
+            out.push_str(&format!("…[{} characters]", payload));
+        } else {
+            out.push_str(&tail[..payload]);
+        }
+        rest = &tail[payload..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Builds and renders one page the way a run does, minus the output encoding.
+async fn render(preview: &Preview, request: &PreviewRequest) -> Result<Value, String> {
+    let mut options = RunOptions::from_args(&request.flags)?;
+    if !request.pins.is_empty() {
+        let pinned = options.style_profile(&request.font).pinned(&request.pins)?;
+        options.style_profile = Arc::new(pinned);
+        options.font_profiles.clear();
+    }
+    let phrase = match &request.phrase {
+        Some(phrase) => phrase.clone(),
+        None => preview
+            .phrases
+            .choose(&mut task_rng())
+            .cloned()
+            .ok_or("No phrases loaded")?,
+    };
+    let sample = Sample {
+        font: &request.font,
+        face: request.face,
+        phrase: &phrase,
+        pool: &preview.phrases,
+        index: 0,
+    };
+    let page = build_page(&sample, &preview.assets, &options)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (width, height) = page.html_sample.viewport.unwrap_or_else(|| {
+        (
+            task_rng().gen_range(400..1000),
+            task_rng().gen_range(400..1000),
+        )
+    });
+
+    let (layout, screenshot) = {
+        let tab = preview.tab.lock().await;
+        load_page(&tab, &page.html_sample.html, width, height).map_err(|e| e.to_string())?;
+        let layout = check_layout(&tab, options.fit_policy)?;
+        let screenshot = tab
+            .capture_screenshot(CaptureScreenshotFormatOption::Png, None, None, true)
+            .map_err(|e| format!("Failed to capture screenshot: {}", e))?;
+        (layout, screenshot)
+    };
+
+    let quads = layout
+        .blocks
+        .iter()
+        .map(|block| block.after.text_box.corners())
+        .collect();
+    let chain = Arc::clone(&options.augmentations);
+    let mut rng = StdRng::seed_from_u64(task_rng().gen());
+    let (image, applied) = tokio::task::spawn_blocking(move || {
+        let (canvas, applied) = augment_screenshot(&screenshot, quads, &chain, &mut rng)?;
+        let png = OutputEncoding {
+            format: ImageFormat::Png,
+            ..OutputEncoding::default()
+        };
+        Ok::<_, String>((png.encode(canvas.image, canvas.quads, &mut rng)?, applied))
+    })
+    .await
+    .map_err(|e| format!("Augmentation panicked: {}", e))??;
+
+    Ok(json!({
+        "seed": request.seed,
+        "flags": request.flags,
+        "phrase": page.phrase,
+        "label": page.labels,
+        "viewport": [width, height],
+        "layout": {
+            "outcome": layout.outcome.to_string(),
+            "font_size": layout.blocks.first().map(|block| block.after.font_size),
+        },
+        "style": page.html_sample.style,
+        "augmentations": applied,
+        "text_boxes": image.quads.iter().map(quad_bounds).collect::<Vec<_>>(),
+        "html": abbreviate_data_urls(&page.html_sample.html),
+        "image": STANDARD.encode(&image.bytes),
+    }))
+}
+
+/// Face names of every font class, in the order `face` indexes them.
+async fn font_faces(preview: &Preview) -> Value {
+    let mut fonts = Map::new();
+    for class in preview.assets.classes.classes() {
+        let Ok(files) = get_font_vector(&format!("{}/{}", FONTS_DIR, class)).await else {
+            continue;
+        };
+        let faces: Vec<String> = files
+            .iter()
+            .map(|file| {
+                format!(
+                    "{} {} ({})",
+                    file.names.family, file.names.subfamily, file.file
+                )
+            })
+            .collect();
+        fonts.insert(class, json!(faces));
+    }
+    Value::Object(fonts)
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    preview: Arc<Preview>,
+) -> Result<(), String> {
+    let head = read_head(&mut stream).await?;
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/") => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            PREVIEW_HTML.to_string(),
+        ),
+        ("GET", "/fonts") => (
+            "200 OK",
+            "application/json",
+            font_faces(&preview).await.to_string(),
+        ),
+        ("GET", "/profiles") => (
+            "200 OK",
+            "application/json",
+            match StyleProfiles::load(STYLES_PATH) {
+                Ok(profiles) => {
+                    let described: Map<String, Value> = profiles
+                        .names()
+                        .into_iter()
+                        .filter_map(|name| Some((name.to_string(), profiles.get(name)?.describe())))
+                        .collect();
+                    Value::Object(described).to_string()
+                }
+                Err(e) => json!({ "error": e }).to_string(),
+            },
+        ),
+        ("GET", "/render") => {
+            let result = match parse_preview_request(query) {
+                Ok(request) => with_seed(request.seed, render(&preview, &request)).await,
+                Err(e) => Err(e),
+            };
+            let body = result.unwrap_or_else(|e| json!({ "error": e }));
+            ("200 OK", "application/json", body.to_string())
+        }
+        _ => ("404 Not Found", "text/plain", "Not found".to_string()),
+    };
+    respond(&mut stream, status, content_type, body.as_bytes()).await
+}
+
+pub async fn run_preview(args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listen = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--listen="))
+        .unwrap_or(PREVIEW_LISTEN);
+
+    let (fonts, html_template, phrases, images) = tokio::join!(
+        get_available_fonts(FONTS_DIR),
+        tokio::fs::read_to_string(TEMPLATE_PATH),
+        load_phrases(PHRASES_PATH),
+        get_image_buffers()
+    );
+    let classes = load_class_index(&fonts?).await?;
+    let browser = BrowserManager::new()
+        .create_browser()
+        .map_err(|e| format!("Failed to launch browser: {:?}", e))?;
+    let tab = browser.new_tab().map_err(|e| e.to_string())?;
+    let preview = Arc::new(Preview {
+        assets: Assets {
+            html_template: html_template?,
+            images: images?,
+            classes,
+        },
+        phrases: phrases?,
+        tab: Mutex::new(tab),
+        _browser: browser,
+    });
+
+    let listener = TcpListener::bind(listen).await?;
+    println!("{} http://{}", "Style preview on".green(), listen);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let preview = Arc::clone(&preview);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, preview).await {
+                eprintln!("Preview request failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn form_controls_become_run_flags() {
+        let request = parse_preview_request(
+            "font=Vazir&face=2&phrase=&method=document&scan=heavy&profile=wild&paragraph=4&extra=1&spans=on&augment=on&pin.rotate=3.5&pin.blur=&pin.shadow=off&seed=9",
+        )
+        .unwrap();
+        assert_eq!(
+            request.pins,
+            vec![
+                ("rotate".to_string(), "3.5".to_string()),
+                ("shadow".to_string(), "off".to_string())
+            ]
+        );
+        assert_eq!(request.font, "Vazir");
+        assert_eq!(request.face, Some(2));
+        assert_eq!(request.phrase, None);
+        assert_eq!(request.seed, 9);
+        assert_eq!(
+            request.flags,
+            vec![
+                "--style-profile=wild",
+                "--paragraph=4-4",
+                "--scan=heavy",
+                "--font-spans=2"
+            ]
+        );
+        let plain = parse_preview_request("font=Vazir&face=&seed=1").unwrap();
+        assert_eq!(plain.flags, vec!["--no-augment"]);
+
+        // Without augmentations, the camera and the document scan still run.
+        let chain = |query: &str| {
+            let request = parse_preview_request(query).unwrap();
+            RunOptions::from_args(&request.flags)
+                .unwrap()
+                .augmentations
+                .names()
+        };
+        let camera = chain("font=Vazir&camera=on&seed=1");
+        assert!(camera.contains(&"camera_viewpoint"));
+        assert!(!camera.contains(&"perspective_warp"));
+        assert!(chain("font=Vazir&method=document&scan=heavy&seed=1").contains(&"skew"));
+        assert!(chain("font=Vazir&seed=1").is_empty());
+        assert!(parse_preview_request("font=Vazir&method=fancy").is_err());
+
+        let html = format!("src: url(data:font/ttf;base64,{}) format", "A".repeat(500));
+        let short = abbreviate_data_urls(&html);
+        assert!(short.ends_with("…[500 characters]) format"));
+        assert!(short.len() < 100);
+    }
+}
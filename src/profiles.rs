@@ -0,0 +1,769 @@
+use once_cell::sync::Lazy;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_json::{json, Map, Value};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Arc;
+
+use crate::seeding::task_rng;
+
+// Style distributions live in TOML instead of in the styling code. A profile is a table of
+// named parameters; the styling code asks a `StyleSampler` for each one by name. Profiles
+// extend `default` unless they name another profile in `extends`, so they only list what
+// they change. Profiles in `styles.toml` are merged over the built-in ones of the same name.
+//
+//     [wild]
+//     rotate = { p = 0.7, normal = { mean = 0.0, std = 6.0 }, clamp = [-20.0, 20.0] }
+//     text_align = { categorical = { center = 2, left = 1, right = 3 } }
+//     image_overlay = { p = 0.5, requires = ["image_background"] }
+//
+// `p` is the chance the parameter is enabled for a page (default 1); a disabled parameter
+// takes its `off` value (default 0). Values come from one of `uniform = [min, max]`
+// (integers when both bounds are), `normal = { mean, std }`, `categorical = { value =
+// weight }`, `set = [values]` or `value = constant`; `clamp` and `decimals` shape numbers.
+// `requires` and `unless` name parameters that must, or must not, be enabled on the same
+// page.
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+const BUILTIN_PROFILES: &str = r#"
+[default]
+# Plain black-on-white pages among the randomly styled ones.
+plain = { p = 0.142857 }
+plain_font_size = { uniform = [24, 59] }
+# Randomly styled pages given the printed-document look instead.
+document_look = { p = 0.0 }
+image_background = { p = 0.5 }
+image_overlay = { p = 0.3, requires = ["image_background"] }
+overlay_opacity = { uniform = [0.05, 0.35] }
+gradient = { p = 0.3, unless = ["image_background"] }
+skew_x = { p = 0.5, uniform = [-6.0, 6.0], decimals = 2 }
+skew_y = { p = 0.5, uniform = [-6.0, 6.0], decimals = 2 }
+rotate = { p = 0.5, uniform = [-6.0, 6.0], decimals = 2 }
+translate_x = { p = 0.4, uniform = [-3.0, 3.0], decimals = 2 }
+translate_y = { p = 0.4, uniform = [-3.0, 3.0], decimals = 2 }
+# The filter values have always been floored at 1: every page gets a 1px blur, and
+# brightness and contrast only ever go up.
+blur = { value = 1.0 }
+brightness = { p = 0.4, uniform = [0.8, 1.2], decimals = 1, clamp = [1.0, 1.2], off = 1.0 }
+contrast = { p = 0.4, uniform = [0.8, 1.2], decimals = 1, clamp = [1.0, 1.2], off = 1.0 }
+width = { uniform = [250, 600] }
+height = { uniform = [200, 450] }
+font_size = { uniform = [36, 100] }
+text_align = { set = ["center", "left", "right"] }
+padding = { uniform = [5, 50] }
+margin = { uniform = [5, 50] }
+shadow = { p = 0.4 }
+shadow_x = { uniform = [-5.0, 6.0], decimals = 2 }
+shadow_y = { uniform = [-5.0, 6.0], decimals = 2 }
+shadow_blur = { uniform = [1.0, 8.0], decimals = 2 }
+outline = { p = 0.2 }
+outline_width = { uniform = [1.0, 3.0], decimals = 2 }
+noise = { p = 0.4 }
+noise_opacity = { uniform = [0.1, 0.3], decimals = 2 }
+# Styles applied to the text container rather than the page body.
+container_styling = { p = 0.5 }
+paper = { uniform = [232, 255] }
+paper_tint = { uniform = [0, 12] }
+ink = { uniform = [0, 60] }
+document_text_align = { set = ["right", "justify", "center"] }
+document_font_size = { uniform = [22, 64] }
+document_padding = { uniform = [10, 40] }
+# Paragraph mode, with --paragraph.
+paragraph_font_size = { uniform = [16, 30] }
+paragraph_line_height = { uniform = [1.3, 2.2] }
+paragraph_word_spacing = { uniform = [0.0, 8.0] }
+# Letter spacing pulls joined Persian letters apart, so it stays slight.
+paragraph_letter_spacing = { uniform = [0.0, 1.0] }
+paragraph_text_align = { categorical = { justify = 3, right = 1, center = 1 } }
+paragraph_width = { uniform = [700, 1100] }
+# Tatweel stretching of justified paragraphs, and the share of joining letters it follows.
+kashida = { p = 0.5 }
+kashida_density = { value = 0.3 }
+# Synthetic variants, with --synthetic; redrawn until at least one applies.
+synthetic_bold = { p = 0.5 }
+synthetic_oblique = { p = 0.5, uniform = [6, 14] }
+synthetic_scale_x = { p = 0.5, uniform = [0.75, 1.25], decimals = 2 }
+# Chance of each run of words being recast in another font, with --font-spans.
+span_font = { p = 0.35 }
+
+[clean]
+plain = { p = 0.3 }
+image_overlay = { p = 0.0 }
+skew_x = { p = 0.0 }
+skew_y = { p = 0.0 }
+rotate = { p = 0.0 }
+translate_x = { p = 0.0 }
+translate_y = { p = 0.0 }
+blur = { p = 0.0 }
+brightness = { p = 0.0, off = 1.0 }
+contrast = { p = 0.0, off = 1.0 }
+shadow = { p = 0.0 }
+outline = { p = 0.0 }
+noise = { p = 0.0 }
+
+[document]
+extends = "clean"
+document_look = { p = 1.0 }
+
+[wild]
+plain = { p = 0.05 }
+image_background = { p = 0.6 }
+image_overlay = { p = 0.5, requires = ["image_background"] }
+gradient = { p = 0.5, unless = ["image_background"] }
+skew_x = { p = 0.7, normal = { mean = 0.0, std = 5.0 }, clamp = [-15.0, 15.0], decimals = 2 }
+skew_y = { p = 0.7, normal = { mean = 0.0, std = 5.0 }, clamp = [-15.0, 15.0], decimals = 2 }
+rotate = { p = 0.7, normal = { mean = 0.0, std = 6.0 }, clamp = [-20.0, 20.0], decimals = 2 }
+translate_x = { p = 0.6, uniform = [-8.0, 8.0], decimals = 2 }
+translate_y = { p = 0.6, uniform = [-8.0, 8.0], decimals = 2 }
+blur = { p = 0.5, uniform = [0.0, 1.2], decimals = 2 }
+brightness = { p = 0.6, uniform = [0.6, 1.4], decimals = 2, off = 1.0 }
+contrast = { p = 0.6, uniform = [0.6, 1.4], decimals = 2, off = 1.0 }
+font_size = { uniform = [24, 140] }
+text_align = { categorical = { center = 2, left = 1, right = 3 } }
+shadow = { p = 0.6 }
+outline = { p = 0.35 }
+noise = { p = 0.6 }
+noise_opacity = { uniform = [0.1, 0.5], decimals = 2 }
+"#;
+
+static BUILTIN: Lazy<StyleProfiles> = Lazy::new(|| {
+    StyleProfiles::parse(BUILTIN_PROFILES, "").expect("built-in style profiles are valid")
+});
+
+#[derive(Debug, Clone, PartialEq)]
+enum Distribution {
+    Uniform {
+        min: f64,
+        max: f64,
+        integer: bool,
+    },
+    Normal {
+        mean: f64,
+        std: f64,
+    },
+    /// Weighted choice among the table's keys.
+    Categorical(Vec<(String, f64)>),
+    /// Equal-chance choice among the listed values.
+    Set(Vec<Value>),
+    Constant(Value),
+}
+
+impl Distribution {
+    fn draw(&self) -> Value {
+        let mut rng = task_rng();
+        match self {
+            Distribution::Uniform { min, max, integer } if *integer => {
+                json!(rng.gen_range(*min as i64..=*max as i64))
+            }
+            Distribution::Uniform { min, max, .. } => json!(rng.gen_range(*min..=*max)),
+            Distribution::Normal { mean, std } => {
+                // Box-Muller; `gen` is in [0, 1), so 1 - u stays clear of ln(0).
+                let (u, v): (f64, f64) = (rng.gen(), rng.gen());
+                let z = (-2.0 * (1.0 - u).ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos();
+                json!(mean + std * z)
+            }
+            Distribution::Categorical(weights) => {
+                let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+                let mut pick = rng.gen_range(0.0..total);
+                for (value, weight) in weights {
+                    if pick < *weight {
+                        return json!(value);
+                    }
+                    pick -= weight;
+                }
+                json!(weights[weights.len() - 1].0)
+            }
+            Distribution::Set(values) => values.choose(&mut rng).cloned().unwrap_or_default(),
+            Distribution::Constant(value) => value.clone(),
+        }
+    }
+
+    /// A short human-readable form, such as `uniform [-6, 6]`.
+    fn describe(&self) -> String {
+        match self {
+            Distribution::Uniform { min, max, .. } => format!("uniform [{}, {}]", min, max),
+            Distribution::Normal { mean, std } => format!("normal {} ± {}", mean, std),
+            Distribution::Categorical(weights) => {
+                let values: Vec<&str> = weights.iter().map(|(value, _)| value.as_str()).collect();
+                format!("one of {}", values.join(", "))
+            }
+            Distribution::Set(values) => {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|value| value.as_str().map_or(value.to_string(), str::to_string))
+                    .collect();
+                format!("one of {}", values.join(", "))
+            }
+            Distribution::Constant(value) => format!("= {}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Parameter {
+    enable: f64,
+    distribution: Option<Distribution>,
+    off: Value,
+    clamp: Option<(f64, f64)>,
+    decimals: Option<i32>,
+    requires: Vec<String>,
+    unless: Vec<String>,
+}
+
+fn number_pair(value: &Value, what: &str) -> Result<(f64, f64), String> {
+    match value.as_array().map(Vec::as_slice) {
+        Some([a, b]) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) if a <= b => Ok((a, b)),
+            _ => Err(format!("{} must be [min, max] with min <= max", what)),
+        },
+        _ => Err(format!("{} must be [min, max]", what)),
+    }
+}
+
+fn names(value: &Value, what: &str) -> Result<Vec<String>, String> {
+    value
+        .as_array()
+        .ok_or_else(|| format!("{} must be a list of parameter names", what))?
+        .iter()
+        .map(|name| {
+            name.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("{} must be a list of parameter names", what))
+        })
+        .collect()
+}
+
+impl Parameter {
+    fn parse(spec: &Value) -> Result<Parameter, String> {
+        let table = spec
+            .as_object()
+            .ok_or("must be a table such as { p = 0.5, uniform = [0, 1] }")?;
+        let mut parameter = Parameter {
+            enable: 1.0,
+            distribution: None,
+            off: json!(0),
+            clamp: None,
+            decimals: None,
+            requires: Vec::new(),
+            unless: Vec::new(),
+        };
+        for (key, value) in table {
+            let distribution = match key.as_str() {
+                "p" => {
+                    parameter.enable = value
+                        .as_f64()
+                        .filter(|p| (0.0..=1.0).contains(p))
+                        .ok_or("p must be between 0 and 1")?;
+                    None
+                }
+                "off" => {
+                    parameter.off = value.clone();
+                    None
+                }
+                "clamp" => {
+                    parameter.clamp = Some(number_pair(value, "clamp")?);
+                    None
+                }
+                "decimals" => {
+                    parameter.decimals =
+                        Some(value.as_u64().ok_or("decimals must be a count")? as i32);
+                    None
+                }
+                "requires" => {
+                    parameter.requires = names(value, "requires")?;
+                    None
+                }
+                "unless" => {
+                    parameter.unless = names(value, "unless")?;
+                    None
+                }
+                "uniform" => {
+                    let (min, max) = number_pair(value, "uniform")?;
+                    let integer = value.as_array().into_iter().flatten().all(Value::is_i64);
+                    Some(Distribution::Uniform { min, max, integer })
+                }
+                "normal" => {
+                    let mean = value["mean"].as_f64().ok_or("normal needs a mean")?;
+                    let std = value["std"]
+                        .as_f64()
+                        .filter(|std| *std >= 0.0)
+                        .ok_or("normal needs a non-negative std")?;
+                    Some(Distribution::Normal { mean, std })
+                }
+                "categorical" => {
+                    let weights = value
+                        .as_object()
+                        .ok_or("categorical must be a table of value = weight")?
+                        .iter()
+                        .map(|(name, weight)| match weight.as_f64() {
+                            Some(weight) if weight >= 0.0 => Ok((name.clone(), weight)),
+                            _ => Err(format!("Weight of {} must be a non-negative number", name)),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if weights.iter().map(|(_, weight)| weight).sum::<f64>() <= 0.0 {
+                        return Err("categorical needs a positive weight".to_string());
+                    }
+                    Some(Distribution::Categorical(weights))
+                }
+                "set" => match value.as_array() {
+                    Some(values) if !values.is_empty() => Some(Distribution::Set(values.clone())),
+                    _ => return Err("set must be a non-empty list".to_string()),
+                },
+                "value" => Some(Distribution::Constant(value.clone())),
+                _ => return Err(format!("Unknown key {}", key)),
+            };
+            if distribution.is_some() {
+                if parameter.distribution.is_some() {
+                    return Err("Only one of uniform, normal, categorical, set, value".to_string());
+                }
+                parameter.distribution = distribution;
+            }
+        }
+        Ok(parameter)
+    }
+
+    fn draw(&self) -> Value {
+        let Some(distribution) = &self.distribution else {
+            return self.off.clone();
+        };
+        let value = distribution.draw();
+        let Some(mut number) = value.as_f64().filter(|_| !value.is_i64()) else {
+            return value;
+        };
+        if let Some((min, max)) = self.clamp {
+            number = number.clamp(min, max);
+        }
+        if let Some(decimals) = self.decimals {
+            let scale = 10f64.powi(decimals);
+            number = (number * scale).round() / scale;
+        }
+        json!(number)
+    }
+}
+
+/// A named set of parameter distributions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StyleProfile {
+    name: String,
+    parameters: BTreeMap<String, Parameter>,
+}
+
+impl StyleProfile {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Every parameter with its distribution in short form, e.g. `p = 0.5, uniform [-6, 6]`;
+    /// plain switches read `switch`.
+    pub fn describe(&self) -> Value {
+        let parameters: Map<String, Value> = self
+            .parameters
+            .iter()
+            .map(|(name, parameter)| {
+                let distribution = parameter
+                    .distribution
+                    .as_ref()
+                    .map_or("switch".to_string(), Distribution::describe);
+                let description = if parameter.enable < 1.0 {
+                    format!("p = {}, {}", parameter.enable, distribution)
+                } else {
+                    distribution
+                };
+                (name.clone(), json!(description))
+            })
+            .collect();
+        Value::Object(parameters)
+    }
+
+    /// A copy with some parameters pinned while the rest are still drawn. A pin of `off`
+    /// disables the parameter, `on` enables it, and anything else enables it at that value:
+    /// a number when it parses as one, text otherwise. `requires` and `unless` still apply.
+    pub fn pinned(&self, pins: &[(String, String)]) -> Result<StyleProfile, String> {
+        let mut profile = self.clone();
+        for (name, pin) in pins {
+            let parameter = profile
+                .parameters
+                .get_mut(name)
+                .ok_or_else(|| format!("Unknown style parameter {}", name))?;
+            match pin.as_str() {
+                "off" => parameter.enable = 0.0,
+                "on" => parameter.enable = 1.0,
+                value => {
+                    let value = match (value.parse::<i64>(), value.parse::<f64>()) {
+                        (Ok(integer), _) => json!(integer),
+                        (_, Ok(number)) if number.is_finite() => json!(number),
+                        _ => json!(value),
+                    };
+                    parameter.enable = 1.0;
+                    parameter.distribution = Some(Distribution::Constant(value));
+                    parameter.clamp = None;
+                    parameter.decimals = None;
+                }
+            }
+        }
+        if !pins.is_empty() {
+            profile.name = format!("{}+pinned", profile.name);
+        }
+        Ok(profile)
+    }
+}
+
+/// The built-in profiles plus those of a `styles.toml`.
+#[derive(Debug, Clone)]
+pub struct StyleProfiles {
+    profiles: BTreeMap<String, Arc<StyleProfile>>,
+}
+
+impl StyleProfiles {
+    pub fn builtin() -> &'static StyleProfiles {
+        &BUILTIN
+    }
+
+    /// The built-in profiles with the ones in `path` merged over them; a missing file leaves
+    /// the built-ins.
+    pub fn load(path: &str) -> Result<StyleProfiles, String> {
+        match fs::read_to_string(path) {
+            Ok(raw) => StyleProfiles::parse(BUILTIN_PROFILES, &raw)
+                .map_err(|e| format!("Invalid {}: {}", path, e)),
+            Err(_) => Ok(StyleProfiles::builtin().clone()),
+        }
+    }
+
+    fn parse(builtin: &str, overrides: &str) -> Result<StyleProfiles, String> {
+        let toml_tables = |raw: &str| -> Result<Map<String, Value>, String> {
+            let table: toml::Table = raw.parse().map_err(|e| format!("{}", e))?;
+            match serde_json::to_value(table).map_err(|e| e.to_string())? {
+                Value::Object(tables) => Ok(tables),
+                _ => Err("Expected a table of profiles".to_string()),
+            }
+        };
+        let mut tables = toml_tables(builtin)?;
+        for (name, table) in toml_tables(overrides)? {
+            let merged = tables.entry(name.clone()).or_insert_with(|| json!({}));
+            match (merged.as_object_mut(), table.as_object()) {
+                (Some(merged), Some(table)) => merged.extend(table.clone()),
+                _ => return Err(format!("Profile {} must be a table", name)),
+            }
+        }
+
+        let mut profiles = BTreeMap::new();
+        for name in tables.keys() {
+            let mut parameters = BTreeMap::new();
+            // Walk up the `extends` chain, letting nearer profiles win.
+            let mut chain = vec![name.as_str()];
+            while let Some(&current) = chain.last() {
+                let parent = match tables[current]["extends"].as_str() {
+                    Some(parent) => parent,
+                    None if current == DEFAULT_PROFILE => break,
+                    None => DEFAULT_PROFILE,
+                };
+                if chain.contains(&parent) {
+                    return Err(format!("Profile {} extends itself", name));
+                }
+                if !tables.contains_key(parent) {
+                    return Err(format!("Profile {} extends unknown {}", current, parent));
+                }
+                chain.push(parent);
+            }
+            for profile in chain.iter().rev() {
+                for (parameter, spec) in tables[*profile].as_object().into_iter().flatten() {
+                    if parameter == "extends" {
+                        continue;
+                    }
+                    let parsed = Parameter::parse(spec)
+                        .map_err(|e| format!("{}.{}: {}", profile, parameter, e))?;
+                    parameters.insert(parameter.clone(), parsed);
+                }
+            }
+            profiles.insert(name.clone(), parameters);
+        }
+
+        // Every profile must stick to the parameters the styling code asks for.
+        let known: Vec<&String> = profiles
+            .get(DEFAULT_PROFILE)
+            .ok_or("No default profile")?
+            .keys()
+            .collect();
+        for (name, parameters) in &profiles {
+            for (parameter, spec) in parameters {
+                let references = spec.requires.iter().chain(&spec.unless);
+                if let Some(unknown) = std::iter::once(parameter)
+                    .chain(references)
+                    .find(|p| !known.contains(p))
+                {
+                    return Err(format!("{}: unknown style parameter {}", name, unknown));
+                }
+            }
+        }
+
+        Ok(StyleProfiles {
+            profiles: profiles
+                .into_iter()
+                .map(|(name, parameters)| {
+                    let profile = StyleProfile {
+                        name: name.clone(),
+                        parameters,
+                    };
+                    (name, Arc::new(profile))
+                })
+                .collect(),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<StyleProfile>> {
+        self.profiles.get(name).cloned()
+    }
+
+    pub fn default_profile(&self) -> Arc<StyleProfile> {
+        self.profiles[DEFAULT_PROFILE].clone()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.profiles.keys().map(String::as_str).collect()
+    }
+}
+
+/// Draws one page's parameters from a profile. Every parameter is decided once per page,
+/// so dependencies see the same answer the styling code does, until `redraw` asks for a new
+/// decision. Every value drawn is recorded for the sample metadata.
+pub struct StyleSampler<'a> {
+    profile: &'a StyleProfile,
+    enabled: HashMap<&'a str, bool>,
+    values: HashMap<&'a str, Value>,
+    drawn: Map<String, Value>,
+    // Earlier draws of redrawn parameters, oldest first.
+    redrawn: BTreeMap<String, Vec<Value>>,
+}
+
+impl<'a> StyleSampler<'a> {
+    pub fn new(profile: &'a StyleProfile) -> StyleSampler<'a> {
+        StyleSampler {
+            profile,
+            enabled: HashMap::new(),
+            values: HashMap::new(),
+            drawn: Map::new(),
+            redrawn: BTreeMap::new(),
+        }
+    }
+
+    /// Whether `name` is on for this page. Parameters without a distribution are plain
+    /// switches and are recorded as `true` or `false`.
+    pub fn enabled(&mut self, name: &str) -> bool {
+        if let Some(&on) = self.enabled.get(name) {
+            return on;
+        }
+        let profile = self.profile;
+        let Some((key, parameter)) = profile.parameters.get_key_value(name) else {
+            return false;
+        };
+        // Settled as off while the dependencies are checked, so a cycle cannot recurse.
+        self.enabled.insert(key, false);
+        let on = parameter.requires.iter().all(|other| self.enabled(other))
+            && !parameter.unless.iter().any(|other| self.enabled(other))
+            && task_rng().gen_bool(parameter.enable);
+        self.enabled.insert(key, on);
+        if parameter.distribution.is_none() {
+            self.drawn.insert(key.clone(), json!(on));
+        }
+        on
+    }
+
+    /// A draw from the parameter's distribution when it is enabled, its `off` value when not.
+    pub fn value(&mut self, name: &str) -> Value {
+        if let Some(value) = self.values.get(name) {
+            return value.clone();
+        }
+        let on = self.enabled(name);
+        let profile = self.profile;
+        let Some((key, parameter)) = profile.parameters.get_key_value(name) else {
+            return Value::Null;
+        };
+        let value = if on {
+            parameter.draw()
+        } else {
+            parameter.off.clone()
+        };
+        self.values.insert(key, value.clone());
+        self.drawn.insert(key.clone(), value.clone());
+        value
+    }
+
+    /// Forgets the decision on `name`, so the next call draws it again; for parameters
+    /// decided more than once per page, such as one per run of words. The parameter is then
+    /// recorded as the list of its draws, in order.
+    pub fn redraw(&mut self, name: &str) {
+        self.enabled.remove(name);
+        self.values.remove(name);
+        if let Some(previous) = self.drawn.remove(name) {
+            self.redrawn
+                .entry(name.to_string())
+                .or_default()
+                .push(previous);
+        }
+    }
+
+    pub fn number(&mut self, name: &str) -> f64 {
+        self.value(name).as_f64().unwrap_or(0.0)
+    }
+
+    pub fn integer(&mut self, name: &str) -> i64 {
+        let value = self.value(name);
+        value
+            .as_i64()
+            .unwrap_or_else(|| value.as_f64().unwrap_or(0.0).round() as i64)
+    }
+
+    pub fn text(&mut self, name: &str) -> String {
+        match self.value(name) {
+            Value::String(text) => text,
+            other => other.to_string(),
+        }
+    }
+
+    /// Records a value the page drew outside the profile, such as a random colour, with
+    /// the parameters.
+    pub fn note(&mut self, name: &str, value: Value) {
+        self.drawn.insert(name.to_string(), value);
+    }
+
+    /// Everything drawn so far, by parameter name.
+    pub fn record(mut self) -> Value {
+        for (name, mut draws) in self.redrawn {
+            draws.extend(self.drawn.remove(&name));
+            self.drawn.insert(name, Value::Array(draws));
+        }
+        Value::Object(self.drawn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seeding::with_seed;
+
+    #[tokio::test]
+    async fn profiles_merge_extend_and_respect_dependencies() {
+        let profiles = StyleProfiles::parse(
+            BUILTIN_PROFILES,
+            r#"
+            [wild]
+            rotate = { value = 2.71828, decimals = 1 }
+            [tilted]
+            extends = "wild"
+            skew_x = { normal = { mean = 50.0, std = 1.0 }, clamp = [-10.0, 10.0] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            profiles.names(),
+            vec!["clean", "default", "document", "tilted", "wild"]
+        );
+
+        let tilted = profiles.get("tilted").unwrap();
+        with_seed(5, async {
+            for _ in 0..50 {
+                let mut sampler = StyleSampler::new(&tilted);
+                assert_eq!(sampler.number("rotate"), 2.7);
+                assert_eq!(sampler.number("skew_x"), 10.0);
+                // Inherited from wild, which only overlays image backgrounds.
+                let overlay = sampler.enabled("image_overlay");
+                assert!(!overlay || sampler.enabled("image_background"));
+                let align = sampler.text("text_align");
+                assert!(["center", "left", "right"].contains(&align.as_str()));
+                let record = sampler.record();
+                assert_eq!(record["image_overlay"], overlay);
+            }
+        })
+        .await;
+
+        let document = profiles.get("document").unwrap();
+        let mut sampler = StyleSampler::new(&document);
+        assert!(sampler.enabled("document_look"));
+        assert_eq!(sampler.number("brightness"), 1.0);
+        assert!((232..=255).contains(&sampler.integer("paper")));
+
+        // The default keeps the filter floor that pages have always had.
+        let default = profiles.default_profile();
+        for _ in 0..50 {
+            let mut sampler = StyleSampler::new(&default);
+            assert_eq!(sampler.number("blur"), 1.0);
+            assert!(sampler.number("brightness") >= 1.0);
+            assert!(sampler.number("contrast") >= 1.0);
+        }
+
+        let typo = StyleProfiles::parse(BUILTIN_PROFILES, "[mine]\nrotation = { p = 0.5 }");
+        assert!(typo
+            .unwrap_err()
+            .contains("unknown style parameter rotation"));
+        let cycle = StyleProfiles::parse(
+            BUILTIN_PROFILES,
+            "[a]\nextends = \"b\"\n[b]\nextends = \"a\"",
+        );
+        assert!(cycle.is_err());
+    }
+
+    #[tokio::test]
+    async fn values_hold_for_the_page_until_redrawn() {
+        let wild = StyleProfiles::builtin().get("wild").unwrap();
+        with_seed(3, async {
+            let mut sampler = StyleSampler::new(&wild);
+            let font_size = sampler.integer("font_size");
+            assert_eq!(sampler.integer("font_size"), font_size);
+
+            let mut sizes = vec![font_size];
+            for _ in 0..5 {
+                sampler.redraw("font_size");
+                sizes.push(sampler.integer("font_size"));
+            }
+            // Redrawing before the first draw records nothing extra.
+            sampler.redraw("shadow");
+            let shadow = sampler.enabled("shadow");
+            let record = sampler.record();
+            assert_eq!(record["font_size"], json!(sizes));
+            assert_eq!(record["shadow"], shadow);
+            sizes.dedup();
+            assert!(sizes.len() > 1);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn pinned_parameters_hold_while_the_rest_vary() {
+        let wild = StyleProfiles::builtin().get("wild").unwrap();
+        let pins = [
+            ("rotate".to_string(), "25".to_string()),
+            ("blur".to_string(), "0.5".to_string()),
+            ("text_align".to_string(), "left".to_string()),
+            ("shadow".to_string(), "off".to_string()),
+        ];
+        let pinned = wild.pinned(&pins).unwrap();
+        assert_eq!(pinned.name(), "wild+pinned");
+        assert_eq!(
+            pinned.describe()["rotate"],
+            "= 25",
+            "pins replace the distribution"
+        );
+        with_seed(8, async {
+            let mut skews = Vec::new();
+            for _ in 0..20 {
+                let mut sampler = StyleSampler::new(&pinned);
+                // Outside wild's clamp, which a pin is not subject to.
+                assert_eq!(sampler.integer("rotate"), 25);
+                assert_eq!(sampler.number("blur"), 0.5);
+                assert_eq!(sampler.text("text_align"), "left");
+                assert!(!sampler.enabled("shadow"));
+                skews.push(sampler.number("skew_x").to_string());
+            }
+            skews.dedup();
+            assert!(skews.len() > 1);
+        })
+        .await;
+
+        assert_eq!(wild.describe()["shadow"], "p = 0.6, switch");
+        let unknown = wild.pinned(&[("rotation".to_string(), "1".to_string())]);
+        assert!(unknown.is_err());
+    }
+}
@@ -0,0 +1,439 @@
+use colored::*;
+use heed::types::{Bytes, Str};
+use heed::{Database, Env, EnvOpenOptions};
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::sinks::{image_extension, image_size, ArchiveWriter, EncodedSample};
+use crate::{read_samples, sample_folders, OUTPUT_DIR};
+
+// Record formats of existing trainers: TFRecord files of `tf.train.Example` protos, and the
+// LMDB layout of the common OCR training scripts (`image-000000001`, `label-000000001`, ...,
+// `num-samples`).
+
+const RECORDS_PER_FILE: usize = 10_000;
+// LMDB reserves address space, not disk; the file only grows as far as it is filled.
+const LMDB_MAP_SIZE: usize = 1 << 40;
+const LMDB_COMMIT_EVERY: usize = 1000;
+// Exports go under `OUTPUT_DIR/export`, apart from the record sinks of a generation run.
+pub const EXPORT_DIR: &str = "export";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    TfRecord,
+    Lmdb,
+}
+
+impl RecordFormat {
+    pub fn by_name(name: &str) -> Option<RecordFormat> {
+        match name {
+            "tfrecord" => Some(RecordFormat::TfRecord),
+            "lmdb" => Some(RecordFormat::Lmdb),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordFormat::TfRecord => "tfrecord",
+            RecordFormat::Lmdb => "lmdb",
+        }
+    }
+}
+
+/// CRC-32C (Castagnoli), bit by bit; record framing is not where the time goes.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn masked_crc(data: &[u8]) -> u32 {
+    let crc = crc32c(data);
+    (crc.rotate_right(15)).wrapping_add(0xa282_ead8)
+}
+
+/// TFRecord framing: length, masked CRC of the length, data, masked CRC of the data.
+fn tfrecord_frame(data: &[u8]) -> Vec<u8> {
+    let length = (data.len() as u64).to_le_bytes();
+    let mut frame = Vec::with_capacity(data.len() + 16);
+    frame.extend_from_slice(&length);
+    frame.extend_from_slice(&masked_crc(&length).to_le_bytes());
+    frame.extend_from_slice(data);
+    frame.extend_from_slice(&masked_crc(data).to_le_bytes());
+    frame
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A length-delimited protobuf field.
+fn message_field(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+/// `tf.train.Feature` variants: `bytes_list` is field 1, `float_list` 2, `int64_list` 3.
+enum Feature {
+    Bytes(Vec<Vec<u8>>),
+    Floats(Vec<f32>),
+    Ints(Vec<i64>),
+}
+
+impl Feature {
+    fn text(value: &str) -> Feature {
+        Feature::Bytes(vec![value.as_bytes().to_vec()])
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut list = Vec::new();
+        let field = match self {
+            Feature::Bytes(values) => {
+                for value in values {
+                    message_field(&mut list, 1, value);
+                }
+                1
+            }
+            Feature::Floats(values) => {
+                let packed: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                message_field(&mut list, 1, &packed);
+                2
+            }
+            Feature::Ints(values) => {
+                let mut packed = Vec::new();
+                for &value in values {
+                    varint(&mut packed, value as u64);
+                }
+                message_field(&mut list, 1, &packed);
+                3
+            }
+        };
+        let mut feature = Vec::new();
+        message_field(&mut feature, field, &list);
+        feature
+    }
+}
+
+/// A serialized `tf.train.Example` with the usual `image/...` feature names.
+fn example(sample: &EncodedSample) -> Vec<u8> {
+    let metadata = &sample.record();
+    let int = |value: &Value| Feature::Ints(value.as_i64().into_iter().collect());
+    let boxes: Vec<f32> = metadata["text_boxes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|b| b.as_array().into_iter().flatten())
+        .filter_map(|v| v.as_f64().map(|v| v as f32))
+        .collect();
+
+    let mut features = BTreeMap::new();
+    features.insert("image/encoded", Feature::Bytes(vec![sample.image.clone()]));
+    features.insert(
+        "image/format",
+        Feature::text(metadata["encoding"]["format"].as_str().unwrap_or("jpeg")),
+    );
+    features.insert("image/key", Feature::text(&sample.key));
+    features.insert(
+        "image/text",
+        Feature::text(metadata["phrase"].as_str().unwrap_or("")),
+    );
+    features.insert("image/class/label", int(&metadata["label"]["id"]));
+    features.insert(
+        "image/class/text",
+        Feature::text(metadata["label"]["name"].as_str().unwrap_or("")),
+    );
+    features.insert("image/width", int(&image_size(metadata)[0]));
+    features.insert("image/height", int(&image_size(metadata)[1]));
+    // Flattened `[x, y, width, height]` per text block, in pixels.
+    features.insert("image/text_boxes", Feature::Floats(boxes));
+    features.insert("image/metadata", Feature::text(&metadata.to_string()));
+
+    let mut map = Vec::new();
+    for (name, feature) in features {
+        let mut entry = Vec::new();
+        message_field(&mut entry, 1, name.as_bytes());
+        message_field(&mut entry, 2, &feature.encode());
+        message_field(&mut map, 1, &entry);
+    }
+    let mut example = Vec::new();
+    message_field(&mut example, 1, &map);
+    example
+}
+
+/// `data-NNNNN.tfrecord` files of `RECORDS_PER_FILE` examples each.
+pub struct TfRecordSet {
+    dir: String,
+    out: Option<BufWriter<File>>,
+    files: usize,
+    samples: usize,
+}
+
+impl TfRecordSet {
+    fn push(&mut self, sample: &EncodedSample) -> Result<(), String> {
+        if self.out.is_none() {
+            let path = format!("{}/data-{:05}.tfrecord", self.dir, self.files);
+            let file =
+                File::create(&path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+            self.out = Some(BufWriter::new(file));
+            self.files += 1;
+        }
+        let out = self.out.as_mut().unwrap();
+        out.write_all(&tfrecord_frame(&example(sample)))
+            .map_err(|e| format!("Failed to write record: {}", e))?;
+        self.samples += 1;
+        if self.samples.is_multiple_of(RECORDS_PER_FILE) {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        match self.out.take() {
+            Some(mut out) => out
+                .flush()
+                .map_err(|e| format!("Failed to flush record: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// One LMDB environment. Entries are 1-indexed and zero-padded to nine digits, as the OCR
+/// scripts expect; `label-` holds the text, `class-` the class id and `meta-` the metadata.
+pub struct LmdbSet {
+    env: Env,
+    db: Database<Str, Bytes>,
+    samples: usize,
+    pending: Vec<(String, Vec<u8>)>,
+}
+
+impl LmdbSet {
+    fn open(dir: &str) -> Result<LmdbSet, String> {
+        let fail = |e: heed::Error| format!("Failed to open LMDB in {}: {}", dir, e);
+        // Safety: the environment is opened once per process and never concurrently resized.
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(LMDB_MAP_SIZE)
+                .max_dbs(1)
+                .open(dir)
+        }
+        .map_err(fail)?;
+        let mut txn = env.write_txn().map_err(fail)?;
+        let db = env.create_database(&mut txn, None).map_err(fail)?;
+        txn.commit().map_err(fail)?;
+        Ok(LmdbSet {
+            env,
+            db,
+            samples: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    fn push(&mut self, sample: &EncodedSample) -> Result<(), String> {
+        self.samples += 1;
+        let n = self.samples;
+        let metadata = &sample.metadata;
+        let phrase = metadata["phrase"].as_str().unwrap_or_default();
+        self.pending.extend([
+            (format!("image-{:09}", n), sample.image.clone()),
+            (format!("label-{:09}", n), phrase.as_bytes().to_vec()),
+            (
+                format!("class-{:09}", n),
+                metadata["label"]["id"].to_string().into_bytes(),
+            ),
+            (
+                format!("meta-{:09}", n),
+                sample.record().to_string().into_bytes(),
+            ),
+        ]);
+        if n.is_multiple_of(LMDB_COMMIT_EVERY) {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), String> {
+        let fail = |e: heed::Error| format!("Failed to write LMDB: {}", e);
+        let mut txn = self.env.write_txn().map_err(fail)?;
+        for (key, value) in self.pending.drain(..) {
+            self.db.put(&mut txn, &key, &value).map_err(fail)?;
+        }
+        self.db
+            .put(&mut txn, "num-samples", self.samples.to_string().as_bytes())
+            .map_err(fail)?;
+        txn.commit().map_err(fail)
+    }
+}
+
+/// Writes samples in one record format under one directory.
+pub enum RecordSet {
+    TfRecord(TfRecordSet),
+    Lmdb(LmdbSet),
+}
+
+impl RecordSet {
+    pub fn new(dir: &str, format: RecordFormat) -> Result<RecordSet, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+        Ok(match format {
+            RecordFormat::TfRecord => RecordSet::TfRecord(TfRecordSet {
+                dir: dir.to_string(),
+                out: None,
+                files: 0,
+                samples: 0,
+            }),
+            RecordFormat::Lmdb => RecordSet::Lmdb(LmdbSet::open(dir)?),
+        })
+    }
+}
+
+impl ArchiveWriter for RecordSet {
+    fn push(&mut self, sample: Arc<EncodedSample>) -> Result<(), String> {
+        match self {
+            RecordSet::TfRecord(set) => set.push(&sample),
+            RecordSet::Lmdb(set) => set.push(&sample),
+        }
+    }
+
+    /// Flushes the last file or transaction and returns a summary.
+    fn finish(self: Box<Self>) -> Result<Value, String> {
+        match *self {
+            RecordSet::TfRecord(mut set) => {
+                set.close()?;
+                let summary =
+                    json!({ "format": "tfrecord", "files": set.files, "samples": set.samples });
+                let path = format!("{}/index.json", set.dir);
+                fs::write(
+                    &path,
+                    serde_json::to_string_pretty(&summary).unwrap_or_default(),
+                )
+                .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+                Ok(summary)
+            }
+            RecordSet::Lmdb(mut set) => {
+                set.commit()?;
+                Ok(json!({ "format": "lmdb", "samples": set.samples }))
+            }
+        }
+    }
+}
+
+/// `export <tfrecord|lmdb>`: converts the folder layout under `OUTPUT_DIR` into a record
+/// format, written to `OUTPUT_DIR/export/<format>`. An earlier export there is replaced; the
+/// `OUTPUT_DIR/<format>` written by `--records` is left alone.
+pub async fn run_export(args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let format = args
+        .first()
+        .and_then(|name| RecordFormat::by_name(name))
+        .ok_or("export takes a format: tfrecord or lmdb")?;
+    let output = format!("{}/{}/{}", OUTPUT_DIR, EXPORT_DIR, format.name());
+    // Leftover shards or an older database would mix stale samples into the export.
+    let _ = fs::remove_dir_all(&output);
+
+    let dir = output.clone();
+    let summary = tokio::task::spawn_blocking(move || {
+        let mut set = Box::new(RecordSet::new(&dir, format)?);
+        for font in sample_folders(OUTPUT_DIR) {
+            let dir = Path::new(OUTPUT_DIR).join(&font);
+            for metadata in read_samples(&dir) {
+                let Some(index) = metadata["index"].as_u64() else {
+                    continue;
+                };
+                let image_path = dir.join(format!("{}.{}", index, image_extension(&metadata)));
+                let image = match fs::read(&image_path) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        eprintln!("Skipping {}: {}", image_path.display(), e);
+                        continue;
+                    }
+                };
+                set.push(Arc::new(EncodedSample {
+                    key: EncodedSample::key_for(&font, index as usize),
+                    font: font.clone(),
+                    index: index as usize,
+                    image,
+                    metadata,
+                    annotations: json!({}),
+                }))?;
+            }
+        }
+        set.finish()
+    })
+    .await??;
+
+    println!(
+        "{} {} samples to {}",
+        "Exported".green(),
+        summary["samples"],
+        output
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_and_examples_match_tensorflow_encoding() {
+        // Reference values from the CRC-32C specification and TensorFlow's `masked_crc32c`.
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(&[0u8; 32]), 0x8A91_36AA);
+        assert_eq!(masked_crc(b""), 0xa282_ead8);
+
+        let frame = tfrecord_frame(b"abc");
+        assert_eq!(frame.len(), 8 + 4 + 3 + 4);
+        assert_eq!(&frame[..8], &3u64.to_le_bytes());
+        assert_eq!(&frame[12..15], b"abc");
+
+        let mut out = Vec::new();
+        varint(&mut out, 300);
+        assert_eq!(out, [0xac, 0x02]);
+
+        // int64_list { value: [1, 300] } inside a Feature.
+        assert_eq!(
+            Feature::Ints(vec![1, 300]).encode(),
+            [0x1a, 0x05, 0x0a, 0x03, 0x01, 0xac, 0x02]
+        );
+
+        let sample = EncodedSample {
+            key: EncodedSample::key_for("Vazir", 1),
+            font: "Vazir".to_string(),
+            index: 1,
+            image: vec![0xff, 0xd8],
+            metadata: json!({
+                "phrase": "سلام",
+                "label": { "name": "Vazir", "id": 2 },
+                "viewport": [640, 480],
+                "encoding": { "resize": { "policy": "letterbox", "size": 224 }, "size": [224, 160] },
+            }),
+            annotations: json!({ "text_boxes": [[1, 2, 3, 4]] }),
+        };
+        let encoded = example(&sample);
+        let needle = "image/text".as_bytes();
+        assert!(encoded.windows(needle.len()).any(|w| w == needle));
+        assert!(encoded.windows("سلام".len()).any(|w| w == "سلام".as_bytes()));
+        // Letterboxed images report the encoded size, not the viewport's.
+        for (name, size) in [("image/width", 224), ("image/height", 160)] {
+            let mut entry = Vec::new();
+            message_field(&mut entry, 1, name.as_bytes());
+            message_field(&mut entry, 2, &Feature::Ints(vec![size]).encode());
+            assert!(encoded.windows(entry.len()).any(|w| w == entry));
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use image::{imageops, DynamicImage, GenericImageView, GrayImage, ImageOutputFormat, RgbImage};
+use once_cell::sync::Lazy;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+use tokio::task;
+
+use crate::seeding::task_rng;
+use crate::styles::{ensure_wcag_contrast, random_color, Color};
+
+// Longest side of the downscaled copy used to look for flat regions.
+const ANALYSIS_SIZE: u32 = 320;
+// How many candidate regions to keep per background.
+const MAX_REGIONS: usize = 8;
+const VIEWPORT_RANGE: (u32, u32) = (400, 1000);
+
+/// A flat, low-texture area of a background that text can plausibly sit on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Mean edge strength plus luminance spread; lower is flatter.
+    pub texture: f64,
+    pub mean_color: Color,
+}
+
+pub struct SceneAnalysis {
+    pub width: u32,
+    pub height: u32,
+    pub regions: Vec<Region>,
+}
+
+/// Scene-text page parts for `create_html_content`.
+pub struct SceneStyles {
+    pub body_styles: String,
+    pub text_styles: String,
+    pub viewport: (u32, u32),
+    pub metadata: Value,
+}
+
+// Caches the region analysis (not the decoded pixels) per background. Key is the address of
+// the shared image buffer, which stays fixed for the whole run.
+static SCENE_CACHE: Lazy<RwLock<HashMap<usize, Arc<SceneAnalysis>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+struct Integral {
+    width: usize,
+    sums: Vec<f64>,
+}
+
+impl Integral {
+    fn new(width: u32, height: u32, value: impl Fn(u32, u32) -> f64) -> Self {
+        let (w, h) = (width as usize + 1, height as usize + 1);
+        let mut sums = vec![0.0; w * h];
+        for y in 1..h {
+            let mut row = 0.0;
+            for x in 1..w {
+                row += value(x as u32 - 1, y as u32 - 1);
+                sums[y * w + x] = sums[(y - 1) * w + x] + row;
+            }
+        }
+        Self { width: w, sums }
+    }
+
+    fn mean(&self, x: u32, y: u32, width: u32, height: u32) -> f64 {
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = (x0 + width as usize, y0 + height as usize);
+        let w = self.width;
+        let sum = self.sums[y1 * w + x1] - self.sums[y0 * w + x1] - self.sums[y1 * w + x0]
+            + self.sums[y0 * w + x0];
+        sum / (width * height) as f64
+    }
+}
+
+fn sobel_magnitude(gray: &GrayImage) -> Vec<f64> {
+    let (w, h) = gray.dimensions();
+    let at = |x: i64, y: i64| {
+        let x = x.clamp(0, w as i64 - 1) as u32;
+        let y = y.clamp(0, h as i64 - 1) as u32;
+        gray.get_pixel(x, y)[0] as f64
+    };
+    let mut out = Vec::with_capacity((w * h) as usize);
+    for y in 0..h as i64 {
+        for x in 0..w as i64 {
+            let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x - 1, y)
+                - at(x - 1, y + 1);
+            let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x, y - 1)
+                - at(x + 1, y - 1);
+            out.push((gx * gx + gy * gy).sqrt());
+        }
+    }
+    out
+}
+
+fn overlap(a: &Region, b: &Region) -> f64 {
+    let ix = (a.x + a.width).min(b.x + b.width) as f64 - a.x.max(b.x) as f64;
+    let iy = (a.y + a.height).min(b.y + b.height) as f64 - a.y.max(b.y) as f64;
+    if ix <= 0.0 || iy <= 0.0 {
+        return 0.0;
+    }
+    let inter = ix * iy;
+    inter / ((a.width * a.height) as f64 + (b.width * b.height) as f64 - inter)
+}
+
+/// Scores text-shaped windows by edge density and luminance variance and keeps the flattest
+/// non-overlapping ones, in the original image's coordinates.
+pub fn find_low_texture_regions(img: &DynamicImage) -> Vec<Region> {
+    let (orig_w, orig_h) = img.dimensions();
+    let scale = ANALYSIS_SIZE as f64 / orig_w.max(orig_h) as f64;
+    let small = if scale < 1.0 {
+        img.resize(
+            (orig_w as f64 * scale) as u32,
+            (orig_h as f64 * scale) as u32,
+            imageops::FilterType::Triangle,
+        )
+    } else {
+        img.clone()
+    };
+    let scale = small.width() as f64 / orig_w as f64;
+
+    let rgb = small.to_rgb8();
+    let gray = small.to_luma8();
+    let (w, h) = gray.dimensions();
+    let edges = sobel_magnitude(&gray);
+    let edge_sum = Integral::new(w, h, |x, y| edges[(y * w + x) as usize]);
+    let luma_sum = Integral::new(w, h, |x, y| gray.get_pixel(x, y)[0] as f64);
+    let luma_sq_sum = Integral::new(w, h, |x, y| (gray.get_pixel(x, y)[0] as f64).powi(2));
+
+    let mut candidates = Vec::new();
+    for height_frac in [0.08, 0.12, 0.18, 0.25] {
+        let wh = ((h as f64 * height_frac) as u32).max(4);
+        for aspect in [2.0, 3.5, 5.0] {
+            let ww = ((wh as f64 * aspect) as u32).min(w);
+            if ww < 8 || wh >= h {
+                continue;
+            }
+            let stride = (wh / 3).max(2);
+            for y in (0..h - wh).step_by(stride as usize) {
+                for x in (0..=w - ww).step_by(stride as usize) {
+                    let mean = luma_sum.mean(x, y, ww, wh);
+                    let variance = (luma_sq_sum.mean(x, y, ww, wh) - mean * mean).max(0.0);
+                    let texture = edge_sum.mean(x, y, ww, wh) + 0.5 * variance.sqrt();
+                    candidates.push((texture, x, y, ww, wh));
+                }
+            }
+        }
+    }
+    // Prefer larger windows among similarly flat ones.
+    candidates.sort_by(|a, b| {
+        let score = |c: &(f64, u32, u32, u32, u32)| c.0 - 0.02 * c.4 as f64;
+        score(a).total_cmp(&score(b))
+    });
+
+    let mut regions: Vec<Region> = Vec::new();
+    for (texture, x, y, ww, wh) in candidates {
+        let (rx, ry) = ((x as f64 / scale) as u32, (y as f64 / scale) as u32);
+        let region = Region {
+            x: rx,
+            y: ry,
+            width: ((ww as f64 / scale) as u32).min(orig_w - rx),
+            height: ((wh as f64 / scale) as u32).min(orig_h - ry),
+            texture,
+            mean_color: mean_color(&rgb, x, y, ww, wh),
+        };
+        if regions.iter().all(|r| overlap(r, &region) < 0.2) {
+            regions.push(region);
+        }
+        if regions.len() >= MAX_REGIONS {
+            break;
+        }
+    }
+    regions
+}
+
+fn mean_color(img: &RgbImage, x: u32, y: u32, w: u32, h: u32) -> Color {
+    let mut sums = [0u64; 3];
+    for py in y..y + h {
+        for px in x..x + w {
+            let p = img.get_pixel(px, py);
+            for c in 0..3 {
+                sums[c] += p[c] as u64;
+            }
+        }
+    }
+    let n = (w * h).max(1) as u64;
+    (
+        (sums[0] / n) as u8,
+        (sums[1] / n) as u8,
+        (sums[2] / n) as u8,
+    )
+}
+
+async fn analyze_background(buffer: &Arc<Vec<u8>>) -> Result<Arc<SceneAnalysis>, String> {
+    let key = Arc::as_ptr(buffer) as usize;
+    {
+        let cache_guard = SCENE_CACHE.read().await;
+        if let Some(cached) = cache_guard.get(&key) {
+            return Ok(cached.clone());
+        }
+    }
+
+    let buffer = Arc::clone(buffer);
+    let analysis = task::spawn_blocking(move || {
+        let image = image::load_from_memory(&buffer)
+            .map_err(|e| format!("Failed to load background: {}", e))?;
+        Ok::<_, String>(SceneAnalysis {
+            width: image.width(),
+            height: image.height(),
+            regions: find_low_texture_regions(&image),
+        })
+    })
+    .await
+    .map_err(|e| format!("Background analysis panicked: {}", e))??;
+
+    let analysis = Arc::new(analysis);
+    SCENE_CACHE.write().await.insert(key, Arc::clone(&analysis));
+    Ok(analysis)
+}
+
+/// Picks a background and one of its flat regions, crops a viewport around it and returns
+/// styles that put the text container on the region with a sampled perspective.
+pub async fn generate_scene_styles(images: &[Arc<Vec<u8>>]) -> Result<SceneStyles, String> {
+    let (buffer_index, analysis) = {
+        let mut attempts = 0;
+        loop {
+            let index = task_rng().gen_range(0..images.len());
+            let analysis = analyze_background(&images[index]).await?;
+            if !analysis.regions.is_empty() {
+                break (index, analysis);
+            }
+            attempts += 1;
+            if attempts > 10 {
+                return Err("No background with a usable text region".to_string());
+            }
+        }
+    };
+
+    let region = analysis
+        .regions
+        .choose(&mut task_rng())
+        .cloned()
+        .ok_or("No text region")?;
+    let (img_w, img_h) = (analysis.width, analysis.height);
+
+    // Crop a viewport-sized window that contains the region.
+    let crop_w = task_rng()
+        .gen_range(VIEWPORT_RANGE.0..=VIEWPORT_RANGE.1)
+        .clamp(region.width, img_w);
+    let crop_h = task_rng()
+        .gen_range(VIEWPORT_RANGE.0..=VIEWPORT_RANGE.1)
+        .clamp(region.height, img_h);
+    let left_min = (region.x + region.width).saturating_sub(crop_w);
+    let left_max = region.x.min(img_w - crop_w);
+    let top_min = (region.y + region.height).saturating_sub(crop_h);
+    let top_max = region.y.min(img_h - crop_h);
+    let left = task_rng().gen_range(left_min.min(left_max)..=left_max);
+    let top = task_rng().gen_range(top_min.min(top_max)..=top_max);
+
+    let source = Arc::clone(&images[buffer_index]);
+    let encoded = task::spawn_blocking(move || {
+        let image = image::load_from_memory(&source)
+            .map_err(|e| format!("Failed to load background: {}", e))?;
+        let mut buffer = Cursor::new(Vec::new());
+        image
+            .crop_imm(left, top, crop_w, crop_h)
+            .write_to(&mut buffer, ImageOutputFormat::Png)
+            .map_err(|e| format!("Failed to write image: {}", e))?;
+        Ok::<_, String>(STANDARD.encode(buffer.get_ref()))
+    })
+    .await
+    .map_err(|e| format!("Background crop panicked: {}", e))??;
+
+    let mut text_color = random_color();
+    while !ensure_wcag_contrast(&region.mean_color, &text_color, &3.0) {
+        text_color = random_color();
+    }
+
+    let (x, y) = (region.x - left, region.y - top);
+    let perspective = task_rng().gen_range(500..=1500);
+    let rotate_x = task_rng().gen_range(-20.0..=20.0f64);
+    let rotate_y = task_rng().gen_range(-25.0..=25.0f64);
+    let rotate_z = task_rng().gen_range(-5.0..=5.0f64);
+    let font_size = (region.height as f64 * task_rng().gen_range(0.45..=0.8)).round();
+
+    let body_styles = format!(
+        "background-image: url(data:image/png;base64,{}); background-size: 100% 100%; background-repeat: no-repeat;",
+        encoded
+    );
+    let text_styles = format!(
+        "position: absolute; left: {}px; top: {}px; width: {}px; height: {}px; display: flex; align-items: center; justify-content: center; text-align: center; white-space: nowrap; font-size: {}px; color: #{:02x}{:02x}{:02x}; transform: perspective({}px) rotateX({:.2}deg) rotateY({:.2}deg) rotateZ({:.2}deg);",
+        x,
+        y,
+        region.width,
+        region.height,
+        font_size,
+        text_color.0,
+        text_color.1,
+        text_color.2,
+        perspective,
+        rotate_x,
+        rotate_y,
+        rotate_z
+    );
+
+    Ok(SceneStyles {
+        body_styles,
+        text_styles,
+        viewport: (crop_w, crop_h),
+        metadata: json!({
+            "background": buffer_index,
+            "crop": [left, top, crop_w, crop_h],
+            "region": [x, y, region.width, region.height],
+            "region_texture": (region.texture * 100.0).round() / 100.0,
+            "local_mean_color": [region.mean_color.0, region.mean_color.1, region.mean_color.2],
+            "text_color": [text_color.0, text_color.1, text_color.2],
+            "perspective": perspective,
+            "rotate": [
+                (rotate_x * 100.0).round() / 100.0,
+                (rotate_y * 100.0).round() / 100.0,
+                (rotate_z * 100.0).round() / 100.0,
+            ],
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn test_flat_region_is_found_on_busy_image() {
+        // Checkerboard everywhere except a flat grey band across the middle.
+        let img = RgbImage::from_fn(300, 200, |x, y| {
+            if (80..120).contains(&y) {
+                Rgb([128, 128, 128])
+            } else if (x / 4 + y / 4) % 2 == 0 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        });
+        let regions = find_low_texture_regions(&DynamicImage::ImageRgb8(img));
+        let best = &regions[0];
+        assert!(best.y >= 78 && best.y + best.height <= 122, "{:?}", best);
+        assert_eq!(best.mean_color, (128, 128, 128));
+    }
+}
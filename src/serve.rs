@@ -0,0 +1,475 @@
+use colored::*;
+use headless_chrome::Browser;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::Semaphore;
+
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::browser::BrowserManager;
+use crate::columnar::{split_for, SPLITS};
+use crate::seeding::{task_rng, with_seed};
+use crate::shards::{tar_entries, BLOCK};
+use crate::sinks::ChannelSink;
+use crate::{
+    get_available_fonts, get_image_buffers, load_class_index, load_phrases, render_sample, Assets,
+    RunOptions, Sample, FONTS_DIR, PHRASES_PATH, SEMAPHORES, TEMPLATE_PATH,
+};
+
+// `serve`: keeps the browser, fonts and backgrounds loaded and renders samples on demand.
+// `GET /samples` answers with a tar stream in the layout of the `--shards` output, which
+// WebDataset reads directly from a URL. Samples are rendered a few ahead of the client, and
+// rendering waits while the client is not reading. `GET /fonts` lists the font classes.
+//
+// The rest of the command line takes the same flags as a generation run; output flags are
+// ignored.
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8642";
+// Samples rendered ahead of what the client has read.
+const READ_AHEAD: usize = 4;
+// A stream stops after this many renders in a row fail or are rejected.
+const MAX_FAILURES_IN_A_ROW: usize = 20;
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+struct Server {
+    assets: Assets,
+    phrases: Vec<String>,
+    browser: Arc<Browser>,
+    options: RunOptions,
+    // One browser tab per open stream.
+    streams: Semaphore,
+}
+
+/// Query parameters of `GET /samples`.
+#[derive(Debug, PartialEq)]
+struct StreamRequest {
+    /// Font classes to draw from; all of them when absent.
+    fonts: Option<Vec<String>>,
+    /// Samples to send before the stream ends; endless when absent.
+    count: Option<usize>,
+    /// Draw phrases from one split only, so validation streams never see training text.
+    split: Option<&'static str>,
+    seed: u64,
+}
+
+fn percent_decode(value: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' if tail.len() >= 2 => {
+                let hex = std::str::from_utf8(&tail[..2]).unwrap_or_default();
+                let decoded = u8::from_str_radix(hex, 16)
+                    .map_err(|_| format!("Invalid escape in {}", value))?;
+                bytes.push(decoded);
+                rest = &tail[2..];
+                continue;
+            }
+            _ => bytes.push(byte),
+        }
+        rest = tail;
+    }
+    String::from_utf8(bytes).map_err(|_| format!("Invalid UTF-8 in {}", value))
+}
+
+/// Decoded `name=value` pairs of a query string, in order.
+pub fn query_pairs(query: &str) -> Result<Vec<(&str, String)>, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((name, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn parse_stream_request(query: &str) -> Result<StreamRequest, String> {
+    let mut request = StreamRequest {
+        fonts: None,
+        count: None,
+        split: None,
+        seed: thread_rng().gen(),
+    };
+    for (name, value) in query_pairs(query)? {
+        match name {
+            "fonts" => {
+                request.fonts = Some(
+                    value
+                        .split(',')
+                        .filter(|font| !font.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                )
+            }
+            "count" => request.count = Some(value.parse().map_err(|_| "count must be a number")?),
+            "split" => {
+                request.split = Some(
+                    SPLITS
+                        .iter()
+                        .map(|(split, _)| *split)
+                        .find(|split| *split == value)
+                        .ok_or("split must be one of train, validation, test")?,
+                )
+            }
+            "seed" => request.seed = value.parse().map_err(|_| "seed must be a number")?,
+            _ => return Err(format!("Unknown parameter {}", name)),
+        }
+    }
+    Ok(request)
+}
+
+/// Reads the request line and headers; requests have no body.
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String, String> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_BYTES {
+            return Err("Request head too large".to_string());
+        }
+        let read = stream
+            .read(&mut buffer)
+            .await
+            .map_err(|e| format!("Failed to read request: {}", e))?;
+        if read == 0 {
+            return Err("Connection closed before the request ended".to_string());
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+pub async fn respond<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), String> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    let sent = async {
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.shutdown().await
+    };
+    sent.await
+        .map_err(|e| format!("Failed to send response: {}", e))
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    server: Arc<Server>,
+) -> Result<(), String> {
+    let head = read_head(&mut stream).await?;
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    match (method, path) {
+        ("GET", "/fonts") => {
+            let fonts = json!(server.assets.classes.classes()).to_string();
+            respond(&mut stream, "200 OK", "application/json", fonts.as_bytes()).await
+        }
+        ("GET", "/samples") => match parse_stream_request(query) {
+            Ok(request) => stream_samples(stream, server, request).await,
+            Err(e) => respond(&mut stream, "400 Bad Request", "text/plain", e.as_bytes()).await,
+        },
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"Not found").await,
+    }
+}
+
+async fn stream_samples<S: AsyncWrite + Unpin>(
+    mut stream: S,
+    server: Arc<Server>,
+    request: StreamRequest,
+) -> Result<(), String> {
+    let classes = server.assets.classes.classes();
+    let fonts = request.fonts.unwrap_or_else(|| classes.clone());
+    if let Some(unknown) = fonts.iter().find(|font| !classes.contains(font)) {
+        let message = format!("Unknown font class {}", unknown);
+        return respond(
+            &mut stream,
+            "400 Bad Request",
+            "text/plain",
+            message.as_bytes(),
+        )
+        .await;
+    }
+    let pool: Vec<String> = server
+        .phrases
+        .iter()
+        .filter(|phrase| request.split.is_none_or(|split| split_for(phrase) == split))
+        .cloned()
+        .collect();
+    if fonts.is_empty() || pool.is_empty() {
+        let message = "Nothing to render: no fonts or no phrases in the split";
+        return respond(
+            &mut stream,
+            "400 Bad Request",
+            "text/plain",
+            message.as_bytes(),
+        )
+        .await;
+    }
+    let Ok(_permit) = server.streams.try_acquire() else {
+        let message = "Too many open streams";
+        return respond(
+            &mut stream,
+            "503 Service Unavailable",
+            "text/plain",
+            message.as_bytes(),
+        )
+        .await;
+    };
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-tar\r\nX-Seed: {}\r\nConnection: close\r\n\r\n",
+        request.seed
+    );
+
+    let (sink, mut samples) = ChannelSink::new(READ_AHEAD);
+    let sink = Arc::new(sink);
+    let mut options = server.options.clone();
+    options.sink = Some(sink.clone());
+    options.split = request.split;
+    let generator = tokio::spawn(with_seed(
+        request.seed,
+        generate(
+            Arc::clone(&server),
+            fonts,
+            pool,
+            request.count,
+            options,
+            sink,
+        ),
+    ));
+
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // The head waits for the first sample, so a stream that fails before producing anything
+    // can still answer with an error status.
+    let mut sent = 0;
+    while let Some(sample) = samples.recv().await {
+        let mut entries = tar_entries(&sample, mtime)?;
+        if sent == 0 {
+            entries.splice(0..0, head.bytes());
+        }
+        if stream.write_all(&entries).await.is_err() {
+            // The client went away; dropping the receiver stops the generator.
+            break;
+        }
+        sent += 1;
+    }
+    drop(samples);
+    let generated = match generator.await {
+        Ok(result) => result,
+        Err(e) => Err(format!("Generator task failed: {}", e)),
+    };
+    if let Err(e) = &generated {
+        eprintln!("Stream with seed {} failed: {}", request.seed, e);
+        if sent == 0 {
+            return respond(
+                &mut stream,
+                "500 Internal Server Error",
+                "text/plain",
+                e.as_bytes(),
+            )
+            .await;
+        }
+    }
+
+    if sent == 0 {
+        let _ = stream.write_all(head.as_bytes()).await;
+    }
+    let _ = stream.write_all(&[0u8; 2 * BLOCK]).await;
+    let _ = stream.shutdown().await;
+    println!(
+        "Stream with seed {} ended after {} samples",
+        request.seed, sent
+    );
+    Ok(())
+}
+
+/// Renders samples into the options' sink until `count` are written or the stream closes.
+async fn generate(
+    server: Arc<Server>,
+    fonts: Vec<String>,
+    pool: Vec<String>,
+    count: Option<usize>,
+    options: RunOptions,
+    sink: Arc<ChannelSink>,
+) -> Result<usize, String> {
+    let tab = server.browser.new_tab().map_err(|e| e.to_string())?;
+    let (mut written, mut failures) = (0, 0);
+    while count.is_none_or(|count| written < count) && !sink.is_closed() {
+        let font = fonts.choose(&mut task_rng()).unwrap();
+        let phrase = pool.choose(&mut task_rng()).unwrap();
+        let sample = Sample {
+            font,
+            face: None,
+            phrase,
+            pool: &pool,
+            index: written,
+        };
+        match render_sample(&tab, &sample, &server.assets, &options).await {
+            Ok(report) if !report.is_rejected() => {
+                written += 1;
+                failures = 0;
+            }
+            Ok(_) => failures += 1,
+            Err(_) if sink.is_closed() => break,
+            Err(e) => {
+                eprintln!("Error creating image for font {}: {}", font, e);
+                failures += 1;
+            }
+        }
+        if failures >= MAX_FAILURES_IN_A_ROW {
+            eprintln!(
+                "Stopping a stream after {} failed samples in a row",
+                failures
+            );
+            break;
+        }
+    }
+    let _ = tab.close(false);
+    Ok(written)
+}
+
+fn spawn_connection<S>(stream: S, server: &Arc<Server>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let server = Arc::clone(server);
+    tokio::spawn(async move {
+        if let Err(e) = handle_connection(stream, server).await {
+            eprintln!("Request failed: {}", e);
+        }
+    });
+}
+
+/// Removes a socket left behind by an earlier server, which would make the bind fail. Any
+/// other file at `path` is kept and reported instead.
+fn remove_stale_socket(path: &str) -> Result<(), String> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).map_err(|e| format!("Failed to remove {}: {}", path, e))
+        }
+        Ok(_) => Err(format!("{} exists and is not a socket", path)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Failed to inspect {}: {}", path, e)),
+    }
+}
+
+pub async fn run_serve(args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut socket = None;
+    let mut render_args = Vec::new();
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("--listen", address)) => listen = address.to_string(),
+            Some(("--socket", path)) => socket = Some(path.to_string()),
+            _ => render_args.push(arg.clone()),
+        }
+    }
+    let options = RunOptions::from_args(&render_args)?;
+
+    let (fonts, html_template, phrases, images) = tokio::join!(
+        get_available_fonts(FONTS_DIR),
+        tokio::fs::read_to_string(TEMPLATE_PATH),
+        load_phrases(PHRASES_PATH),
+        get_image_buffers()
+    );
+    let classes = load_class_index(&fonts?).await?;
+    let browser_manager = BrowserManager::new();
+    let browser = browser_manager
+        .create_browser()
+        .map_err(|e| format!("Failed to launch browser: {:?}", e))?;
+    let server = Arc::new(Server {
+        assets: Assets {
+            html_template: html_template?,
+            images: images?,
+            classes,
+        },
+        phrases: phrases?,
+        browser: Arc::new(browser),
+        options,
+        streams: Semaphore::new(SEMAPHORES),
+    });
+
+    match socket {
+        Some(path) => {
+            remove_stale_socket(&path)?;
+            let listener = UnixListener::bind(&path)?;
+            println!("{} unix:{}", "Serving samples on".green(), path);
+            loop {
+                let (stream, _) = listener.accept().await?;
+                spawn_connection(stream, &server);
+            }
+        }
+        None => {
+            let listener = TcpListener::bind(&listen).await?;
+            println!("{} http://{}", "Serving samples on".green(), listen);
+            loop {
+                let (stream, _) = listener.accept().await?;
+                spawn_connection(stream, &server);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stream_parameters() {
+        let request =
+            parse_stream_request("fonts=Vazir,B%20Nazanin&count=100&split=validation&seed=7")
+                .unwrap();
+        assert_eq!(
+            request,
+            StreamRequest {
+                fonts: Some(vec!["Vazir".to_string(), "B Nazanin".to_string()]),
+                count: Some(100),
+                split: Some("validation"),
+                seed: 7,
+            }
+        );
+        assert_eq!(percent_decode("%D9%88%D8%B2%DB%8C%D8%B1").unwrap(), "وزیر");
+        assert!(parse_stream_request("split=dev").is_err());
+        assert!(parse_stream_request("colour=red").is_err());
+        assert_eq!(parse_stream_request("").unwrap().count, None);
+    }
+
+    #[test]
+    fn only_stale_sockets_are_removed() {
+        let dir = std::env::temp_dir().join(format!("serve-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("data.txt");
+        fs::write(&file, "keep").unwrap();
+        let socket = dir.join("serve.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+
+        assert!(remove_stale_socket(file.to_str().unwrap()).is_err());
+        assert!(file.exists());
+        remove_stale_socket(socket.to_str().unwrap()).unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(socket.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
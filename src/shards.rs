@@ -0,0 +1,324 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::sinks::{ArchiveWriter, EncodedSample};
+
+// WebDataset output: samples are streamed into numbered `.tar` shards, each sample stored as
+// `<key>.<ext>` (the encoded image), `<key>.json` and `<key>.cls` next to each other.
+
+pub const BLOCK: usize = 512;
+
+/// The tar members of a sample, with the key as their shared basename.
+fn members(sample: &EncodedSample) -> [(String, Cow<'_, [u8]>); 3] {
+    let key = &sample.key;
+    [
+        (
+            format!("{}.{}", key, sample.extension()),
+            Cow::Borrowed(&sample.image),
+        ),
+        (
+            format!("{}.json", key),
+            Cow::Owned(sample.record().to_string().into_bytes()),
+        ),
+        (
+            format!("{}.cls", key),
+            Cow::Owned(sample.metadata["label"]["id"].to_string().into_bytes()),
+        ),
+    ]
+}
+
+/// A sample's members as consecutive tar entries, for writing to a stream. The stream ends
+/// with two empty blocks.
+pub fn tar_entries(sample: &EncodedSample, mtime: u64) -> Result<Vec<u8>, String> {
+    let mut entries = Vec::new();
+    for (name, data) in members(sample) {
+        entries.extend_from_slice(&tar_header(&name, data.len(), mtime)?);
+        entries.extend_from_slice(&data);
+        entries.resize(entries.len() + padded(data.len()) - data.len(), 0);
+    }
+    Ok(entries)
+}
+
+/// Where and how shards are written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardConfig {
+    pub max_bytes: u64,
+    /// Size of the shuffle buffer; 0 writes samples in the order they arrive.
+    pub shuffle: usize,
+}
+
+fn padded(len: usize) -> usize {
+    len.div_ceil(BLOCK) * BLOCK
+}
+
+/// Octal field of `width` bytes, NUL terminated.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+/// A ustar header for a regular file. Names over 100 bytes are split into prefix and name
+/// at a `/`.
+fn tar_header(name: &str, size: usize, mtime: u64) -> Result<[u8; BLOCK], String> {
+    let mut header = [0u8; BLOCK];
+    let (prefix, name) = if name.len() <= 100 {
+        ("", name)
+    } else {
+        name.rsplit_once('/')
+            .filter(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+            .ok_or_else(|| format!("Name too long for a tar header: {}", name))?
+    };
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size as u64);
+    octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    let digits = format!("{:06o}\0 ", checksum);
+    header[148..156].copy_from_slice(digits.as_bytes());
+    Ok(header)
+}
+
+struct OpenShard {
+    name: String,
+    out: BufWriter<File>,
+    bytes: u64,
+    members: Vec<Value>,
+}
+
+/// Writes samples into `shard-NNNNNN.tar` files under one directory, starting a new shard
+/// whenever the next sample would push the current one past `max_bytes`. Every shard gets
+/// a `shard-NNNNNN.index.json` with the byte offset of each member, and `finish` writes
+/// `index.json` listing all shards.
+pub struct ShardSet {
+    dir: String,
+    config: ShardConfig,
+    mtime: u64,
+    current: Option<OpenShard>,
+    shards: Vec<Value>,
+    buffer: Vec<Arc<EncodedSample>>,
+    rng: StdRng,
+    // Extension of the image members, taken from the first sample written.
+    image_extension: Option<String>,
+}
+
+impl ShardSet {
+    pub fn new(dir: &str, config: ShardConfig) -> Result<ShardSet, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+        Ok(ShardSet {
+            dir: dir.to_string(),
+            config,
+            mtime: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            current: None,
+            shards: Vec::new(),
+            buffer: Vec::new(),
+            rng: StdRng::from_entropy(),
+            image_extension: None,
+        })
+    }
+
+    fn write(&mut self, sample: &EncodedSample) -> Result<(), String> {
+        let members = members(sample);
+        self.image_extension
+            .get_or_insert_with(|| sample.extension().to_string());
+        // Bytes the sample takes in the archive, headers and padding included.
+        let size: u64 = members
+            .iter()
+            .map(|(_, data)| (BLOCK + padded(data.len())) as u64)
+            .sum();
+        if let Some(shard) = &self.current {
+            if !shard.members.is_empty() && shard.bytes + size > self.config.max_bytes {
+                self.close()?;
+            }
+        }
+        if self.current.is_none() {
+            let name = format!("shard-{:06}.tar", self.shards.len());
+            let path = format!("{}/{}", self.dir, name);
+            let file =
+                File::create(&path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+            self.current = Some(OpenShard {
+                name,
+                out: BufWriter::new(file),
+                bytes: 0,
+                members: Vec::new(),
+            });
+        }
+
+        let shard = self.current.as_mut().unwrap();
+        let mut files = serde_json::Map::new();
+        for (name, data) in &members {
+            let header = tar_header(name, data.len(), self.mtime)?;
+            let offset = shard.bytes + BLOCK as u64;
+            let padding = padded(data.len()) - data.len();
+            shard
+                .out
+                .write_all(&header)
+                .and_then(|_| shard.out.write_all(data))
+                .and_then(|_| shard.out.write_all(&[0u8; BLOCK][..padding]))
+                .map_err(|e| format!("Failed to write {}: {}", shard.name, e))?;
+            shard.bytes += (BLOCK + data.len() + padding) as u64;
+            let extension = name.rsplit('.').next().unwrap_or_default().to_string();
+            files.insert(extension, json!({ "offset": offset, "size": data.len() }));
+        }
+        shard
+            .members
+            .push(json!({ "key": sample.key, "files": files }));
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), String> {
+        let Some(mut shard) = self.current.take() else {
+            return Ok(());
+        };
+        // A tar archive ends with two empty blocks.
+        shard
+            .out
+            .write_all(&[0u8; 2 * BLOCK])
+            .and_then(|_| shard.out.flush())
+            .map_err(|e| format!("Failed to finish {}: {}", shard.name, e))?;
+        shard.bytes += 2 * BLOCK as u64;
+
+        let index = json!({
+            "shard": shard.name,
+            "bytes": shard.bytes,
+            "samples": shard.members.len(),
+            "members": shard.members,
+        });
+        let path = format!("{}/{}", self.dir, shard.name.replace(".tar", ".index.json"));
+        fs::write(&path, index.to_string())
+            .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        self.shards.push(json!({
+            "shard": shard.name,
+            "bytes": shard.bytes,
+            "samples": index["samples"],
+        }));
+        Ok(())
+    }
+}
+
+impl ArchiveWriter for ShardSet {
+    /// Queues a sample. With shuffling on, samples wait in the buffer and a random one is
+    /// written once it is full, so consecutive samples of one font spread over many shards.
+    fn push(&mut self, sample: Arc<EncodedSample>) -> Result<(), String> {
+        if self.config.shuffle == 0 {
+            return self.write(&sample);
+        }
+        self.buffer.push(sample);
+        if self.buffer.len() > self.config.shuffle {
+            let pick = self.rng.gen_range(0..self.buffer.len());
+            let sample = self.buffer.swap_remove(pick);
+            self.write(&sample)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the shuffle buffer, closes the last shard and writes `index.json`.
+    fn finish(mut self: Box<Self>) -> Result<Value, String> {
+        while !self.buffer.is_empty() {
+            let pick = self.rng.gen_range(0..self.buffer.len());
+            let sample = self.buffer.swap_remove(pick);
+            self.write(&sample)?;
+        }
+        self.close()?;
+
+        let samples: u64 = self
+            .shards
+            .iter()
+            .filter_map(|s| s["samples"].as_u64())
+            .sum();
+        let index = json!({
+            "format": "webdataset",
+            "extensions": [self.image_extension.as_deref().unwrap_or("jpg"), "json", "cls"],
+            "max_bytes": self.config.max_bytes,
+            "shuffle_buffer": self.config.shuffle,
+            "samples": samples,
+            "shards": self.shards,
+        });
+        let path = format!("{}/index.json", self.dir);
+        fs::write(
+            &path,
+            serde_json::to_string_pretty(&index).unwrap_or_default(),
+        )
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(index: usize, jpg_len: usize) -> Arc<EncodedSample> {
+        Arc::new(EncodedSample {
+            key: EncodedSample::key_for("Vazir.v2", index),
+            font: "Vazir.v2".to_string(),
+            index,
+            image: vec![0xff; jpg_len],
+            metadata: json!({ "index": index, "label": { "id": 3 } }),
+            annotations: json!({}),
+        })
+    }
+
+    #[test]
+    fn samples_roll_over_into_bounded_shards_with_indexes() {
+        let dir = std::env::temp_dir().join(format!("shards-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let config = ShardConfig {
+            max_bytes: 8 * 1024,
+            shuffle: 0,
+        };
+        let mut shards = Box::new(ShardSet::new(dir, config).unwrap());
+        for i in 0..5 {
+            shards.push(sample(i, 3000)).unwrap();
+        }
+        let index = shards.finish().unwrap();
+
+        // Each sample is 3 headers + 3072 + 512 + 512 bytes = 5632; two don't fit in 8 KiB.
+        assert_eq!(index["samples"], 5);
+        assert_eq!(index["shards"].as_array().unwrap().len(), 5);
+
+        let tar = fs::read(format!("{}/shard-000001.tar", dir)).unwrap();
+        assert_eq!(tar.len(), 5632 + 2 * BLOCK);
+        assert!(tar.starts_with(b"Vazir_v2/000001.jpg\0"));
+        assert_eq!(&tar[257..263], b"ustar\0");
+        let checksum: u32 = tar[..BLOCK]
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    b as u32
+                }
+            })
+            .sum();
+        let stored = std::str::from_utf8(&tar[148..154]).unwrap();
+        assert_eq!(u32::from_str_radix(stored, 8).unwrap(), checksum);
+
+        let shard_index: Value = serde_json::from_str(
+            &fs::read_to_string(format!("{}/shard-000001.index.json", dir)).unwrap(),
+        )
+        .unwrap();
+        let cls = &shard_index["members"][0]["files"]["cls"];
+        let offset = cls["offset"].as_u64().unwrap() as usize;
+        assert_eq!(&tar[offset..offset + 1], b"3");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,625 @@
+use futures::future::{join_all, BoxFuture};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::columnar::{ParquetConfig, ParquetSet};
+use crate::encoding::mime_type;
+use crate::records::{RecordFormat, RecordSet};
+use crate::shards::{ShardConfig, ShardSet};
+
+// Where finished samples go. Every sink gets the same `EncodedSample`; the folder layout,
+// the archive formats and the object store only differ in how they lay it out.
+
+// Samples in flight between the render workers and an archive writer before workers wait.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// One finished sample: the encoded image, its metadata and its text annotations.
+#[derive(Debug, Clone)]
+pub struct EncodedSample {
+    pub key: String,
+    pub font: String,
+    pub index: usize,
+    pub image: Vec<u8>,
+    pub metadata: Value,
+    /// Block, span and line geometry; kept apart so sinks that only need labels skip it.
+    pub annotations: Value,
+}
+
+impl EncodedSample {
+    /// Keys may not contain dots: WebDataset splits the extension off at the first one.
+    pub fn key_for(font: &str, index: usize) -> String {
+        format!("{}/{:06}", font.replace('.', "_"), index)
+    }
+
+    /// File extension of `image`, `jpg` unless the metadata names another encoding.
+    pub fn extension(&self) -> &str {
+        image_extension(&self.metadata)
+    }
+
+    /// Metadata and annotations as the single JSON document written next to the image.
+    pub fn record(&self) -> Value {
+        let mut record = self.metadata.clone();
+        if let (Some(record), Some(annotations)) =
+            (record.as_object_mut(), self.annotations.as_object())
+        {
+            for (key, value) in annotations {
+                record.insert(key.clone(), value.clone());
+            }
+        }
+        record
+    }
+}
+
+/// Image file extension recorded in a sample's metadata; samples written before the output
+/// encoding was configurable are JPEGs.
+pub fn image_extension(metadata: &Value) -> &str {
+    metadata["encoding"]["extension"].as_str().unwrap_or("jpg")
+}
+
+/// `[width, height]` of the encoded image; samples written before the output encoding could
+/// resize were saved at the viewport size.
+pub fn image_size(metadata: &Value) -> &Value {
+    match &metadata["encoding"]["size"] {
+        Value::Null => &metadata["viewport"],
+        size => size,
+    }
+}
+
+/// A destination for finished samples. `finish` is called once, after the last `write`,
+/// and returns a summary of what was written.
+pub trait OutputSink: Send + Sync {
+    fn write(&self, sample: Arc<EncodedSample>) -> BoxFuture<'_, Result<(), String>>;
+    fn finish(&self) -> BoxFuture<'_, Result<Value, String>>;
+}
+
+/// A format that appends samples to files on one thread, such as tar shards or Parquet.
+pub trait ArchiveWriter: Send {
+    fn push(&mut self, sample: Arc<EncodedSample>) -> Result<(), String>;
+    fn finish(self: Box<Self>) -> Result<Value, String>;
+}
+
+/// Outputs selected on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputSpec {
+    Folder,
+    Shards(ShardConfig),
+    Parquet {
+        row_group_rows: usize,
+    },
+    Records(RecordFormat),
+    /// `http://host:port/bucket[/prefix]` of an S3-compatible endpoint.
+    ObjectStore(String),
+}
+
+/// `{dir}/{font}/{index}.<ext>` and `.json`, the layout `gallery` and `export` read.
+pub struct FolderSink {
+    dir: String,
+    written: AtomicUsize,
+}
+
+impl FolderSink {
+    pub fn new(dir: &str) -> FolderSink {
+        FolderSink {
+            dir: dir.to_string(),
+            written: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl OutputSink for FolderSink {
+    fn write(&self, sample: Arc<EncodedSample>) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let folder = format!("{}/{}", self.dir, sample.font);
+            tokio::fs::create_dir_all(&folder)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", folder, e))?;
+            let output_image = format!("{}/{}.{}", folder, sample.index, sample.extension());
+            tokio::fs::write(&output_image, &sample.image)
+                .await
+                .map_err(|e| format!("Failed to write image file {}: {}", output_image, e))?;
+            let output_metadata = format!("{}/{}.json", folder, sample.index);
+            tokio::fs::write(&output_metadata, sample.record().to_string())
+                .await
+                .map_err(|e| format!("Failed to write metadata file {}: {}", output_metadata, e))?;
+            self.written.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        })
+    }
+
+    fn finish(&self) -> BoxFuture<'_, Result<Value, String>> {
+        let written = self.written.load(Ordering::Relaxed);
+        Box::pin(
+            async move { Ok(json!({ "sink": "folder", "dir": self.dir, "samples": written })) },
+        )
+    }
+}
+
+/// Hands samples to a bounded channel. `write` waits while the channel is full, so whoever
+/// drains it sets the pace, and fails once the receiver is gone.
+pub struct ChannelSink {
+    tx: mpsc::Sender<Arc<EncodedSample>>,
+}
+
+impl ChannelSink {
+    pub fn new(capacity: usize) -> (ChannelSink, mpsc::Receiver<Arc<EncodedSample>>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (ChannelSink { tx }, rx)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+impl OutputSink for ChannelSink {
+    fn write(&self, sample: Arc<EncodedSample>) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            self.tx
+                .send(sample)
+                .await
+                .map_err(|_| "Sample receiver is gone".to_string())
+        })
+    }
+
+    fn finish(&self) -> BoxFuture<'_, Result<Value, String>> {
+        Box::pin(async move { Ok(json!({ "sink": "channel" })) })
+    }
+}
+
+/// Runs an `ArchiveWriter` on its own blocking thread, fed by the render workers over a
+/// channel so slow disks never stall rendering beyond the channel's capacity.
+pub struct ArchiveSink {
+    tx: Mutex<Option<mpsc::Sender<Arc<EncodedSample>>>>,
+    handle: Mutex<Option<JoinHandle<Result<Value, String>>>>,
+}
+
+impl ArchiveSink {
+    pub fn spawn(mut writer: Box<dyn ArchiveWriter>) -> ArchiveSink {
+        let (tx, mut rx) = mpsc::channel::<Arc<EncodedSample>>(CHANNEL_CAPACITY);
+        let handle = tokio::task::spawn_blocking(move || {
+            while let Some(sample) = rx.blocking_recv() {
+                writer.push(sample)?;
+            }
+            writer.finish()
+        });
+        ArchiveSink {
+            tx: Mutex::new(Some(tx)),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+}
+
+impl OutputSink for ArchiveSink {
+    fn write(&self, sample: Arc<EncodedSample>) -> BoxFuture<'_, Result<(), String>> {
+        let tx = self.tx.lock().unwrap().clone();
+        Box::pin(async move {
+            let tx = tx.ok_or("Archive writer already finished")?;
+            tx.send(sample)
+                .await
+                .map_err(|_| "Archive writer stopped".to_string())
+        })
+    }
+
+    fn finish(&self) -> BoxFuture<'_, Result<Value, String>> {
+        // Dropping the sender ends the writer loop once the queued samples are written.
+        drop(self.tx.lock().unwrap().take());
+        let handle = self.handle.lock().unwrap().take();
+        Box::pin(async move {
+            let handle = handle.ok_or("Archive writer already finished")?;
+            handle.await.map_err(|e| e.to_string())?
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .to_vec()
+}
+
+/// Percent-encodes an object path, keeping `/` and the unreserved characters.
+fn uri_encode(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// `YYYYMMDDTHHMMSSZ` for a Unix time, as signature version 4 wants it.
+fn amz_date(seconds: u64) -> String {
+    let days = (seconds / 86_400) as i64;
+    let time = seconds % 86_400;
+    // Civil-from-days, after Howard Hinnant's date algorithms.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    region: String,
+}
+
+/// Uploads every sample as `<prefix>/<font>/<index>.<ext>` and `.json` with plain HTTP PUTs,
+/// signed with AWS signature version 4 when `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+/// are set. Meant for a MinIO or similar endpoint on the local network; there is no TLS.
+pub struct ObjectStoreSink {
+    host: String,
+    prefix: String,
+    credentials: Option<Credentials>,
+    uploaded: AtomicUsize,
+}
+
+impl ObjectStoreSink {
+    pub fn new(url: &str) -> Result<ObjectStoreSink, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Object store URL must start with http://: {}", url))?;
+        let (host, prefix) = rest
+            .split_once('/')
+            .filter(|(_, prefix)| !prefix.is_empty())
+            .ok_or_else(|| format!("Object store URL needs a bucket: {}", url))?;
+        let credentials = match (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key), Ok(secret_key)) => Some(Credentials {
+                access_key,
+                secret_key,
+                region: std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            }),
+            _ => None,
+        };
+        Ok(ObjectStoreSink {
+            host: host.to_string(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+            credentials,
+            uploaded: AtomicUsize::new(0),
+        })
+    }
+
+    fn authorization(&self, path: &str, payload_hash: &str, date: &str) -> Option<String> {
+        let credentials = self.credentials.as_ref()?;
+        let headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            path, self.host, payload_hash, date, headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", &date[..8], credentials.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            date,
+            scope,
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let mut key = format!("AWS4{}", credentials.secret_key).into_bytes();
+        for part in [&date[..8], &credentials.region, "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        Some(format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key,
+            scope,
+            headers,
+            hex(&hmac_sha256(&key, to_sign.as_bytes()))
+        ))
+    }
+
+    async fn put(&self, key: &str, content_type: &str, body: &[u8]) -> Result<(), String> {
+        let path = uri_encode(&format!("/{}/{}", self.prefix, key));
+        let payload_hash = hex(&Sha256::digest(body));
+        let date = amz_date(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        );
+        let mut request = format!(
+            "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nx-amz-content-sha256: {}\r\nx-amz-date: {}\r\nConnection: close\r\n",
+            path,
+            self.host,
+            content_type,
+            body.len(),
+            payload_hash,
+            date
+        );
+        if let Some(authorization) = self.authorization(&path, &payload_hash, &date) {
+            request.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+
+        let fail = |e: std::io::Error| format!("Failed to upload {}: {}", path, e);
+        let mut stream = TcpStream::connect(&self.host).await.map_err(fail)?;
+        stream.write_all(request.as_bytes()).await.map_err(fail)?;
+        stream.write_all(body).await.map_err(fail)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.map_err(fail)?;
+
+        let response = String::from_utf8_lossy(&response);
+        let status = response.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            let line = response.lines().next().unwrap_or_default();
+            return Err(format!("Upload of {} failed: {}", path, line));
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for ObjectStoreSink {
+    fn write(&self, sample: Arc<EncodedSample>) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let base = format!("{}/{}", sample.font, sample.index);
+            let extension = sample.extension();
+            self.put(
+                &format!("{}.{}", base, extension),
+                mime_type(extension),
+                &sample.image,
+            )
+            .await?;
+            let record = sample.record().to_string();
+            self.put(
+                &format!("{}.json", base),
+                "application/json",
+                record.as_bytes(),
+            )
+            .await?;
+            self.uploaded.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        })
+    }
+
+    fn finish(&self) -> BoxFuture<'_, Result<Value, String>> {
+        let uploaded = self.uploaded.load(Ordering::Relaxed);
+        Box::pin(async move {
+            Ok(json!({
+                "sink": "object_store",
+                "endpoint": format!("http://{}/{}", self.host, self.prefix),
+                "samples": uploaded,
+            }))
+        })
+    }
+}
+
+/// Writes every sample to all of its sinks.
+pub struct TeeSink(pub Vec<Box<dyn OutputSink>>);
+
+impl OutputSink for TeeSink {
+    fn write(&self, sample: Arc<EncodedSample>) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let results = join_all(self.0.iter().map(|sink| sink.write(Arc::clone(&sample)))).await;
+            results.into_iter().collect()
+        })
+    }
+
+    /// Finishes every sink even when one fails, so the others still flush and close; the
+    /// errors are reported together.
+    fn finish(&self) -> BoxFuture<'_, Result<Value, String>> {
+        Box::pin(async move {
+            let mut summaries = Vec::new();
+            let mut errors = Vec::new();
+            for sink in &self.0 {
+                match sink.finish().await {
+                    Ok(summary) => summaries.push(summary),
+                    Err(e) => errors.push(e),
+                }
+            }
+            if errors.is_empty() {
+                Ok(Value::Array(summaries))
+            } else {
+                Err(errors.join("; "))
+            }
+        })
+    }
+}
+
+/// Builds the sink for the selected outputs under `dir`; more than one becomes a tee.
+pub fn open_sink(
+    specs: &[OutputSpec],
+    dir: &str,
+    class_names: Vec<String>,
+) -> Result<Box<dyn OutputSink>, String> {
+    let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+    for spec in specs {
+        let sink: Box<dyn OutputSink> =
+            match spec {
+                OutputSpec::Folder => Box::new(FolderSink::new(dir)),
+                OutputSpec::Shards(config) => Box::new(ArchiveSink::spawn(Box::new(
+                    ShardSet::new(&format!("{}/shards", dir), config.clone())?,
+                ))),
+                OutputSpec::Parquet { row_group_rows } => {
+                    let config = ParquetConfig {
+                        row_group_rows: *row_group_rows,
+                        class_names: class_names.clone(),
+                    };
+                    Box::new(ArchiveSink::spawn(Box::new(ParquetSet::new(
+                        &format!("{}/parquet", dir),
+                        config,
+                    )?)))
+                }
+                OutputSpec::Records(format) => Box::new(ArchiveSink::spawn(Box::new(
+                    RecordSet::new(&format!("{}/{}", dir, format.name()), *format)?,
+                ))),
+                OutputSpec::ObjectStore(url) => Box::new(ObjectStoreSink::new(url)?),
+            };
+        sinks.push(sink);
+    }
+    match sinks.len() {
+        1 => Ok(sinks.pop().unwrap()),
+        _ => Ok(Box::new(TeeSink(sinks))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // A stand-in for an S3 endpoint: answers every PUT with 200 and reports what it received.
+    async fn stand_in() -> (String, mpsc::Receiver<(String, usize)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                let (head, body) = loop {
+                    let n = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, _)) = text.split_once("\r\n\r\n") {
+                        let length: usize = head
+                            .lines()
+                            .find_map(|l| l.strip_prefix("Content-Length: "))
+                            .unwrap()
+                            .parse()
+                            .unwrap();
+                        if request.len() >= head.len() + 4 + length {
+                            break (head.to_string(), length);
+                        }
+                    }
+                };
+                let line = head.lines().next().unwrap().to_string();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+                drop(stream);
+                tx.send((line, body)).await.unwrap();
+            }
+        });
+        (address, rx)
+    }
+
+    #[tokio::test]
+    async fn tee_writes_the_folder_layout_and_uploads() {
+        let (address, mut uploads) = stand_in().await;
+        let dir = std::env::temp_dir().join(format!("sinks-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let specs = [
+            OutputSpec::Folder,
+            OutputSpec::ObjectStore(format!("http://{}/bucket/run", address)),
+        ];
+        let sink = open_sink(&specs, dir, Vec::new()).unwrap();
+
+        let sample = EncodedSample {
+            key: EncodedSample::key_for("وزیر", 7),
+            font: "وزیر".to_string(),
+            index: 7,
+            image: vec![0xff, 0xd8, 0xff],
+            metadata: json!({ "index": 7 }),
+            annotations: json!({ "text_boxes": [[1, 2, 3, 4]] }),
+        };
+        sink.write(Arc::new(sample)).await.unwrap();
+        let summary = sink.finish().await.unwrap();
+        assert_eq!(summary[1]["samples"], 1);
+
+        let record: Value =
+            serde_json::from_str(&std::fs::read_to_string(format!("{}/وزیر/7.json", dir)).unwrap())
+                .unwrap();
+        assert_eq!(record["text_boxes"][0][2], 3);
+
+        let (line, length) = uploads.recv().await.unwrap();
+        assert_eq!(
+            line,
+            "PUT /bucket/run/%D9%88%D8%B2%DB%8C%D8%B1/7.jpg HTTP/1.1"
+        );
+        assert_eq!(length, 3);
+        assert!(uploads.recv().await.unwrap().0.contains("/7.json"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Counts finishes and fails them when `fails` is set.
+    struct Probe {
+        fails: bool,
+        finished: Arc<AtomicUsize>,
+    }
+
+    impl OutputSink for Probe {
+        fn write(&self, _: Arc<EncodedSample>) -> BoxFuture<'_, Result<(), String>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn finish(&self) -> BoxFuture<'_, Result<Value, String>> {
+            self.finished.fetch_add(1, Ordering::Relaxed);
+            let fails = self.fails;
+            Box::pin(async move {
+                if fails {
+                    Err("disk full".to_string())
+                } else {
+                    Ok(json!({}))
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn tee_finishes_every_sink_before_reporting_errors() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        let probe = |fails| -> Box<dyn OutputSink> {
+            Box::new(Probe {
+                fails,
+                finished: Arc::clone(&finished),
+            })
+        };
+        let tee = TeeSink(vec![probe(true), probe(false), probe(true)]);
+        assert_eq!(tee.finish().await.unwrap_err(), "disk full; disk full");
+        assert_eq!(finished.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn signing_helpers_match_reference_values() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(amz_date(1_369_353_600), "20130524T000000Z");
+        assert_eq!(amz_date(951_782_400 + 3661), "20000229T010101Z");
+    }
+}
@@ -0,0 +1,278 @@
+use colored::*;
+use headless_chrome::protocol::cdp::Page::CaptureScreenshotFormatOption;
+use headless_chrome::Tab;
+use image::{imageops, DynamicImage};
+use serde_json::json;
+
+use std::error::Error;
+use std::fs;
+
+use crate::fonts::FontFile;
+use crate::layout::{check_layout, FitPolicy};
+use crate::styles::DUAL_JOINING;
+use crate::{load_page, render_simple_pages};
+
+const SPECIMEN_DIR: &str = "./specimens";
+const SPECIMEN_VIEWPORT: (u32, u32) = (1200, 1400);
+
+const ATLAS_COLUMNS: usize = 12;
+const ATLAS_CELL: u32 = 96;
+const ATLAS_GLYPH_SIZE: u32 = 56;
+
+const ALPHABET: &str = "اآبپتثجچحخدذرزژسشصضطظعغفقکگلمنوهی";
+const DIGITS: [&str; 3] = ["۰۱۲۳۴۵۶۷۸۹", "٠١٢٣٤٥٦٧٨٩", "0123456789"];
+const PUNCTUATION: &str = "، ؛ ؟ « » ( ) [ ] ! . : / - ـ ٪ ٫";
+const SAMPLE_WORDS: &str = "خوشنویسی فارسی با حروف پیوسته و ظریف نوشته می‌شود";
+const SAMPLE_SIZES: [u32; 6] = [12, 16, 24, 36, 48, 72];
+
+const ZWJ: char = '\u{200D}';
+
+// Bounding boxes of every `.glyph-cell`, once the page's fonts have loaded.
+const CELL_BOXES_JS: &str = r#"
+JSON.stringify([...document.querySelectorAll('.glyph-cell')].map((cell) => {
+    const r = cell.getBoundingClientRect();
+    return [r.left, r.top, r.width, r.height];
+}))
+"#;
+
+/// One atlas entry: a character in one contextual form.
+#[derive(Debug, Clone, PartialEq)]
+struct Glyph {
+    character: char,
+    form: &'static str,
+    /// The character with zero-width joiners around it to force the form.
+    text: String,
+}
+
+impl Glyph {
+    fn label(&self) -> String {
+        format!("U+{:04X}_{}", self.character as u32, self.form)
+    }
+}
+
+/// Every letter in each form the shaper can produce for it, then the digits, isolated.
+/// Zero-width joiners on either side select the initial, medial and final forms.
+fn atlas_glyphs() -> Vec<Glyph> {
+    let mut glyphs = Vec::new();
+    for c in ALPHABET.chars() {
+        let forms: &[&'static str] = if DUAL_JOINING.contains(c) {
+            &["isolated", "initial", "medial", "final"]
+        } else {
+            &["isolated", "final"]
+        };
+        for &form in forms {
+            let text = match form {
+                "initial" => format!("{}{}", c, ZWJ),
+                "medial" => format!("{}{}{}", ZWJ, c, ZWJ),
+                "final" => format!("{}{}", ZWJ, c),
+                _ => c.to_string(),
+            };
+            glyphs.push(Glyph {
+                character: c,
+                form,
+                text,
+            });
+        }
+    }
+    for c in DIGITS.iter().flat_map(|digits| digits.chars()) {
+        glyphs.push(Glyph {
+            character: c,
+            form: "isolated",
+            text: c.to_string(),
+        });
+    }
+    glyphs
+}
+
+fn specimen_markup() -> String {
+    let section = |title: &str, body: &str, size: u32| {
+        format!(
+            "<div style=\"font-size: 14px; font-family: sans-serif; color: #666; margin-top: 16px;\">{}</div><div style=\"font-size: {}px; line-height: 1.6;\">{}</div>",
+            title, size, body
+        )
+    };
+    let letters: Vec<String> = ALPHABET.chars().map(String::from).collect();
+    let mut markup = String::from("<div style=\"width: 1100px; text-align: right;\">");
+    markup.push_str(&section("الفبا", &letters.join(" "), 40));
+    markup.push_str(&section("ارقام", &DIGITS.join(" "), 36));
+    markup.push_str(&section("نشانه‌ها", PUNCTUATION, 36));
+    for size in SAMPLE_SIZES {
+        markup.push_str(&section(&format!("{}px", size), SAMPLE_WORDS, size));
+    }
+    markup.push_str("</div>");
+    markup
+}
+
+fn atlas_markup(glyphs: &[Glyph]) -> String {
+    let cells: String = glyphs
+        .iter()
+        .map(|glyph| {
+            format!(
+                "<span class=\"glyph-cell\" style=\"display: inline-block; width: {cell}px; height: {cell}px; line-height: {cell}px; font-size: {size}px; text-align: center; overflow: hidden;\">{}</span>",
+                glyph.text,
+                cell = ATLAS_CELL,
+                size = ATLAS_GLYPH_SIZE
+            )
+        })
+        .collect();
+    format!(
+        "<div style=\"width: {}px; line-height: 0;\">{}</div>",
+        ATLAS_COLUMNS as u32 * ATLAS_CELL,
+        cells
+    )
+}
+
+fn atlas_viewport(glyphs: usize) -> (u32, u32) {
+    let rows = glyphs.div_ceil(ATLAS_COLUMNS) as u32;
+    (
+        ATLAS_COLUMNS as u32 * ATLAS_CELL + 64,
+        rows * ATLAS_CELL + 64,
+    )
+}
+
+fn capture(tab: &Tab, html: &str, (width, height): (u32, u32)) -> Result<Vec<u8>, Box<dyn Error>> {
+    load_page(tab, html, width, height)?;
+    // Waits for the fonts; the reject policy measures without resizing anything.
+    check_layout(tab, FitPolicy::Reject)?;
+    let png = tab
+        .capture_screenshot(CaptureScreenshotFormatOption::Png, None, None, true)
+        .map_err(|e| format!("Failed to capture screenshot: {}", e))?;
+    Ok(png)
+}
+
+fn cell_boxes(tab: &Tab) -> Result<Vec<[f64; 4]>, Box<dyn Error>> {
+    let result = tab
+        .evaluate(CELL_BOXES_JS, false)
+        .map_err(|e| format!("Failed to measure glyph cells: {}", e))?;
+    let raw = result
+        .value
+        .as_ref()
+        .and_then(|v| v.as_str())
+        .ok_or("Glyph cell measurement returned no value")?;
+    Ok(serde_json::from_str(raw)?)
+}
+
+/// Writes `specimen.png`, `atlas.png`, `atlas.json` and one PNG per glyph for a class.
+fn render_class(
+    tab: &Tab,
+    class: &str,
+    specimen_html: &str,
+    atlas_html: &str,
+    glyphs: &[Glyph],
+) -> Result<(), Box<dyn Error>> {
+    let dir = format!("{}/{}", SPECIMEN_DIR, class);
+    fs::create_dir_all(format!("{}/glyphs", dir))?;
+
+    let specimen = capture(tab, specimen_html, SPECIMEN_VIEWPORT)?;
+    fs::write(format!("{}/specimen.png", dir), specimen)?;
+
+    let atlas = capture(tab, atlas_html, atlas_viewport(glyphs.len()))?;
+    let boxes = cell_boxes(tab)?;
+    if boxes.len() != glyphs.len() {
+        return Err(format!(
+            "expected {} glyph cells, found {}",
+            glyphs.len(),
+            boxes.len()
+        )
+        .into());
+    }
+    fs::write(format!("{}/atlas.png", dir), &atlas)?;
+
+    let image = image::load_from_memory(&atlas)?;
+    let mut entries = Vec::new();
+    for (glyph, [x, y, width, height]) in glyphs.iter().zip(boxes) {
+        let cell = imageops::crop_imm(
+            &image,
+            x.max(0.0) as u32,
+            y.max(0.0) as u32,
+            width as u32,
+            height as u32,
+        )
+        .to_image();
+        let file = format!("glyphs/{}.png", glyph.label());
+        DynamicImage::ImageRgba8(cell).save(format!("{}/{}", dir, file))?;
+        entries.push(json!({
+            "label": glyph.label(),
+            "character": glyph.character.to_string(),
+            "form": glyph.form,
+            "box": [x, y, width, height],
+            "file": file,
+        }));
+    }
+    let index = json!({ "class": class, "cell_size": ATLAS_CELL, "glyphs": entries });
+    fs::write(
+        format!("{}/atlas.json", dir),
+        serde_json::to_string_pretty(&index)?,
+    )?;
+    Ok(())
+}
+
+// The face that best stands for a class in its specimen: upright, closest to regular weight.
+fn representative(fonts: &[FontFile]) -> Option<&FontFile> {
+    fonts
+        .iter()
+        .min_by_key(|font| (font.names.italic, font.names.weight.abs_diff(400)))
+}
+
+/// `specimen`: a specimen page and a glyph atlas per class under `SPECIMEN_DIR`, rendered
+/// with the simple style so every class is set the same way.
+pub async fn run_specimens() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let glyphs = atlas_glyphs();
+    let specimen = specimen_markup();
+    let atlas = atlas_markup(&glyphs);
+
+    let rendered = render_simple_pages(
+        &[&specimen, &atlas],
+        |files| representative(files).into_iter().collect(),
+        move |tab, face| {
+            render_class(tab, &face.class, &face.html[0], &face.html[1], &glyphs)
+                .map_err(|e| e.to_string())
+        },
+    )
+    .await?;
+
+    let mut count = 0;
+    for (face, result) in rendered {
+        match result {
+            Ok(()) => count += 1,
+            Err(e) => eprintln!("Could not render specimen for {}: {}", face.class, e),
+        }
+    }
+
+    println!(
+        "{} {} specimens and glyph atlases in {}",
+        "Rendered".green(),
+        count,
+        SPECIMEN_DIR
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atlas_covers_contextual_forms_by_joining_type() {
+        let glyphs = atlas_glyphs();
+        let forms = |c: char| -> Vec<&str> {
+            glyphs
+                .iter()
+                .filter(|g| g.character == c)
+                .map(|g| g.form)
+                .collect()
+        };
+        assert_eq!(forms('ب'), ["isolated", "initial", "medial", "final"]);
+        assert_eq!(forms('ر'), ["isolated", "final"]);
+        assert_eq!(forms('۷'), ["isolated"]);
+
+        let medial = glyphs
+            .iter()
+            .find(|g| g.label() == "U+0628_medial")
+            .unwrap();
+        assert_eq!(medial.text, "\u{200D}ب\u{200D}");
+
+        let labels: std::collections::HashSet<String> = glyphs.iter().map(Glyph::label).collect();
+        assert_eq!(labels.len(), glyphs.len());
+    }
+}
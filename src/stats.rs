@@ -0,0 +1,480 @@
+use colored::*;
+use image::{Rgb, RgbImage};
+use serde_json::Value;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+
+use crate::manifest::{is_rejected, read_manifest};
+use crate::OUTPUT_DIR;
+
+// `stats`: what a run actually drew, read from its manifest. Every scalar under the fields
+// below of each sample's metadata is a parameter; numbers are binned into histograms,
+// everything else is counted by value. `summary.csv` and `histograms.csv` hold one table per
+// parameter for the whole run (scope `all`) and for every font; `charts/<parameter>.png`
+// draws the run's histogram on top and one strip per font under it, all on the run's bins.
+
+const STATS_DIR: &str = "stats";
+const STAT_FIELDS: [&str; 6] = [
+    "style",
+    "layout",
+    "viewport",
+    "jpeg_quality",
+    "synthetic",
+    "scan_profile",
+];
+// Recorded per face rather than drawn, and the composition slots are per-block arrays.
+const SKIPPED_PATHS: [&str; 3] = [
+    "style.font_file",
+    "style.composition",
+    "style.span_typography",
+];
+// Arrays longer than this (quads, slot lists) are not parameters.
+const MAX_ARRAY: usize = 4;
+const MAX_BINS: usize = 20;
+
+const CHART_BAR: u32 = 14;
+const CHART_GAP: u32 = 2;
+const CHART_MARGIN: u32 = 8;
+const CHART_RUN_HEIGHT: u32 = 120;
+const CHART_FONT_HEIGHT: u32 = 36;
+
+/// The sampled parameters of one sample's metadata, by dotted path (`style.params.rotate`,
+/// `viewport.0`).
+pub fn style_parameters(sample: &Value) -> BTreeMap<String, Value> {
+    let mut parameters = BTreeMap::new();
+    for field in STAT_FIELDS {
+        flatten(field, &sample[field], &mut parameters);
+    }
+    parameters
+}
+
+fn flatten(path: &str, value: &Value, out: &mut BTreeMap<String, Value>) {
+    if SKIPPED_PATHS.contains(&path) {
+        return;
+    }
+    match value {
+        Value::Null => {}
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten(&format!("{}.{}", path, key), value, out);
+            }
+        }
+        Value::Array(items) => {
+            if items.len() <= MAX_ARRAY
+                && items
+                    .iter()
+                    .all(|item| !item.is_array() && !item.is_object())
+            {
+                for (i, item) in items.iter().enumerate() {
+                    flatten(&format!("{}.{}", path, i), item, out);
+                }
+            }
+        }
+        _ => {
+            out.insert(path.to_string(), value.clone());
+        }
+    }
+}
+
+/// Text form of a categorical value.
+pub fn category(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// How a parameter's values are grouped: equal-width bins over the run's range for numbers,
+/// one bin per value otherwise.
+#[derive(Debug, PartialEq)]
+pub enum Bins {
+    Numeric { min: f64, max: f64, count: usize },
+    Categorical(Vec<String>),
+}
+
+impl Bins {
+    pub fn of(values: &[&Value]) -> Bins {
+        let numbers: Option<Vec<f64>> = values.iter().map(|value| value.as_f64()).collect();
+        match numbers {
+            Some(numbers) if !numbers.is_empty() => {
+                let min = numbers.iter().cloned().fold(f64::INFINITY, f64::min);
+                let max = numbers.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                let distinct: BTreeSet<u64> = numbers.iter().map(|n| n.to_bits()).collect();
+                Bins::Numeric {
+                    min,
+                    max,
+                    count: distinct.len().min(MAX_BINS),
+                }
+            }
+            _ => {
+                // Most common first.
+                let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+                for value in values {
+                    *counts.entry(category(value)).or_default() += 1;
+                }
+                let mut categories: Vec<(String, usize)> = counts.into_iter().collect();
+                categories.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                Bins::Categorical(categories.into_iter().map(|(name, _)| name).collect())
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Bins::Numeric { count, .. } => *count,
+            Bins::Categorical(categories) => categories.len(),
+        }
+    }
+
+    pub fn index(&self, value: &Value) -> Option<usize> {
+        match self {
+            Bins::Numeric { min, max, count } => {
+                let number = value.as_f64()?;
+                if max <= min {
+                    return Some(0);
+                }
+                let bin = ((number - min) / (max - min) * *count as f64).floor() as usize;
+                Some(bin.min(count - 1))
+            }
+            Bins::Categorical(categories) => {
+                let name = category(value);
+                categories.iter().position(|c| *c == name)
+            }
+        }
+    }
+
+    /// Lower and upper edge of a numeric bin, or the value of a categorical one.
+    fn label(&self, bin: usize) -> (String, String) {
+        match self {
+            Bins::Numeric { min, max, count } => {
+                let width = (max - min) / *count as f64;
+                (
+                    format!("{}", min + width * bin as f64),
+                    format!("{}", min + width * (bin + 1) as f64),
+                )
+            }
+            Bins::Categorical(categories) => (categories[bin].clone(), String::new()),
+        }
+    }
+
+    pub fn histogram(&self, values: &[&Value]) -> Vec<usize> {
+        let mut counts = vec![0; self.len()];
+        for value in values {
+            if let Some(bin) = self.index(value) {
+                counts[bin] += 1;
+            }
+        }
+        counts
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn csv_line(fields: &[String]) -> String {
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    fields.join(",") + "\n"
+}
+
+const SUMMARY_HEADER: [&str; 12] = [
+    "scope",
+    "parameter",
+    "kind",
+    "count",
+    "missing",
+    "distinct",
+    "min",
+    "max",
+    "mean",
+    "std",
+    "top",
+    "varied",
+];
+
+/// One summary row. `missing` counts the scope's samples without the parameter.
+fn summary_row(
+    scope: &str,
+    parameter: &str,
+    bins: &Bins,
+    values: &[&Value],
+    samples: usize,
+) -> Vec<String> {
+    let distinct: BTreeSet<String> = values.iter().map(|value| category(value)).collect();
+    let mut row = vec![
+        scope.to_string(),
+        parameter.to_string(),
+        match bins {
+            Bins::Numeric { .. } => "numeric".to_string(),
+            Bins::Categorical(_) => "categorical".to_string(),
+        },
+        values.len().to_string(),
+        (samples - values.len()).to_string(),
+        distinct.len().to_string(),
+    ];
+    let numbers: Vec<f64> = values.iter().filter_map(|value| value.as_f64()).collect();
+    if matches!(bins, Bins::Numeric { .. }) && !numbers.is_empty() {
+        let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+        let variance =
+            numbers.iter().map(|n| (n - mean).powi(2)).sum::<f64>() / numbers.len() as f64;
+        row.extend([
+            format!("{}", numbers.iter().cloned().fold(f64::INFINITY, f64::min)),
+            format!(
+                "{}",
+                numbers.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+            ),
+            format!("{:.4}", mean),
+            format!("{:.4}", variance.sqrt()),
+            String::new(),
+        ]);
+    } else {
+        let histogram = bins.histogram(values);
+        let top = histogram
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, count)| (**count, std::cmp::Reverse(*i)))
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| {
+                format!(
+                    "{} ({:.1}%)",
+                    bins.label(i).0,
+                    100.0 * *count as f64 / values.len() as f64
+                )
+            })
+            .unwrap_or_default();
+        row.extend([
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            top,
+        ]);
+    }
+    row.push((distinct.len() > 1).to_string());
+    row
+}
+
+/// The run's histogram on top, then one strip per font, each scaled to its own tallest bar.
+fn chart(run: &[usize], fonts: &[Vec<usize>]) -> RgbImage {
+    let bins = run.len().max(1) as u32;
+    let width = CHART_MARGIN * 2 + bins * (CHART_BAR + CHART_GAP);
+    let height =
+        CHART_MARGIN * 2 + CHART_RUN_HEIGHT + fonts.len() as u32 * (CHART_FONT_HEIGHT + CHART_GAP);
+    let mut image = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+
+    let mut draw_row = |counts: &[usize], top: u32, row_height: u32, color: Rgb<u8>| {
+        for x in CHART_MARGIN..width - CHART_MARGIN {
+            image.put_pixel(x, top + row_height - 1, Rgb([200, 200, 200]));
+        }
+        let tallest = counts.iter().copied().max().unwrap_or(0).max(1);
+        for (bin, count) in counts.iter().enumerate() {
+            let bar = (*count as f64 / tallest as f64 * (row_height - 2) as f64).round() as u32;
+            let left = CHART_MARGIN + bin as u32 * (CHART_BAR + CHART_GAP);
+            for x in left..left + CHART_BAR {
+                for y in top + row_height - 1 - bar..top + row_height - 1 {
+                    image.put_pixel(x, y, color);
+                }
+            }
+        }
+    };
+    draw_row(run, CHART_MARGIN, CHART_RUN_HEIGHT, Rgb([52, 101, 164]));
+    for (i, counts) in fonts.iter().enumerate() {
+        let top = CHART_MARGIN
+            + CHART_RUN_HEIGHT
+            + CHART_GAP
+            + i as u32 * (CHART_FONT_HEIGHT + CHART_GAP);
+        draw_row(counts, top, CHART_FONT_HEIGHT, Rgb([114, 159, 207]));
+    }
+    image
+}
+
+fn file_name(parameter: &str) -> String {
+    parameter
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// `stats`: per-parameter summaries and histograms of the samples in `OUTPUT_DIR`, written to
+/// `OUTPUT_DIR/stats`. Parameters that took a single value over the whole run are listed.
+pub async fn run_stats() -> Result<(), Box<dyn Error + Send + Sync>> {
+    // The manifest is ordered by font, so each font's samples are one run of entries.
+    let mut by_font: Vec<(String, Vec<BTreeMap<String, Value>>)> = Vec::new();
+    let manifest = read_manifest(OUTPUT_DIR)?;
+    for sample in manifest.iter().filter(|sample| !is_rejected(sample)) {
+        let font = sample["font"].as_str().unwrap_or_default();
+        if by_font.last().is_none_or(|(last, _)| last != font) {
+            by_font.push((font.to_string(), Vec::new()));
+        }
+        by_font.last_mut().unwrap().1.push(style_parameters(sample));
+    }
+    let total: usize = by_font.iter().map(|(_, samples)| samples.len()).sum();
+    if total == 0 {
+        return Err(format!("No samples under {}", OUTPUT_DIR).into());
+    }
+
+    let stats_dir = format!("{}/{}", OUTPUT_DIR, STATS_DIR);
+    fs::create_dir_all(format!("{}/charts", stats_dir))?;
+    let names: BTreeSet<&String> = by_font
+        .iter()
+        .flat_map(|(_, samples)| samples.iter().flat_map(|sample| sample.keys()))
+        .collect();
+
+    let mut summary = csv_line(&SUMMARY_HEADER.map(str::to_string));
+    let mut histograms =
+        csv_line(&["scope", "parameter", "bin", "from", "to", "count"].map(str::to_string));
+    let mut constant = Vec::new();
+    for name in names {
+        let font_values: Vec<(&str, Vec<&Value>, usize)> = by_font
+            .iter()
+            .map(|(font, samples)| {
+                let values = samples
+                    .iter()
+                    .filter_map(|sample| sample.get(name))
+                    .collect();
+                (font.as_str(), values, samples.len())
+            })
+            .collect();
+        let run_values: Vec<&Value> = font_values
+            .iter()
+            .flat_map(|(_, values, _)| values.iter().copied())
+            .collect();
+        let bins = Bins::of(&run_values);
+
+        let scopes = std::iter::once(("all", &run_values, total)).chain(
+            font_values
+                .iter()
+                .map(|(font, values, samples)| (*font, values, *samples)),
+        );
+        let mut font_histograms = Vec::new();
+        for (scope, values, samples) in scopes {
+            let row = summary_row(scope, name, &bins, values, samples);
+            if scope == "all" && row[SUMMARY_HEADER.len() - 1] == "false" {
+                constant.push(format!(
+                    "{} = {}",
+                    name,
+                    run_values.first().map(|v| category(v)).unwrap_or_default()
+                ));
+            }
+            summary.push_str(&csv_line(&row));
+            let histogram = bins.histogram(values);
+            for (bin, count) in histogram.iter().enumerate() {
+                let (from, to) = bins.label(bin);
+                histograms.push_str(&csv_line(&[
+                    scope.to_string(),
+                    name.to_string(),
+                    bin.to_string(),
+                    from,
+                    to,
+                    count.to_string(),
+                ]));
+            }
+            if scope != "all" {
+                font_histograms.push(histogram);
+            }
+        }
+        let chart_path = format!("{}/charts/{}.png", stats_dir, file_name(name));
+        chart(&bins.histogram(&run_values), &font_histograms).save(&chart_path)?;
+    }
+    fs::write(format!("{}/summary.csv", stats_dir), summary)?;
+    fs::write(format!("{}/histograms.csv", stats_dir), histograms)?;
+
+    println!(
+        "{} {} samples of {} fonts: {}",
+        "Wrote style statistics for".green(),
+        total,
+        by_font.len(),
+        stats_dir
+    );
+    if !constant.is_empty() {
+        println!("{}", "Parameters that never varied:".yellow());
+        for parameter in constant {
+            println!("  {}", parameter);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parameters_flatten_and_bin_on_the_run_range() {
+        let sample = json!({
+            "font": "Vazir",
+            "phrase": "not a parameter",
+            "viewport": [640, 480],
+            "synthetic": false,
+            "layout": { "outcome": "fit", "font_size": 40.0 },
+            "style": {
+                "font_file": { "file": "Vazir.ttf" },
+                "params": { "rotate": 1.5, "shadow": true },
+                "composition": { "slots": [[0, 0, 10, 10]] },
+            },
+        });
+        let parameters = style_parameters(&sample);
+        assert_eq!(
+            parameters.keys().map(String::as_str).collect::<Vec<_>>(),
+            vec![
+                "layout.font_size",
+                "layout.outcome",
+                "style.params.rotate",
+                "style.params.shadow",
+                "synthetic",
+                "viewport.0",
+                "viewport.1"
+            ]
+        );
+
+        let (a, b, c, d) = (json!(0.0), json!(2.5), json!(10.0), json!(10.0));
+        let values = vec![&a, &b, &c, &d];
+        let bins = Bins::of(&values);
+        assert_eq!(
+            bins,
+            Bins::Numeric {
+                min: 0.0,
+                max: 10.0,
+                count: 3
+            }
+        );
+        assert_eq!(bins.histogram(&values), vec![2, 0, 2]);
+        let row = summary_row("all", "rotate", &bins, &values, 5);
+        assert_eq!(row[4], "1");
+        assert_eq!(row[5], "3");
+        assert_eq!(row[11], "true");
+
+        let (yes, no) = (json!(true), json!(false));
+        let flags = vec![&no, &yes, &no];
+        let bins = Bins::of(&flags);
+        assert_eq!(
+            bins,
+            Bins::Categorical(vec!["false".to_string(), "true".to_string()])
+        );
+        assert_eq!(
+            summary_row("Vazir", "shadow", &bins, &flags, 3)[10],
+            "false (66.7%)"
+        );
+        let same = summary_row("Vazir", "shadow", &bins, &[&yes, &yes], 2);
+        assert_eq!(same[11], "false");
+
+        let image = chart(&[3, 1], &[vec![1, 0], vec![2, 1]]);
+        assert_eq!(
+            image.height(),
+            CHART_MARGIN * 2 + CHART_RUN_HEIGHT + 2 * (CHART_FONT_HEIGHT + CHART_GAP)
+        );
+    }
+}
@@ -3,20 +3,33 @@ use std::sync::Arc;
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 use image::{DynamicImage, GenericImageView, ImageBuffer, ImageOutputFormat, Pixel, Rgb};
 use rand::seq::SliceRandom;
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
+use serde_json::{json, Map, Value};
 use std::io::Cursor;
 use tokio::task;
 
-type Color = (u8, u8, u8);
+use crate::fonts::{FontFile, Typography};
+use crate::profiles::{StyleProfile, StyleSampler};
+use crate::scene::generate_scene_styles;
+use crate::seeding::task_rng;
+
+pub(crate) type Color = (u8, u8, u8);
 
 const IMAGE_MINIMUM_DIMENSION: u32 = 350;
+// Draws of the synthetic variant before giving up on getting at least one effect.
+const SYNTHETIC_ATTEMPTS: usize = 64;
 
-fn random_color() -> Color {
-    let mut rng = thread_rng();
+pub(crate) fn random_color() -> Color {
+    let mut rng = task_rng();
     (rng.gen(), rng.gen(), rng.gen())
 }
 
+// Colours are recorded as `[r, g, b]`, so stats can histogram each channel.
+fn color_json(color: &Color) -> Value {
+    json!([color.0, color.1, color.2])
+}
+
 fn calc_mean_color(c1: &Color, c2: &Color) -> Color {
     (
         ((c1.0 as u16 + c2.0 as u16) / 2) as u8,
@@ -64,7 +77,7 @@ fn contrast_ratio(rgb1: &Color, rgb2: &Color) -> f64 {
     (lighter + 0.05) / (darker + 0.05)
 }
 
-fn ensure_wcag_contrast(bg_color: &Color, text_color: &Color, ratio: &f64) -> bool {
+pub(crate) fn ensure_wcag_contrast(bg_color: &Color, text_color: &Color, ratio: &f64) -> bool {
     contrast_ratio(bg_color, text_color) >= *ratio
 }
 
@@ -91,12 +104,12 @@ fn calc_mean_image(buffer: &[u8]) -> Result<Color, String> {
 }
 
 fn generate_noise_image() -> Result<String, String> {
-    let width = thread_rng().gen_range(100..=1000);
-    let height = thread_rng().gen_range(100..=1000);
-    let noise_level = thread_rng().gen_range(0.1..=0.9);
+    let width = task_rng().gen_range(100..=1000);
+    let height = task_rng().gen_range(100..=1000);
+    let noise_level = task_rng().gen_range(0.1..=0.9);
 
     let img = ImageBuffer::from_fn(width, height, |_, _| {
-        let noise = || (thread_rng().gen::<f32>() * 255.0 * noise_level) as u8;
+        let noise = || (task_rng().gen::<f32>() * 255.0 * noise_level) as u8;
         Rgb([noise(), noise(), noise()])
     });
 
@@ -110,10 +123,8 @@ fn generate_noise_image() -> Result<String, String> {
     ))
 }
 
-async fn select_image(
-    images: &Vec<Arc<Vec<u8>>>,
-) -> Result<(image::DynamicImage, u32, u32), String> {
-    let buffer = images.choose(&mut thread_rng()).unwrap().clone();
+async fn select_image(images: &[Arc<Vec<u8>>]) -> Result<(image::DynamicImage, u32, u32), String> {
+    let buffer = images.choose(&mut task_rng()).unwrap().clone();
 
     let image_result = task::spawn_blocking(move || image::load_from_memory(&buffer))
         .await
@@ -126,9 +137,14 @@ async fn select_image(
     Ok((img, width, height))
 }
 
-async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String, String), String> {
-    let use_image_bg = thread_rng().gen_bool(0.5);
-    let use_overlay = thread_rng().gen_bool(0.3);
+/// Returns the background declarations, a text colour that contrasts with them, and the kind
+/// of background chosen (`image`, `image_overlay`, `gradient` or `solid`).
+async fn generate_background_style(
+    images: &[Arc<Vec<u8>>],
+    sampler: &mut StyleSampler<'_>,
+) -> Result<(String, String, &'static str), String> {
+    let use_image_bg = sampler.enabled("image_background");
+    let use_overlay = sampler.enabled("image_overlay");
 
     if use_image_bg {
         let mut img: DynamicImage;
@@ -143,15 +159,15 @@ async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String
         while width <= IMAGE_MINIMUM_DIMENSION || height <= IMAGE_MINIMUM_DIMENSION
         // && attempts < max_attempts
         {
-            (img, width, height) = select_image(&images).await?;
+            (img, width, height) = select_image(images).await?;
             // attempts += 1;
         }
 
-        let crop_width = thread_rng().gen_range(IMAGE_MINIMUM_DIMENSION..=width.min(1500));
-        let crop_height = thread_rng().gen_range(IMAGE_MINIMUM_DIMENSION..=height.min(1500));
+        let crop_width = task_rng().gen_range(IMAGE_MINIMUM_DIMENSION..=width.min(1500));
+        let crop_height = task_rng().gen_range(IMAGE_MINIMUM_DIMENSION..=height.min(1500));
 
-        let left = thread_rng().gen_range(0..(width - crop_width + 1));
-        let top = thread_rng().gen_range(0..(height - crop_height + 1));
+        let left = task_rng().gen_range(0..(width - crop_width + 1));
+        let top = task_rng().gen_range(0..(height - crop_height + 1));
 
         let cropped_image = img.crop(left, top, crop_width, crop_height);
         let mut buffer = Cursor::new(Vec::new());
@@ -168,7 +184,8 @@ async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String
         // Add overlay pattern on top of the image
         if use_overlay {
             let overlay_color = random_color();
-            let opacity = thread_rng().gen_range(0.05..0.35);
+            sampler.note("overlay_color", color_json(&overlay_color));
+            let opacity = sampler.number("overlay_opacity");
             bg_style = format!(
                 "{} background: linear-gradient(rgba({},{},{},{}), rgba({},{},{},{})), {}",
                 bg_style,
@@ -189,6 +206,8 @@ async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String
         while !ensure_wcag_contrast(&bg_color, &text_color, &3.0) {
             text_color = random_color();
         }
+        sampler.note("background_color", color_json(&bg_color));
+        sampler.note("text_color", color_json(&text_color));
 
         Ok((
             bg_style,
@@ -196,11 +215,14 @@ async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String
                 "#{:02x}{:02x}{:02x}",
                 text_color.0, text_color.1, text_color.2
             ),
+            if use_overlay {
+                "image_overlay"
+            } else {
+                "image"
+            },
         ))
     } else {
-        let use_gradient = thread_rng().gen_bool(0.3); // 30% chance to use gradient
-
-        if use_gradient {
+        if sampler.enabled("gradient") {
             let color1 = random_color();
             let color2 = random_color();
 
@@ -212,6 +234,9 @@ async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String
             while !ensure_wcag_contrast(&mean_color, &text_color, &3.0) {
                 text_color = random_color();
             }
+            sampler.note("background_color", color_json(&color1));
+            sampler.note("gradient_color", color_json(&color2));
+            sampler.note("text_color", color_json(&text_color));
             Ok((
                 format!(
                     "background: linear-gradient(45deg, #{:02x}{:02x}{:02x}, #{:02x}{:02x}{:02x});",
@@ -221,6 +246,7 @@ async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String
                     "#{:02x}{:02x}{:02x}",
                     text_color.0, text_color.1, text_color.2
                 ),
+                "gradient",
             ))
         } else {
             let bg_color = random_color();
@@ -229,6 +255,8 @@ async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String
             while !ensure_wcag_contrast(&bg_color, &text_color, &3.0) {
                 text_color = random_color();
             }
+            sampler.note("background_color", color_json(&bg_color));
+            sampler.note("text_color", color_json(&text_color));
             Ok((
                 format!(
                     "background-color: #{:02x}{:02x}{:02x};",
@@ -238,82 +266,56 @@ async fn generate_background_style(images: &Vec<Arc<Vec<u8>>>) -> Result<(String
                     "#{:02x}{:02x}{:02x}",
                     text_color.0, text_color.1, text_color.2
                 ),
+                "solid",
             ))
         }
     }
 }
 
-fn generate_style_properties() -> String {
-    let random_prop = |prob: f64, range: (f64, f64), decimals: usize| -> f64 {
-        if thread_rng().gen::<f64>() < prob {
-            let value = thread_rng().gen_range(range.0..=range.1);
-            (value * 10f64.powi(decimals as i32)).round() / 10f64.powi(decimals as i32)
-        } else {
-            0.0
-        }
-    };
-
-    let props = [
-        ("skew", 0.5, (-6.0, 6.0), 2),
-        ("rotate", 0.5, (-6.0, 6.0), 2),
-        ("translate", 0.4, (-3.0, 3.0), 2),
-        ("blur", 0.33, (0.0, 0.3), 2),
-        ("brightness", 0.4, (0.8, 1.2), 1),
-        ("contrast", 0.4, (0.8, 1.2), 1),
-    ];
-
-    let transform = props
-        .iter()
-        .take(3)
-        .map(|(name, prob, range, decimals)| {
-            let x = random_prop(*prob, *range, *decimals);
-            let y = if *name == "rotate" {
-                0.0
-            } else {
-                random_prop(*prob, *range, *decimals)
-            };
-            if *name == "translate" {
-                format!("{}({}px, {}px)", name, x, y)
-            } else if *name == "rotate" {
-                format!("{}({}deg)", name, x)
-            } else {
-                format!("{}({}deg, {}deg)", name, x, y)
-            }
-        })
-        .collect::<Vec<_>>()
-        .join(" ");
+/// `geometry` is false in paragraph mode, whose `.text-container` rule owns the box size and
+/// font size.
+fn generate_style_properties(sampler: &mut StyleSampler<'_>, geometry: bool) -> String {
+    let transform = format!(
+        "skew({}deg, {}deg) rotate({}deg) translate({}px, {}px)",
+        sampler.number("skew_x"),
+        sampler.number("skew_y"),
+        sampler.number("rotate"),
+        sampler.number("translate_x"),
+        sampler.number("translate_y")
+    );
+    let filter = format!(
+        "blur({}px) brightness({}) contrast({})",
+        sampler.number("blur"),
+        sampler.number("brightness"),
+        sampler.number("contrast")
+    );
 
-    let filter = props
-        .iter()
-        .skip(3)
-        .map(|(name, prob, range, decimals)| {
-            let value = random_prop(*prob, *range, *decimals).max(1.0);
-            if *name == "blur" {
-                format!("{}({}px)", name, value)
-            } else {
-                format!("{}({})", name, value)
-            }
-        })
-        .collect::<Vec<_>>()
-        .join(" ");
-
-    let width = thread_rng().gen_range(250..=600);
-    let height = thread_rng().gen_range(200..=450);
-    let font_size = thread_rng().gen_range(36..=100);
-    let text_align = ["center", "left", "right"]
-        .choose(&mut thread_rng())
-        .unwrap();
+    let geometry = if geometry {
+        format!(
+            "width: {}px; height: {}px; font-size: {}px; ",
+            sampler.integer("width"),
+            sampler.integer("height"),
+            sampler.integer("font_size")
+        )
+    } else {
+        String::new()
+    };
+    let text_align = sampler.text("text_align");
 
-    let padding = thread_rng().gen_range(5..=50);
-    let margin = thread_rng().gen_range(5..=50);
+    let padding = sampler.integer("padding");
+    let margin = sampler.integer("margin");
 
     format!(
-        "width: {}px; height: {}px; font-size: {}px; text-align: {}; transform: {}; filter: {}; padding: {}px; margin: {}px;",
-        width, height, font_size, text_align, transform, filter, padding, margin
+        "{}text-align: {}; transform: {}; filter: {}; padding: {}px; margin: {}px;",
+        geometry, text_align, transform, filter, padding, margin
     )
 }
-fn generate_shadow_style(bg_style: &str, text_color: &str) -> String {
-    if thread_rng().gen_bool(0.4) {
+fn generate_shadow_style(
+    bg_style: &str,
+    text_color: &str,
+    sampler: &mut StyleSampler<'_>,
+) -> String {
+    if sampler.enabled("shadow") {
         let bg_color = parse_color(bg_style);
         let text_color = parse_color(text_color);
         let mut shadow_color = random_color();
@@ -324,10 +326,11 @@ fn generate_shadow_style(bg_style: &str, text_color: &str) -> String {
             // {
             shadow_color = random_color();
         }
+        sampler.note("shadow_color", color_json(&shadow_color));
 
-        let shadow_x = thread_rng().gen_range(-5.0..=6.0);
-        let shadow_y = thread_rng().gen_range(-5.0..=6.0);
-        let shadow_blur = thread_rng().gen_range(1.0..=8.0);
+        let shadow_x = sampler.number("shadow_x");
+        let shadow_y = sampler.number("shadow_y");
+        let shadow_blur = sampler.number("shadow_blur");
         format!(
             "text-shadow: {:.2}px {:.2}px {:.2}px #{:02x}{:02x}{:02x};",
             shadow_x, shadow_y, shadow_blur, shadow_color.0, shadow_color.1, shadow_color.2
@@ -337,8 +340,12 @@ fn generate_shadow_style(bg_style: &str, text_color: &str) -> String {
     }
 }
 
-fn generate_outline_style(bg_style: &str, text_color: &str) -> String {
-    if thread_rng().gen_bool(0.2) {
+fn generate_outline_style(
+    bg_style: &str,
+    text_color: &str,
+    sampler: &mut StyleSampler<'_>,
+) -> String {
+    if sampler.enabled("outline") {
         let bg_color = parse_color(bg_style);
         let text_color = parse_color(text_color);
         let mut outline_color = random_color();
@@ -349,8 +356,9 @@ fn generate_outline_style(bg_style: &str, text_color: &str) -> String {
             // {
             outline_color = random_color();
         }
+        sampler.note("outline_color", color_json(&outline_color));
 
-        let outline_width = thread_rng().gen_range(1.0..=3.0);
+        let outline_width = sampler.number("outline_width");
         format!(
             "-webkit-text-stroke: {:.2}px #{:02x}{:02x}{:02x};",
             outline_width, outline_color.0, outline_color.1, outline_color.2
@@ -360,10 +368,10 @@ fn generate_outline_style(bg_style: &str, text_color: &str) -> String {
     }
 }
 
-fn generate_noise_style() -> String {
-    if thread_rng().gen_bool(0.4) {
+fn generate_noise_style(sampler: &mut StyleSampler<'_>) -> String {
+    if sampler.enabled("noise") {
         let noise_image = generate_noise_image().unwrap_or_default();
-        let noise_intensity = thread_rng().gen_range(0.1..=0.3);
+        let noise_intensity = sampler.number("noise_opacity");
         format!(
             "body::after {{ content: ''; position: absolute; top: 0; left: 0; width: 100%; height: 100%; background-image: url({}); opacity: {:.2}; pointer-events: none; z-index: -1; }}",
             noise_image, noise_intensity
@@ -386,16 +394,46 @@ fn parse_color(color_str: &str) -> Color {
     }
 }
 
-async fn generate_random_styles(images: &Vec<Arc<Vec<u8>>>) -> Result<String, String> {
-    let (bg_style, text_color_hex) = generate_background_style(&images).await?;
+// Printed-page look used with the scan profile: dark ink on off-white paper, no effects.
+fn generate_document_style(sampler: &mut StyleSampler<'_>, geometry: bool) -> String {
+    let paper = sampler.integer("paper").clamp(0, 255) as u8;
+    let paper_tint = sampler.integer("paper_tint").clamp(0, paper as i64) as u8;
+    let ink = sampler.integer("ink").clamp(0, 255) as u8;
+    let text_align = sampler.text("document_text_align");
+    let font_size = if geometry {
+        format!(" font-size: {}px;", sampler.integer("document_font_size"))
+    } else {
+        String::new()
+    };
 
-    let style_properties = generate_style_properties();
+    format!(
+        "background-color: #{:02x}{:02x}{:02x}; color: #{:02x}{:02x}{:02x}; text-align: {};{} padding: {}px;",
+        paper,
+        paper,
+        paper - paper_tint,
+        ink,
+        ink,
+        ink,
+        text_align,
+        font_size,
+        sampler.integer("document_padding")
+    )
+}
+
+async fn generate_random_styles(
+    images: &[Arc<Vec<u8>>],
+    sampler: &mut StyleSampler<'_>,
+    geometry: bool,
+) -> Result<(String, &'static str), String> {
+    let (bg_style, text_color_hex, background) = generate_background_style(images, sampler).await?;
+
+    let style_properties = generate_style_properties(sampler, geometry);
 
-    let shadow_style = generate_shadow_style(&bg_style, &text_color_hex);
+    let shadow_style = generate_shadow_style(&bg_style, &text_color_hex, sampler);
 
-    let outline_style = generate_outline_style(&bg_style, &text_color_hex);
+    let outline_style = generate_outline_style(&bg_style, &text_color_hex, sampler);
 
-    let noise_style = generate_noise_style();
+    let noise_style = generate_noise_style(sampler);
 
     let styles = format!(
         "
@@ -410,53 +448,711 @@ async fn generate_random_styles(images: &Vec<Arc<Vec<u8>>>) -> Result<String, St
         bg_style, text_color_hex, style_properties, shadow_style, outline_style
     );
 
-    Ok(styles + &noise_style)
+    Ok((styles + &noise_style, background))
 }
 
-pub async fn create_html_content(
+/// A filled-in page template plus what was chosen while styling it.
+pub struct HtmlSample {
+    pub html: String,
+    /// Style choices worth recording in the sample metadata.
+    pub style: Map<String, Value>,
+    /// Viewport the page was laid out for, when the style dictates one.
+    pub viewport: Option<(u32, u32)>,
+    /// Font label of every `.text-container` on the page, in document order.
+    pub block_fonts: Vec<String>,
+}
+
+/// A run of text and the font file it is set in.
+pub struct TextBlock<'a> {
+    pub font_name: &'a str,
+    pub font: &'a FontFile,
+    pub phrase: &'a str,
+}
+
+/// How fonts other than the sample's own are mixed into the page.
+pub enum FontMix<'a> {
+    /// One font for the whole page.
+    Single,
+    /// A composition of separate blocks, one per font.
+    Blocks(&'a [TextBlock<'a>]),
+    /// Runs of words within the phrase recast in the given fonts; their phrases are unused.
+    Spans(&'a [TextBlock<'a>]),
+}
+
+fn font_face_rule(block: &TextBlock) -> String {
+    format!(
+        "@font-face {{ font-family: '{}'; src: {}; }}\n",
+        block.font_name,
+        block.font.css_src()
+    )
+}
+
+/// Samples OpenType features and axis positions for `font`, returning the CSS declarations
+/// and the record for the metadata. With `keep_weight`, the `wght` axis stays at its default.
+fn sample_typography(font: &FontFile, keep_weight: bool) -> (String, Value) {
+    let mut typography = Typography::sample(font, &mut task_rng());
+    if keep_weight {
+        typography.variations.retain(|(tag, _)| tag != "wght");
+    }
+    (typography.css(), typography.to_json())
+}
+
+/// Splits the phrase into runs of one to three words and wraps each run in a `.font-span`
+/// labelled with its font. Each run is set in one of `fonts` with the profile's `span_font`
+/// chance, redrawn per run, and always at least one when there are two or more; the rest
+/// keep `font_name`.
+/// Each extra font gets its own sampled typography. Returns the phrase markup, the
+/// `@font-face` rules it needs and the typography chosen per extra font.
+fn mix_font_spans(
+    phrase: &str,
     font_name: &str,
+    fonts: &[TextBlock],
+    sampler: &mut StyleSampler,
+) -> (String, String, Map<String, Value>) {
+    let words: Vec<&str> = phrase.split_whitespace().collect();
+    let mut runs = Vec::new();
+    let mut start = 0;
+    while start < words.len() {
+        let end = (start + task_rng().gen_range(1..=3)).min(words.len());
+        runs.push(words[start..end].join(" "));
+        start = end;
+    }
+
+    let mut mixed: Vec<Option<&TextBlock>> = runs
+        .iter()
+        .map(|_| {
+            sampler.redraw("span_font");
+            if sampler.enabled("span_font") {
+                fonts.choose(&mut task_rng())
+            } else {
+                None
+            }
+        })
+        .collect();
+    if runs.len() > 1 && mixed.iter().all(Option::is_none) {
+        let i = task_rng().gen_range(0..runs.len());
+        mixed[i] = fonts.choose(&mut task_rng());
+    }
+
+    let mut typography: Map<String, Value> = Map::new();
+    let mut settings: Vec<(&str, String)> = Vec::new();
+    let spans: Vec<String> = runs
+        .iter()
+        .zip(&mixed)
+        .map(|(run, block)| {
+            let (family, css) = match block {
+                Some(block) => {
+                    if !typography.contains_key(block.font_name) {
+                        let (css, record) = sample_typography(block.font, false);
+                        typography.insert(block.font_name.to_string(), record);
+                        settings.push((block.font_name, css));
+                    }
+                    let css = &settings.iter().find(|(f, _)| *f == block.font_name).unwrap().1;
+                    (block.font_name, css.as_str())
+                }
+                None => (font_name, ""),
+            };
+            format!(
+                "<span class=\"font-span\" data-font=\"{}\" style=\"font-family: '{}'; {}\">{}</span>",
+                family, family, css, run
+            )
+        })
+        .collect();
+
+    let font_faces = fonts
+        .iter()
+        .filter(|block| typography.contains_key(block.font_name))
+        .map(font_face_rule)
+        .collect();
+    (spans.join(" "), font_faces, typography)
+}
+
+fn fill_template(
     template: &str,
+    font_name: &str,
     phrase: &str,
-    base64_font: &str,
-    images: &Vec<Arc<Vec<u8>>>,
-    method: Option<&str>,
-) -> Result<String, String> {
-    let styles = match method {
-        Some("simple") => {
-            "background-color: white; color: black; text-align: center; font-size: 50px;"
+    font: &FontFile,
+    text_styles: &str,
+    body_styles: &str,
+) -> String {
+    template
+        .replace("{font_src}", &font.css_src())
+        .replace("{phrase}", phrase)
+        .replace("{font_name}", font_name)
+        .replace("{text_styles}", text_styles)
+        .replace("{body_styles}", body_styles)
+}
+
+/// Splits the page into one horizontal band per block, so blocks never overlap, and places
+/// each block at a random width and offset inside its band. The first band is taller, as a
+/// headline. Returns `(left, top, width, height)` per block.
+fn composition_layout(width: u32, height: u32, blocks: usize) -> Vec<(u32, u32, u32, u32)> {
+    let margin = 16;
+    // Gaps shrink on crowded pages so every block keeps a band.
+    let gap = task_rng()
+        .gen_range(8..=24)
+        .min(height.saturating_sub(2 * margin) / blocks as u32);
+    let usable_height = height
+        .saturating_sub(2 * margin)
+        .saturating_sub(gap * (blocks as u32).saturating_sub(1));
+    let usable_width = width.saturating_sub(2 * margin);
+    let weights: Vec<f64> = (0..blocks)
+        .map(|i| {
+            let base = task_rng().gen_range(1.0..=2.0);
+            if i == 0 {
+                base * 1.3
+            } else {
+                base
+            }
+        })
+        .collect();
+    let total: f64 = weights.iter().sum();
+
+    let mut top = margin;
+    weights
+        .iter()
+        .map(|weight| {
+            let band = (usable_height as f64 * weight / total) as u32;
+            let block_width = (usable_width as f64 * task_rng().gen_range(0.5..=1.0)) as u32;
+            let left = margin + task_rng().gen_range(0..=usable_width - block_width);
+            let slot = (left, top, block_width, band);
+            top += band + gap;
+            slot
+        })
+        .collect()
+}
+
+async fn create_composition_content(
+    template: &str,
+    text: &TextBlock<'_>,
+    images: &[Arc<Vec<u8>>],
+    extra_blocks: &[TextBlock<'_>],
+    profile: &StyleProfile,
+    keep_weight: bool,
+) -> Result<HtmlSample, String> {
+    let font_name = text.font_name;
+    let width = task_rng().gen_range(600..=1000);
+    let height = task_rng().gen_range(500..=1000);
+    let slots = composition_layout(width, height, extra_blocks.len() + 1);
+    let mut sampler = StyleSampler::new(profile);
+    let (bg_style, text_color, background) =
+        generate_background_style(images, &mut sampler).await?;
+
+    let mut extra_styles = String::new();
+    let mut extra_html = String::new();
+    let mut block_fonts = vec![font_name.to_string()];
+    let mut layout = Vec::new();
+    let mut typography = Vec::new();
+    for (i, (left, top, block_width, block_height)) in slots.into_iter().enumerate() {
+        let block = if i == 0 { text } else { &extra_blocks[i - 1] };
+        let (font_settings, record) = sample_typography(block.font, keep_weight && i == 0);
+        typography.push(record);
+        let font_family = if i == 0 {
+            font_name
+        } else {
+            extra_styles.push_str(&font_face_rule(block));
+            extra_html.push_str(&format!(
+                "\n    <div class=\"text-container\">{}</div>",
+                block.phrase
+            ));
+            block_fonts.push(block.font_name.to_string());
+            block.font_name
+        };
+
+        // Headline-sized first block; body-sized, wrapping text below it.
+        let font_size = if i == 0 {
+            (block_height as f64 * task_rng().gen_range(0.4..=0.7)) as u32
+        } else {
+            (block_height as f64 * task_rng().gen_range(0.2..=0.45)) as u32
+        };
+        let text_align = ["right", "center", "justify"]
+            .choose(&mut task_rng())
+            .unwrap();
+        extra_styles.push_str(&format!(
+            "body > .text-container:nth-of-type({}) {{ position: absolute; left: {}px; top: {}px; width: {}px; height: {}px; overflow: hidden; font-family: '{}'; font-size: {}px; text-align: {}; color: {}; {} }}\n",
+            i + 1,
+            left,
+            top,
+            block_width,
+            block_height,
+            font_family,
+            font_size,
+            text_align,
+            text_color,
+            font_settings
+        ));
+        layout.push(json!([left, top, block_width, block_height]));
+    }
+
+    let mut style = Map::new();
+    style.insert("method".to_string(), json!("composition"));
+    style.insert("background".to_string(), json!(background));
+    style.insert("font_file".to_string(), text.font.to_json());
+    style.insert(
+        "composition".to_string(),
+        json!({ "slots": layout, "typography": typography }),
+    );
+    style.insert("profile".to_string(), json!(profile.name()));
+    style.insert("params".to_string(), sampler.record());
+
+    let html = fill_template(template, font_name, text.phrase, text.font, "", &bg_style)
+        .replace("{extra_styles}", &extra_styles)
+        .replace("{extra_blocks}", &extra_html);
+
+    Ok(HtmlSample {
+        html,
+        style,
+        viewport: Some((width, height)),
+        block_fonts,
+    })
+}
+
+// Arabic-script letters that join on both sides, so a tatweel may follow them; the rest join
+// only to the right.
+pub(crate) const DUAL_JOINING: &str = "بپتثجچحخسشصضطظعغفقکكگلمنهیيئ";
+
+/// Stretches words with tatweel (kashida, U+0640), the way Persian typesetters justify lines:
+/// at most one insertion point per word, taken with probability `density`, between a joining
+/// letter and the letter after it. Lam-alef is left alone so its ligature survives. Returns
+/// the text and the number of words stretched.
+fn add_kashida(text: &str, density: f64) -> (String, usize) {
+    let is_letter = |c: char| ('\u{0621}'..='\u{064A}').contains(&c) || "پچژکگی".contains(c);
+    let mut out = String::with_capacity(text.len());
+    let mut stretched = 0;
+    let mut word_done = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c.is_whitespace() {
+            word_done = false;
+            continue;
         }
+        let Some(&next) = chars.peek() else { break };
+        let lam_alef = c == 'ل' && "اآأإ".contains(next);
+        if !word_done
+            && DUAL_JOINING.contains(c)
+            && is_letter(next)
+            && !lam_alef
+            && task_rng().gen_bool(density)
+        {
+            for _ in 0..task_rng().gen_range(1..=2) {
+                out.push('\u{0640}');
+            }
+            word_done = true;
+            stretched += 1;
+        }
+    }
+    (out, stretched)
+}
+
+/// Typography for paragraph mode. The container width is left to the layout check, which
+/// narrows it until the text wraps onto `--target-lines` lines.
+struct ParagraphStyle {
+    css: String,
+    viewport: (u32, u32),
+    kashida: bool,
+    metadata: Value,
+}
+
+fn generate_paragraph_style(lines: usize, sampler: &mut StyleSampler<'_>) -> ParagraphStyle {
+    let font_size = sampler.integer("paragraph_font_size");
+    let line_height = sampler.number("paragraph_line_height");
+    let word_spacing = sampler.number("paragraph_word_spacing");
+    let letter_spacing = sampler.number("paragraph_letter_spacing");
+    let text_align = sampler.text("paragraph_text_align");
+    let kashida = text_align == "justify" && sampler.enabled("kashida");
+
+    let text_height = lines as f64 * font_size as f64 * line_height;
+    let width = sampler.integer("paragraph_width").max(1) as u32;
+    let height = ((text_height * 1.2) as u32 + 80).clamp(300, 1600);
+
+    let css = format!(
+        ".text-container {{ --target-lines: {}; box-sizing: border-box; max-width: none; font-size: {}px; line-height: {:.2}; word-spacing: {:.1}px; letter-spacing: {:.2}px; text-align: {}; text-align-last: right; white-space: normal; }}\n",
+        lines, font_size, line_height, word_spacing, letter_spacing, text_align
+    );
+    let metadata = json!({
+        "target_lines": lines,
+        "font_size": font_size,
+        "line_height": line_height,
+        "word_spacing": word_spacing,
+        "letter_spacing": letter_spacing,
+        "text_align": text_align,
+    });
+
+    ParagraphStyle {
+        css,
+        viewport: (width, height),
+        kashida,
+        metadata,
+    }
+}
+
+/// Browser-synthesized styling of a face that lacks it: faux bold on non-bold faces, oblique
+/// slant on upright ones, and horizontal compression or expansion. At least one of the three
+/// is always applied. `scale` is used instead of `transform` so it composes with any rotation
+/// the random styles already set. With `keep_weight`, faux bold is never applied. A profile
+/// that turns all three off gets the plain face after `SYNTHETIC_ATTEMPTS` draws.
+fn generate_synthetic_variant(
+    font: &FontFile,
+    keep_weight: bool,
+    sampler: &mut StyleSampler,
+) -> (String, Value) {
+    let can_embolden = !keep_weight && font.names.weight < 600;
+    let can_slant = !font.names.italic;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        for name in ["synthetic_bold", "synthetic_oblique", "synthetic_scale_x"] {
+            sampler.redraw(name);
+        }
+        let bold = can_embolden && sampler.enabled("synthetic_bold");
+        let oblique = (can_slant && sampler.enabled("synthetic_oblique"))
+            .then(|| sampler.integer("synthetic_oblique"));
+        let scale_x = sampler
+            .enabled("synthetic_scale_x")
+            .then(|| sampler.number("synthetic_scale_x"));
+        if !bold && oblique.is_none() && scale_x.is_none() && attempt < SYNTHETIC_ATTEMPTS {
+            continue;
+        }
+
+        let mut css = String::from("font-synthesis: weight style;");
+        if bold {
+            css.push_str(" font-weight: bold;");
+        }
+        if let Some(angle) = oblique {
+            css.push_str(&format!(" font-style: oblique {}deg;", angle));
+        }
+        if let Some(scale_x) = scale_x {
+            css.push_str(&format!(" scale: {} 1;", scale_x));
+        }
+        let metadata = json!({ "bold": bold, "oblique_deg": oblique, "scale_x": scale_x });
+        return (css, metadata);
+    }
+}
+
+/// How a page is styled, beyond the text on it.
+pub struct PageOptions<'a> {
+    /// `simple`, `document` or `scene`; anything else styles the page at random.
+    pub method: Option<&'a str>,
+    pub mix: FontMix<'a>,
+    /// Switches the text container to paragraph typography wrapped onto that many lines; it
+    /// applies on top of every method except scenes and compositions.
+    pub paragraph_lines: Option<usize>,
+    /// Adds a synthesized bold, oblique or width variant of the main font, recorded under
+    /// `synthetic` in the style.
+    pub synthetic: bool,
+    /// Distributions the style parameters are drawn from; the draws are recorded under
+    /// `params` in the style.
+    pub profile: &'a StyleProfile,
+    /// Renders the main font at its own weight: no `wght` axis sampling and no faux bold.
+    /// Set when the label names a weight, so the pixels match it.
+    pub keep_weight: bool,
+}
+
+/// Builds the page for one sample. With `FontMix::Blocks`, the page becomes a multi-font
+/// composition: the phrase is the first block and every extra block gets its own font. With
+/// `FontMix::Spans`, the styling is unchanged but words inside the phrase switch fonts.
+pub async fn create_html_content(
+    template: &str,
+    text: &TextBlock<'_>,
+    images: &[Arc<Vec<u8>>],
+    page: PageOptions<'_>,
+) -> Result<HtmlSample, String> {
+    let PageOptions {
+        method,
+        mix,
+        paragraph_lines,
+        synthetic,
+        profile,
+        keep_weight,
+    } = page;
+    if let FontMix::Blocks(extra_blocks) = mix {
+        return create_composition_content(
+            template,
+            text,
+            images,
+            extra_blocks,
+            profile,
+            keep_weight,
+        )
+        .await;
+    }
+    let font_name = text.font_name;
+    let phrase = text.phrase;
+
+    let mut style = Map::new();
+    style.insert("method".to_string(), json!(method.unwrap_or("random")));
+    style.insert("font_file".to_string(), text.font.to_json());
+    style.insert("profile".to_string(), json!(profile.name()));
+    let mut sampler = StyleSampler::new(profile);
+
+    let paragraph = paragraph_lines
+        .filter(|_| method != Some("scene"))
+        .map(|lines| generate_paragraph_style(lines, &mut sampler));
+    let mut phrase = phrase.to_string();
+    if let Some(paragraph) = &paragraph {
+        let mut metadata = paragraph.metadata.clone();
+        if paragraph.kashida {
+            let (stretched, count) = add_kashida(&phrase, sampler.number("kashida_density"));
+            phrase = stretched;
+            metadata["kashida_words"] = json!(count);
+        }
+        style.insert("paragraph".to_string(), metadata);
+    }
+
+    let (phrase_html, mut extra_styles) = match mix {
+        FontMix::Spans(fonts) => {
+            let (html, font_faces, span_typography) =
+                mix_font_spans(&phrase, font_name, fonts, &mut sampler);
+            style.insert("font_mix".to_string(), json!("spans"));
+            style.insert(
+                "span_typography".to_string(),
+                Value::Object(span_typography),
+            );
+            (html, font_faces)
+        }
+        _ => (phrase, String::new()),
+    };
+    let phrase = phrase_html.as_str();
+
+    // The simple style is the fixed baseline rendering, so it keeps the font's defaults.
+    if method != Some("simple") {
+        let (font_settings, typography) = sample_typography(text.font, keep_weight);
+        style.insert("typography".to_string(), typography);
+        if !font_settings.is_empty() {
+            extra_styles.push_str(&format!(".text-container {{ {} }}\n", font_settings));
+        }
+    }
+    if synthetic {
+        let (css, variant) = generate_synthetic_variant(text.font, keep_weight, &mut sampler);
+        style.insert("synthetic".to_string(), variant);
+        extra_styles.push_str(&format!(".text-container {{ {} }}\n", css));
+    }
+    if let Some(paragraph) = &paragraph {
+        extra_styles.push_str(&paragraph.css);
+    }
+
+    if method == Some("scene") {
+        let scene = generate_scene_styles(images).await?;
+        style.insert("scene".to_string(), scene.metadata);
+        style.insert("background".to_string(), json!("scene"));
+        let html = fill_template(
+            template,
+            font_name,
+            phrase,
+            text.font,
+            &scene.text_styles,
+            &scene.body_styles,
+        )
+        .replace("{extra_styles}", &extra_styles)
+        .replace("{extra_blocks}", "");
+        return Ok(HtmlSample {
+            html,
+            style,
+            viewport: Some(scene.viewport),
+            block_fonts: vec![font_name.to_string()],
+        });
+    }
+
+    // Paragraph pages leave the box and font size to the paragraph rule in `extra_styles`.
+    let geometry = paragraph.is_none();
+    let plain = |font_size: i64| {
+        let mut styles = "background-color: white; color: black; text-align: center;".to_string();
+        if geometry {
+            styles.push_str(&format!(" font-size: {}px;", font_size));
+        }
+        styles
+    };
+    let (styles, background) = match method {
+        Some("simple") => (plain(50), "plain"),
+        Some("document") => (generate_document_style(&mut sampler, geometry), "paper"),
         _ => {
-            if thread_rng().gen_range(1..8) == 5 {
-                &format!(
-                    "background-color: white; color: black; text-align: center; font-size: {}px;",
-                    thread_rng().gen_range(24..60)
-                )
+            if sampler.enabled("plain") {
+                (plain(sampler.integer("plain_font_size")), "plain")
+            } else if sampler.enabled("document_look") {
+                (generate_document_style(&mut sampler, geometry), "paper")
             } else {
-                &match generate_random_styles(&images).await {
-                    Ok(style_string) => style_string,
-                    Err(_) => format!("failed to generate styles for {}", font_name),
+                match generate_random_styles(images, &mut sampler, geometry).await {
+                    Ok(generated) => generated,
+                    Err(_) => (
+                        format!("failed to generate styles for {}", font_name),
+                        "none",
+                    ),
                 }
             }
         }
     };
+    style.insert("background".to_string(), json!(background));
+    let styles = styles.as_str();
 
-    let text_styling = thread_rng().gen_bool(0.5);
+    // The simple style always styles the container, so its renders are reproducible.
+    let text_styling = method == Some("simple") || sampler.enabled("container_styling");
+    style.insert("params".to_string(), sampler.record());
 
-    let html_content = if text_styling {
-        template
-            .replace("{phrase}", &phrase)
-            .replace("{base64_font}", &base64_font)
-            .replace("{font_name}", &font_name)
-            .replace("{text_styles}", &styles)
-            .replace("{body_styles}", "")
+    let html = if text_styling {
+        fill_template(template, font_name, phrase, text.font, styles, "")
     } else {
-        template
-            .replace("{phrase}", &phrase)
-            .replace("{base64_font}", &base64_font)
-            .replace("{font_name}", &font_name)
-            .replace("{text_styles}", "")
-            .replace("{body_styles}", &styles)
+        fill_template(template, font_name, phrase, text.font, "", styles)
     };
 
-    Ok(html_content)
+    Ok(HtmlSample {
+        html: html
+            .replace("{extra_styles}", &extra_styles)
+            .replace("{extra_blocks}", ""),
+        style,
+        viewport: paragraph.map(|paragraph| paragraph.viewport),
+        block_fonts: vec![font_name.to_string()],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiles::StyleProfiles;
+
+    #[test]
+    fn synthetic_variants_only_add_what_the_face_lacks() {
+        let mut font = FontFile::load("Nazanin-BoldItalic.ttf", b"\0\x01\0\0")
+            .unwrap()
+            .remove(0);
+        font.names.weight = 700;
+        font.names.italic = true;
+        let default = StyleProfiles::builtin().default_profile();
+        for _ in 0..50 {
+            let (css, variant) =
+                generate_synthetic_variant(&font, false, &mut StyleSampler::new(&default));
+            assert_eq!(variant["bold"], false);
+            assert!(variant["oblique_deg"].is_null());
+            assert!(css.contains("scale: "));
+        }
+    }
+
+    #[test]
+    fn kept_weights_get_no_wght_axis_or_faux_bold() {
+        let mut font = FontFile::load("Vazirmatn.ttf", b"\0\x01\0\0")
+            .unwrap()
+            .remove(0);
+        font.axes.push(crate::fonts::Axis {
+            tag: "wght".to_string(),
+            min: 100.0,
+            default: 400.0,
+            max: 900.0,
+        });
+        let default = StyleProfiles::builtin().default_profile();
+        for _ in 0..50 {
+            let (css, typography) = sample_typography(&font, true);
+            assert!(!css.contains("wght"), "{}", css);
+            assert!(typography["variations"].get("wght").is_none());
+            let (css, variant) =
+                generate_synthetic_variant(&font, true, &mut StyleSampler::new(&default));
+            assert_eq!(variant["bold"], false);
+            assert!(!css.contains("font-weight"));
+        }
+    }
+
+    #[test]
+    fn kashida_stretches_joining_letters_only() {
+        let (text, count) = add_kashida("سلام بهار کتاب", 1.0);
+        assert_eq!(count, 3);
+        assert_eq!(text.replace('\u{0640}', ""), "سلام بهار کتاب");
+        // Never inside lam-alef, after a right-joining letter, or at the end of a word.
+        assert!(!text.contains("ل\u{0640}ا"));
+        assert!(!text.contains("ا\u{0640}"));
+        assert!(!text.contains("\u{0640} "));
+
+        let (untouched, count) = add_kashida("دو ورد", 1.0);
+        assert_eq!((untouched.as_str(), count), ("دو ورد", 0));
+    }
+
+    #[test]
+    fn font_spans_cover_every_word_and_mix_in_another_font() {
+        let font = FontFile::load("Nazanin.ttf", b"\0\x01\0\0")
+            .unwrap()
+            .remove(0);
+        let fonts = [TextBlock {
+            font_name: "Nazanin",
+            font: &font,
+            phrase: "",
+        }];
+        let phrase = "یک دو سه چهار پنج شش";
+        let default = StyleProfiles::builtin().default_profile();
+        for _ in 0..20 {
+            let mut sampler = StyleSampler::new(&default);
+            let (html, font_faces, _) = mix_font_spans(phrase, "Vazir", &fonts, &mut sampler);
+            assert!(html.contains("data-font=\"Nazanin\""));
+            // One recorded decision per run of words.
+            let runs = html.matches("class=\"font-span\"").count();
+            let decisions = sampler.record()["span_font"].clone();
+            match decisions.as_array() {
+                Some(decisions) => assert_eq!(decisions.len(), runs),
+                None => assert_eq!(runs, 1),
+            }
+            assert!(font_faces.contains("font-family: 'Nazanin'"));
+            let words = html
+                .split("</span>")
+                .filter_map(|span| span.rsplit_once('>').map(|(_, text)| text))
+                .flat_map(str::split_whitespace)
+                .collect::<Vec<_>>();
+            assert_eq!(words.join(" "), phrase);
+        }
+    }
+
+    #[test]
+    fn composition_blocks_stay_on_page_without_overlap() {
+        for blocks in [2, 3, 4, 30] {
+            let slots = composition_layout(600, 500, blocks);
+            assert_eq!(slots.len(), blocks);
+            for (i, &(left, top, width, height)) in slots.iter().enumerate() {
+                assert!(left + width <= 600 && top + height <= 500);
+                if let Some(&(_, next_top, _, _)) = slots.get(i + 1) {
+                    assert!(top + height <= next_top);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn paragraph_pages_leave_geometry_to_the_paragraph_rule() {
+        let font = FontFile::load("Nazanin.ttf", b"\0\x01\0\0")
+            .unwrap()
+            .remove(0);
+        let text = TextBlock {
+            font_name: "Nazanin",
+            font: &font,
+            phrase: "سلام بهار کتاب",
+        };
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(IMAGE_MINIMUM_DIMENSION + 1, IMAGE_MINIMUM_DIMENSION + 1)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let images = [Arc::new(png.into_inner())];
+        let clean = StyleProfiles::builtin().get("clean").unwrap();
+        for method in [None, Some("simple"), Some("document")] {
+            for _ in 0..20 {
+                let sample = create_html_content(
+                    "{text_styles}|{body_styles}",
+                    &text,
+                    &images,
+                    PageOptions {
+                        method,
+                        mix: FontMix::Single,
+                        paragraph_lines: Some(4),
+                        synthetic: false,
+                        profile: &clean,
+                        keep_weight: false,
+                    },
+                )
+                .await
+                .unwrap();
+                for property in ["width:", "height:", "font-size:"] {
+                    assert!(!sample.html.contains(property), "{}", sample.html);
+                }
+                // Random colours are recorded with the parameters, one number per channel.
+                if !["plain", "paper"].contains(&sample.style["background"].as_str().unwrap()) {
+                    let text_color = &sample.style["params"]["text_color"];
+                    assert_eq!(text_color.as_array().unwrap().len(), 3);
+                }
+            }
+        }
+    }
 }